@@ -5,7 +5,9 @@ use std::fmt::Error as FormatError;
 use std::fmt::{Formatter};
 use std::collections::BTreeMap;
 
-use super::tokenizer::Pos;
+use indexmap::IndexMap;
+
+use super::tokenizer::{Pos, Span};
 use super::errors::{Error, ErrorCollector};
 use super::parser::Node as P;
 use super::parser::{Directive, Node, Document};
@@ -14,7 +16,10 @@ use self::Ast::*;
 use self::NullKind::*;
 use self::ScalarKind::*;
 use self::Tag::*;
-use options::{Options, Include, DoInclude};
+use options::{Options, Include, DoInclude, AliasLimits, DuplicateKeys};
+
+/// Prefix the `!!` tag handle expands to, unless redefined by `%TAG`
+pub const CORE_SCHEMA_PREFIX: &'static str = "tag:yaml.org,2002:";
 
 
 /// Kind of scalar value
@@ -48,7 +53,9 @@ pub enum Tag {
     /// Global tag, i.e. either a prefix defined in directives or a full
     /// url tag
     ///
-    /// Largely unsupported in the current code
+    /// The value is a fully resolved tag, e.g. `!!set` is stored as
+    /// `tag:yaml.org,2002:set`. Tags of the core schema, like `!!str`,
+    /// are applied at preprocessing stage and never reach this variant
     GlobalTag(String),
 }
 
@@ -61,155 +68,348 @@ impl Tag {
     }
 }
 
+/// Anchor or alias the node is written with
+///
+/// Only the top node of the alias expansion has `Alias` anchor, the nodes
+/// inside keep anchors of the original nodes. Keys merged using `<<` don't
+/// have any information on where they come from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Anchor {
+    /// Node is marked with an anchor `&name`
+    Defined(String),
+    /// Node is a copy of the node marked with `&name`, produced by `*name`
+    Alias(String),
+}
+
+/// Children of the `Ast::Map` node, in the order of the source
+pub type MapItems = IndexMap<String, Ast>;
+
 /// Yaml node
 #[derive(Debug)]
 pub enum Ast {
     /// Mapping node
-    Map(Pos, Tag, BTreeMap<String, Ast>),
+    ///
+    /// Non-scalar keys (like in `? [a, b]`) are converted to a canonical
+    /// flow-style string (`[a, b]`)
+    ///
+    /// Keys are in the order of the source, keys which come from merge keys
+    /// (`<<`) follow the keys of the mapping itself
+    Map(Span, Tag, Option<Anchor>, MapItems),
     /// Sequence node
-    Seq(Pos, Tag, Vec<Ast>),
+    Seq(Span, Tag, Option<Anchor>, Vec<Ast>),
     /// Scalar node (except null)
-    Scalar(Pos, Tag, ScalarKind, String),
+    Scalar(Span, Tag, Option<Anchor>, ScalarKind, String),
     /// Null node
-    Null(Pos, Tag, NullKind),
+    Null(Span, Tag, Option<Anchor>, NullKind),
 }
 
 impl Display for Ast {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), FormatError> {
         match *self {
-            Map(_, _, _) => write!(fmt, "Map"),
-            Seq(_, _, _) => write!(fmt, "Seq"),
-            Scalar(_, _, _, _) => write!(fmt, "Scalar"),
-            Null(_, _, _) => write!(fmt, "Null"),
+            Map(_, _, _, _) => write!(fmt, "Map"),
+            Seq(_, _, _, _) => write!(fmt, "Seq"),
+            Scalar(_, _, _, _, _) => write!(fmt, "Scalar"),
+            Null(_, _, _, _) => write!(fmt, "Null"),
         }
     }
 }
 
 impl Ast {
     pub fn pos(&self) -> Pos {
+        self.span().start
+    }
+    /// Span of the source text the node was produced from
+    ///
+    /// For nodes which are not in the source text (defaults, includes
+    /// failed) this is a zero-length span
+    pub fn span(&self) -> Span {
         match *self {
-            Map(ref pos, _, _) => pos.clone(),
-            Seq(ref pos, _, _) => pos.clone(),
-            Scalar(ref pos, _, _, _) => pos.clone(),
-            Null(ref pos, _, _) => pos.clone(),
+            Map(ref span, _, _, _) => span.clone(),
+            Seq(ref span, _, _, _) => span.clone(),
+            Scalar(ref span, _, _, _, _) => span.clone(),
+            Null(ref span, _, _, _) => span.clone(),
         }
     }
     pub fn tag<'x>(&'x self) -> &'x Tag {
         match *self {
-            Map(_, ref tag, _) => tag,
-            Seq(_, ref tag, _) => tag,
-            Scalar(_, ref tag, _, _) => tag,
-            Null(_, ref tag, _) => tag,
+            Map(_, ref tag, _, _) => tag,
+            Seq(_, ref tag, _, _) => tag,
+            Scalar(_, ref tag, _, _, _) => tag,
+            Null(_, ref tag, _, _) => tag,
         }
     }
     pub fn with_tag(self, tag: Tag) -> Ast {
         match self {
-            Map(pos, _, children) => Map(pos, tag, children),
-            Seq(pos,  _, children) => Seq(pos, tag, children),
-            Scalar(pos, _, style, value) => Scalar(pos, tag, style, value),
-            Null(pos, _, kind) => Null(pos, tag, kind),
+            Map(pos, _, anchor, children) => Map(pos, tag, anchor, children),
+            Seq(pos,  _, anchor, children) => Seq(pos, tag, anchor, children),
+            Scalar(pos, _, anchor, style, value) => {
+                Scalar(pos, tag, anchor, style, value)
+            }
+            Null(pos, _, anchor, kind) => Null(pos, tag, anchor, kind),
+        }
+    }
+    /// Anchor the node is marked with or alias it's produced by
+    pub fn anchor(&self) -> Option<&Anchor> {
+        match *self {
+            Map(_, _, ref anchor, _) => anchor.as_ref(),
+            Seq(_, _, ref anchor, _) => anchor.as_ref(),
+            Scalar(_, _, ref anchor, _, _) => anchor.as_ref(),
+            Null(_, _, ref anchor, _) => anchor.as_ref(),
+        }
+    }
+    /// Returns the same node with anchor replaced
+    pub fn with_anchor(self, anchor: Option<Anchor>) -> Ast {
+        match self {
+            Map(pos, tag, _, children) => Map(pos, tag, anchor, children),
+            Seq(pos, tag, _, children) => Seq(pos, tag, anchor, children),
+            Scalar(pos, tag, _, style, value) => {
+                Scalar(pos, tag, anchor, style, value)
+            }
+            Null(pos, tag, _, kind) => Null(pos, tag, anchor, kind),
         }
     }
     /// A special null value that is used when node inside is errorneous
     pub fn void(pos: &Pos) -> Ast {
-        Ast::Null(pos.clone(), Tag::NonSpecific, NullKind::Implicit)
+        Ast::Null(pos.clone().into(), Tag::NonSpecific, None,
+                  NullKind::Implicit)
     }
 }
 
+/// Version of YAML specification, selected by `%YAML` directive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Version {
+    /// Plain `yes`, `no`, `on` and `off` are booleans
+    Yaml11,
+    /// Core schema, default when no directive is specified
+    Yaml12,
+}
+
 struct Context<'a, 'b: 'a> {
     options: &'a Options<'b>,
-    directives: Vec<Directive<'a>>,
+    version: Version,
+    tag_handles: BTreeMap<&'a str, &'a str>,
+    /// Positions of aliases being expanded, outermost first
+    alias_stack: Vec<Pos>,
+    expanded_nodes: usize,
+    expansion_aborted: bool,
     err: &'a ErrorCollector,
 }
 
 
-fn pos_for_node<'x>(node: &Node<'x>) -> Pos {
-    match *node {
-        P::Map(_, _, _, ref tokens) => tokens[0].start.clone(),
-        P::Seq(_, _, _, ref tokens) => tokens[0].start.clone(),
-        P::Scalar(_, _, _, ref token) => token.start.clone(),
-        P::ImplicitNull(_, _, ref pos) => pos.clone(),
-        P::Alias(_, ref token, _) => token.start.clone(),
-    }
+fn defined_anchor(anchor: Option<&str>) -> Option<Anchor> {
+    anchor.map(|name| Anchor::Defined(name.to_string()))
 }
 
-fn parse_key<'x>(node: &Node, value: &'x Node<'x>, merge: &mut Option<&Node<'x>>)
-    -> Result<String, Option<Error>>
-{
-     match *node {
-        P::Scalar(_, _, ref key, ref tok) => {
-            if tok.kind == T::PlainString && &key[..] == "<<" {
-                *merge = Some(value);
-                return Err(None);
-            }
-            Ok(key.clone())
-        }
-        P::ImplicitNull(_, _, _) => Ok("".to_string()),
-        P::Alias(_, _, ref node) => {
-            parse_key(node, value, merge)
-        }
-        ref node => {
-            Err(Some(Error::preprocess_error(&pos_for_node(node),
-                "Non scalar keys are not supported yet"
-                .to_string())))
-        }
-    }
+fn pos_for_node<'x>(node: &Node<'x>) -> Pos {
+    return node.span().start;
 }
 
 impl<'a, 'b: 'a> Context<'a, 'b> {
     fn process(&mut self, node: &'a Node<'a>) -> Ast {
+        self.count_node();
+        let anchor = defined_anchor(node.anchor());
         match *node {
-            P::Map(ref origtag, _, _, ref tokens) => {
-                let pos = tokens[0].start.clone();
-                let tag = self.string_to_tag(&pos, origtag);
-                let mut mapping = BTreeMap::new();
+            P::Map(..) => {
+                let span = node.span();
+                let tag = self.string_to_tag(&span.start, node.tag());
+                let mut mapping = IndexMap::new();
                 self.merge_mapping(&mut mapping, node);
 
-                return Map(pos, tag, mapping);
+                return self.core_schema(Map(span, tag, anchor, mapping));
             }
-            P::Seq(ref origtag, _, _, ref tokens) => {
-                let pos = tokens[0].start.clone();
-                let tag = self.string_to_tag(&pos, origtag);
+            P::Seq(..) => {
+                let span = node.span();
+                let tag = self.string_to_tag(&span.start, node.tag());
                 let mut seq = Vec::new();
                 self.merge_sequence(&mut seq, node);
 
-                return Seq(pos, tag, seq);
+                return self.core_schema(Seq(span, tag, anchor, seq));
+            }
+            P::Scalar(_, _, ref val, _, ref span)
+            if node.tag() == Some("!*Include") => {
+                return self.include(&span.start, val);
             }
-            P::Scalar(Some("!*Include"), _anch, ref val, ref tok) => {
-                return self.options.include(&tok.start,
-                    &Include::File { filename: val }, self.err);
+            P::Scalar(_, _, ref val, _, ref span)
+            if node.tag() == Some("!*IncludeSeq") => {
+                return self.include_seq(&span.start, val);
             }
-            P::Scalar(ref tag, _, ref val, ref tok) => {
-                let pos = tok.start.clone();
-                let tag = self.string_to_tag(&pos, tag);
-                if tok.kind == T::PlainString {
+            P::Scalar(_, _, ref val, _, ref span)
+            if node.tag() == Some("!*IncludeMap") => {
+                return self.include_map(&span.start, val);
+            }
+            P::Scalar(_, _, ref val, kind, _) => {
+                let span = node.span();
+                let tag = self.string_to_tag(&span.start, node.tag());
+                let ast = if kind == T::PlainString &&
+                    !is_core_tag(&tag, "str")
+                {
                     if &val[..] == "~" || &val[..] == "null" {
-                        return Ast::Null(tok.start.clone(), tag, Explicit);
+                        Ast::Null(span, tag, anchor, Explicit)
+                    } else if let Some(val) = self.resolve_bool(val) {
+                        Ast::Scalar(span, tag, anchor, Plain, val.to_string())
                     } else {
-                        return Ast::Scalar(pos, tag, Plain, val.clone());
+                        Ast::Scalar(span, tag, anchor, Plain, val.clone())
                     }
                 } else {
-                    return Ast::Scalar(pos, tag, Quoted, val.clone());
+                    Ast::Scalar(span, tag, anchor, Quoted, val.clone())
+                };
+                return self.core_schema(ast);
+            }
+            P::ImplicitNull(_, _, ref pos) => {
+                let tag = self.string_to_tag(pos, node.tag());
+                return self.core_schema(Ast::Null(pos.clone().into(), tag,
+                    anchor, Implicit));
+            }
+            P::Alias(ref name, ref span, ref node) => {
+                return self.expand_alias(&span.start, node,
+                                         |ctx, node| ctx.process(node))
+                    .map(|ast| {
+                        ast.with_anchor(Some(Anchor::Alias(name.to_string())))
+                    })
+                    .unwrap_or_else(|| Ast::void(&span.start));
+            }
+        }
+    }
+
+    /// Expands an alias using `f`, unless limits are exceeded
+    ///
+    /// Returns `None` if alias is nested too deep or if too many nodes were
+    /// expanded in the document. The error is reported at the outermost
+    /// alias, and all further aliases expand to nothing
+    fn expand_alias<T, F>(&mut self, pos: &Pos, node: &'a Node<'a>, f: F)
+        -> Option<T>
+        where F: FnOnce(&mut Self, &'a Node<'a>) -> T
+    {
+        if self.expansion_aborted {
+            return None;
+        }
+        if self.alias_stack.len() >= self.options.alias_depth_limit() {
+            let outermost = self.alias_stack.get(0).unwrap_or(pos).clone();
+            self.err.add_error(Error::preprocess_error(&outermost,
+                format!("Aliases are nested too deep, maximum depth is {}",
+                    self.options.alias_depth_limit())));
+            self.expansion_aborted = true;
+            return None;
+        }
+        self.alias_stack.push(pos.clone());
+        let result = f(self, node);
+        self.alias_stack.pop();
+        if self.expansion_aborted {
+            return None;
+        }
+        return Some(result);
+    }
+
+    /// Counts a node produced by expanding an alias
+    fn count_node(&mut self) {
+        if self.alias_stack.len() == 0 || self.expansion_aborted {
+            return;
+        }
+        self.expanded_nodes += 1;
+        if self.expanded_nodes > self.options.expanded_nodes_limit() {
+            self.err.add_error(Error::preprocess_error(&self.alias_stack[0],
+                format!("Too many nodes produced by expanding aliases, \
+                    maximum is {}", self.options.expanded_nodes_limit())));
+            self.expansion_aborted = true;
+        }
+    }
+
+    /// Returns the string key and whether it's a canonical representation
+    /// of a non-scalar key
+    fn parse_key(&mut self, node: &'a Node<'a>, value: &'a Node<'a>,
+        merge: &mut Option<&'a Node<'a>>)
+        -> Result<(String, bool), Option<Error>>
+    {
+        match *node {
+            P::Scalar(_, _, ref key, kind, _) => {
+                if kind == T::PlainString && &key[..] == "<<" {
+                    *merge = Some(value);
+                    return Err(None);
                 }
+                Ok((key.clone(), false))
             }
-            P::ImplicitNull(ref tag, _, ref pos) => {
-                let tag = self.string_to_tag(pos, tag);
-                return Ast::Null(pos.clone(), tag, Implicit);
+            P::ImplicitNull(_, _, _) => Ok(("".to_string(), true)),
+            P::Alias(_, ref span, ref node) => {
+                self.expand_alias(&span.start, node,
+                    |ctx, node| ctx.parse_key(node, value, merge))
+                .unwrap_or(Err(None))
             }
-            P::Alias(_, _, ref node) => {
-                return self.process(node);
+            ref node => {
+                let mut key = String::new();
+                self.write_key(node, &mut key);
+                Ok((key, true))
             }
         }
     }
 
-    fn merge_mapping(&mut self, target: &mut BTreeMap<String, Ast>,
+    /// Writes canonical representation of a non-scalar mapping key
+    ///
+    /// Keys are written in flow style, e.g. `[a, b]` or `{a: 1}`, keys of
+    /// mappings are sorted. Tags and anchors are dropped. Plain nulls are
+    /// written as `null`, other scalars are written bare only if they can't
+    /// be mistaken for a flow collection or a value of another type, so
+    /// `[null]`, `["null"]` and `[1]`, `["1"]` are different keys.
+    fn write_key(&mut self, node: &'a Node<'a>, buf: &mut String) {
+        self.count_node();
+        match *node {
+            P::Map(_, _, ref children, _) => {
+                // Pairs are sorted by their canonical form, so aliases are
+                // expanded (and counted) only once
+                let mut pairs = Vec::with_capacity(children.len());
+                for &(ref k, ref v) in children.iter() {
+                    let mut pair = String::new();
+                    self.write_key(k, &mut pair);
+                    pair.push_str(": ");
+                    self.write_key(v, &mut pair);
+                    pairs.push(pair);
+                }
+                pairs.sort();
+                buf.push('{');
+                buf.push_str(&pairs.join(", "));
+                buf.push('}');
+            }
+            P::Seq(_, _, ref children, _) => {
+                buf.push('[');
+                for (idx, item) in children.iter().enumerate() {
+                    if idx > 0 {
+                        buf.push_str(", ");
+                    }
+                    self.write_key(item, buf);
+                }
+                buf.push(']');
+            }
+            P::Scalar(_, _, ref value, kind, _) => {
+                let plain = kind == T::PlainString;
+                if plain && is_core_null(value) {
+                    buf.push_str("null");
+                } else if value.len() > 0 && value.chars()
+                    .all(|c| c.is_alphanumeric() || "_-./+".contains(c))
+                    && (plain || self.is_plain_string(value))
+                {
+                    buf.push_str(value);
+                } else {
+                    buf.push_str(&format!("{:?}", value));
+                }
+            }
+            P::ImplicitNull(_, _, _) => buf.push_str("null"),
+            P::Alias(_, ref span, ref node) => {
+                self.expand_alias(&span.start, node,
+                                  |ctx, node| ctx.write_key(node, buf));
+            }
+        }
+    }
+
+    fn merge_mapping(&mut self, target: &mut MapItems,
         node: &'a Node<'a>)
     {
         match *node {
             P::Map(_, _, ref children, _) => {
                 let mut merge = None;
-                for (k, v) in children.iter() {
-                    let string_key = match parse_key(k, v, &mut merge) {
+                // Keys of this very mapping, the last duplicate wins
+                let mut own = IndexMap::new();
+                for &(ref k, ref v) in children.iter() {
+                    let key = self.parse_key(k, v, &mut merge);
+                    let (string_key, complex) = match key {
                         Ok(k) => k,
                         Err(None) => continue,  // merge key
                         Err(Some(e)) => {
@@ -217,13 +417,35 @@ impl<'a, 'b: 'a> Context<'a, 'b> {
                             continue;
                         }
                     };
+                    let pos = pos_for_node(k);
                     let value = self.process(v);
-                    if !target.contains_key(&string_key) {
-                        target.insert(string_key, value);
+                    let old = own.insert(string_key.clone(),
+                                         (pos.clone(), complex, value));
+                    match old {
+                        Some((old_pos, old_complex, _))
+                        if old_complex != complex => {
+                            self.err.add_error(Error::preprocess_error(&pos,
+                                format!("Key {:?} is ambiguous, it matches \
+                                    both a scalar and a non-scalar key, \
+                                    previously defined at {}:{}:{}",
+                                    string_key, old_pos.filename,
+                                    old_pos.line, old_pos.line_offset)));
+                        }
+                        Some((old_pos, _, _)) => {
+                            self.duplicate_key(&pos, &old_pos, &string_key);
+                        }
+                        None => {}
+                    }
+                }
+                let mut explicit = BTreeMap::new();
+                for (key, (pos, _, value)) in own.into_iter() {
+                    if !target.contains_key(&key) {
+                        target.insert(key.clone(), value);
                     }
+                    explicit.insert(key, pos);
                 }
                 match merge {
-                    Some(node) => self.merge_mapping(target, node),
+                    Some(node) => self.merge_key(target, node, &explicit),
                     _ => {}
                 }
             }
@@ -233,13 +455,13 @@ impl<'a, 'b: 'a> Context<'a, 'b> {
                     self.merge_mapping(target, item);
                 }
             }
-            P::Scalar(Some("!*Include"), _anch, ref val, ref tok) => {
-                self.merge_mapping_ast(target,
-                    self.options.include(&tok.start,
-                        &Include::File { filename: val }, self.err));
+            P::Scalar(..) if is_include(node, true) => {
+                let ast = self.process(node);
+                self.merge_mapping_ast(target, ast);
             }
-            P::Alias(_, _, ref node) => {
-                self.merge_mapping(target, node);
+            P::Alias(_, ref span, ref node) => {
+                self.expand_alias(&span.start, node,
+                                  |ctx, node| ctx.merge_mapping(target, node));
             }
             _ => {
                 self.err.add_error(Error::preprocess_error(&pos_for_node(node),
@@ -248,15 +470,61 @@ impl<'a, 'b: 'a> Context<'a, 'b> {
             }
         }
     }
+
+    /// Merges the value of the merge key `<<` into the mapping
+    ///
+    /// Same as `merge_mapping`, except keys included by `!*IncludeMap`
+    /// which are also `explicit` keys of the mapping are reported as
+    /// duplicates. Explicit keys are kept anyway.
+    fn merge_key(&mut self, target: &mut MapItems, node: &'a Node<'a>,
+        explicit: &BTreeMap<String, Pos>)
+    {
+        match *node {
+            P::Scalar(_, _, ref val, _, ref span)
+            if node.tag() == Some("!*IncludeMap") => {
+                match self.process(node) {
+                    Map(_, _, _, children) => {
+                        for (k, v) in children.into_iter() {
+                            if let Some(old_pos) = explicit.get(&k) {
+                                self.options.duplicate_key(&span.start,
+                                    format!("Key {:?} included from {:?} \
+                                        is already defined at {}:{}:{}",
+                                        k, val, old_pos.filename,
+                                        old_pos.line, old_pos.line_offset),
+                                    self.err);
+                            } else if !target.contains_key(&k) {
+                                target.insert(k, v);
+                            }
+                        }
+                    }
+                    ast => self.merge_mapping_ast(target, ast),
+                }
+            }
+            P::Seq(_, _, ref items, _) => {
+                for item in items.iter() {
+                    self.merge_key(target, item, explicit);
+                }
+            }
+            _ => self.merge_mapping(target, node),
+        }
+    }
+
+    fn duplicate_key(&mut self, pos: &Pos, old_pos: &Pos, key: &str) {
+        self.options.duplicate_key(pos,
+            format!("Duplicate key {:?}, previously defined at {}:{}:{}",
+                key, old_pos.filename, old_pos.line, old_pos.line_offset),
+            self.err);
+    }
+
     /// This is same as merge_mapping but for asts
     ///
     /// Used for includes, may be this function can be used for everything
     /// but we don't use it perhaps for efficiency
-    fn merge_mapping_ast(&mut self, target: &mut BTreeMap<String, Ast>,
+    fn merge_mapping_ast(&mut self, target: &mut MapItems,
         ast: Ast)
     {
         match ast {
-            Map(_, _, children) => {
+            Map(_, _, _, children) => {
                 for (k, v) in children.into_iter() {
                     if !target.contains_key(&k) {
                         // We don't make deep merging here, because other map
@@ -265,14 +533,14 @@ impl<'a, 'b: 'a> Context<'a, 'b> {
                     }
                 }
             }
-            Seq(_, _, lst) => {
+            Seq(_, _, _, lst) => {
                 // TODO(tailhook) check and assert on tags?
                 for item in lst.into_iter() {
                     self.merge_mapping_ast(target, item);
                 }
             }
             node => {
-                self.err.add_error(Error::preprocess_error(&node.pos(),
+                self.err.add_error(Error::preprocess_error(&node.span(),
                     "Value of merge key must be either mapping or \
                      list of mappings".to_string()));
             }
@@ -286,10 +554,12 @@ impl<'a, 'b: 'a> Context<'a, 'b> {
             P::Seq(_, _, ref children, _) => {
                 for item in children.iter() {
                     match *item {
-                        P::Seq(Some("!*Unpack"), _, ref children, _) => {
-                            for child in children.iter() {
-                                self.merge_sequence(target, child);
-                            }
+                        P::Seq(..) if is_unpack(item) => {
+                            self.unpack(target, item);
+                        }
+                        P::Alias(_, ref span, ref node) if is_unpack(node) => {
+                            self.expand_alias(&span.start, node,
+                                |ctx, node| ctx.unpack(target, node));
                         }
                         _ => {
                             let value = self.process(item);
@@ -298,16 +568,16 @@ impl<'a, 'b: 'a> Context<'a, 'b> {
                     }
                 }
             }
-            P::Alias(_, _, ref node) => {
-                self.merge_sequence(target, node);
+            P::Alias(_, ref span, ref node) => {
+                self.expand_alias(&span.start, node,
+                                  |ctx, node| ctx.merge_sequence(target, node));
             }
-            P::Scalar(Some("!*Include"), _anch, ref val, ref tok) => {
-                let ast = self.options.include(&tok.start,
-                    &Include::File { filename: val }, self.err);
+            P::Scalar(..) if is_include(node, false) => {
+                let ast = self.process(node);
                 // We don't make deep unpacking here, because other map
                 // is already unpacked
                 match ast {
-                    Seq(_, _, vec) => {
+                    Seq(_, _, _, vec) => {
                         target.extend(vec)
                     }
                     other => {
@@ -326,39 +596,434 @@ impl<'a, 'b: 'a> Context<'a, 'b> {
         }
     }
 
-    fn string_to_tag(&mut self, pos: &Pos, src: &Option<&'a str>)
-        -> Tag
-    {
-        match *src {
-            Some(val) => {
-                let mut pieces = val.splitn(3, '!');
-                assert!(pieces.next().unwrap() == "");
-                match (pieces.next().unwrap(), pieces.next()) {
-                    ("", None) => {
-                        self.err.add_error(Error::preprocess_error(pos,
-                            "Unexpected empty tag".to_string()));
-                        NonSpecific
-                    }
-                    (val, None) => {
-                        LocalTag(val.to_string())
-                    }
-                    ("", Some(_)) => {
-                        self.err.add_error(Error::preprocess_error(pos,
-                            "Global tags are unsupported yet".to_string()));
-                        NonSpecific
+    /// Appends items of every sequence inside `!*Unpack` node to target
+    fn unpack(&mut self, target: &mut Vec<Ast>, node: &'a Node<'a>) {
+        if let P::Seq(_, _, ref children, _) = *node {
+            for child in children.iter() {
+                self.merge_sequence(target, child);
+            }
+        }
+    }
+
+    /// Resolves `!*Include` tag
+    ///
+    /// The value is either a file name or `filename:key`, in the latter
+    /// case only the value of the `key` in the included mapping is used
+    fn include(&mut self, pos: &Pos, value: &'a str) -> Ast {
+        let (filename, key) = match split_include_key(value) {
+            Some(pair) => pair,
+            None => {
+                return self.options.include(pos,
+                    &Include::File { filename: value }, self.err);
+            }
+        };
+        let mut ast = self.options.include(pos,
+            &Include::SubKey { filename: filename, key: key }, self.err);
+        // Length of the key path looked up so far
+        let mut found = 0;
+        for name in key.split(':') {
+            let path = &key[..found];
+            found += if found == 0 { name.len() } else { name.len() + 1 };
+            ast = match ast {
+                Map(_, _, _, mut children) => {
+                    match children.remove(name) {
+                        Some(value) => value,
+                        None => {
+                            self.err.add_error(Error::preprocess_error(pos,
+                                format!("Key {:?} is not found in {:?}",
+                                    &key[..found], filename)));
+                            return Ast::void(pos);
+                        }
                     }
-                    (_, Some(_)) => {
-                        self.err.add_error(Error::preprocess_error(pos,
-                            "Tag prefixes are unsupported yet".to_string()));
-                        NonSpecific
+                }
+                _ if path.is_empty() => {
+                    self.err.add_error(Error::preprocess_error(pos,
+                        format!("File {:?} must contain a mapping to \
+                            include key {:?} from it", filename, key)));
+                    return Ast::void(pos);
+                }
+                _ => {
+                    self.err.add_error(Error::preprocess_error(pos,
+                        format!("Key {:?} in {:?} must be a mapping to \
+                            include key {:?} from it",
+                            path, filename, key)));
+                    return Ast::void(pos);
+                }
+            };
+        }
+        return ast;
+    }
+
+    /// Resolves `!*IncludeSeq` tag
+    fn include_seq(&mut self, pos: &Pos, value: &'a str) -> Ast {
+        match self.glob_pattern(pos, "!*IncludeSeq", value) {
+            Some((directory, prefix, suffix)) => {
+                self.options.include(pos, &Include::Sequence {
+                    directory: directory, prefix: prefix, suffix: suffix,
+                }, self.err)
+            }
+            None => Ast::void(pos),
+        }
+    }
+
+    /// Resolves `!*IncludeMap` tag
+    fn include_map(&mut self, pos: &Pos, value: &'a str) -> Ast {
+        match self.glob_pattern(pos, "!*IncludeMap", value) {
+            Some((directory, prefix, suffix)) => {
+                let ast = self.options.include(pos, &Include::Mapping {
+                    directory: directory, prefix: prefix, suffix: suffix,
+                }, self.err);
+                match ast {
+                    Map(span, tag, anchor, mut children) => {
+                        children.sort_keys();
+                        Map(span, tag, anchor, children)
                     }
+                    other => other,
                 }
             }
-            None => NonSpecific,
+            None => Ast::void(pos),
         }
     }
+
+    /// Splits a pattern like `dir/prefix*suffix` into its parts
+    ///
+    /// Only a single star in the last path component is supported
+    fn glob_pattern(&mut self, pos: &Pos, tag: &str, value: &'a str)
+        -> Option<(&'a str, &'a str, &'a str)>
+    {
+        let (directory, name) = match value.rfind('/') {
+            Some(0) => (&value[..1], &value[1..]),
+            Some(idx) => (&value[..idx], &value[idx+1..]),
+            None => ("", value),
+        };
+        let mut pieces = name.splitn(2, '*');
+        let prefix = pieces.next().unwrap();
+        match pieces.next() {
+            Some(suffix) if !suffix.contains('*') &&
+                            !directory.contains('*') =>
+            {
+                Some((directory, prefix, suffix))
+            }
+            _ => {
+                self.err.add_error(Error::preprocess_error(pos,
+                    format!("{} requires a pattern with a single star \
+                        in the file name, like `dir/*.yaml`, got {:?}",
+                        tag, value)));
+                None
+            }
+        }
+    }
+
+    fn string_to_tag(&mut self, pos: &Pos, src: Option<&str>) -> Tag {
+        let val = match src {
+            Some(val) => val,
+            None => return NonSpecific,
+        };
+        if val.starts_with("!<") {
+            // Verbatim tag `!<...>`, is never resolved using directives
+            let uri = &val[2..val.len()-1];
+            return match uri {
+                "" | "!" => {
+                    self.err.add_error(Error::preprocess_error(pos,
+                        "Unexpected empty tag".to_string()));
+                    NonSpecific
+                }
+                _ if uri.starts_with('!') => LocalTag(uri[1..].to_string()),
+                _ => GlobalTag(uri.to_string()),
+            };
+        }
+        let mut pieces = val.splitn(3, '!');
+        assert!(pieces.next().unwrap() == "");
+        let (handle, suffix) = match (pieces.next().unwrap(), pieces.next()) {
+            (suffix, None) => ("!", suffix),
+            ("", Some(suffix)) => ("!!", suffix),
+            (name, Some(suffix)) => (&val[..name.len()+2], suffix),
+        };
+        if suffix == "" {
+            self.err.add_error(Error::preprocess_error(pos,
+                "Unexpected empty tag".to_string()));
+            return NonSpecific;
+        }
+        match self.tag_handles.get(handle) {
+            Some(prefix) if prefix.starts_with('!') => {
+                LocalTag(format!("{}{}", &prefix[1..], suffix))
+            }
+            Some(prefix) => GlobalTag(format!("{}{}", prefix, suffix)),
+            None if handle == "!" => LocalTag(suffix.to_string()),
+            None if handle == "!!" => {
+                GlobalTag(format!("{}{}", CORE_SCHEMA_PREFIX, suffix))
+            }
+            None => {
+                self.err.add_error(Error::preprocess_error(pos,
+                    format!("Tag handle {:?} is not defined by \
+                        a %TAG directive", handle)));
+                NonSpecific
+            }
+        }
+    }
+
+    /// Checks if a plain scalar with this value would be a string
+    fn is_plain_string(&self, val: &str) -> bool {
+        return !is_core_null(val) && !is_core_bool(val) &&
+            self.resolve_bool(val).is_none() &&
+            !is_core_int(val) && !is_core_float(val);
+    }
+
+    /// Converts YAML 1.1 booleans like `yes` or `off` to `true`/`false`
+    ///
+    /// Single letter `y` and `n` are not treated as booleans, as most
+    /// YAML 1.1 implementations do
+    fn resolve_bool(&self, val: &str) -> Option<&'static str> {
+        if self.version != Version::Yaml11 {
+            return None;
+        }
+        match val {
+            "yes" | "Yes" | "YES" | "on" | "On" | "ON" => Some("true"),
+            "no" | "No" | "NO" | "off" | "Off" | "OFF" => Some("false"),
+            _ => None,
+        }
+    }
+
+    /// Applies `!!str`, `!!int` and other tags of the core schema
+    ///
+    /// Known tags are checked against the value and then replaced by
+    /// `NonSpecific`, so they are transparent for validators. Unknown
+    /// global tags are left as is.
+    fn core_schema(&mut self, ast: Ast) -> Ast {
+        let name = match *ast.tag() {
+            GlobalTag(ref tag) if tag.starts_with(CORE_SCHEMA_PREFIX) => {
+                tag[CORE_SCHEMA_PREFIX.len()..].to_string()
+            }
+            _ => return ast,
+        };
+        let ast = match (&name[..], ast) {
+            ("map", ast@Map(..)) => ast,
+            ("seq", ast@Seq(..)) => ast,
+            ("null", Null(pos, tag, anchor, kind)) => {
+                Null(pos, tag, anchor, kind)
+            }
+            ("null", Scalar(pos, tag, anchor, _, ref val)) if val == "" => {
+                Null(pos, tag, anchor, Explicit)
+            }
+            ("str", Scalar(pos, tag, anchor, _, val)) => {
+                Scalar(pos, tag, anchor, Quoted, val)
+            }
+            ("str", Null(pos, tag, anchor, _)) => {
+                Scalar(pos, tag, anchor, Quoted, "".to_string())
+            }
+            ("int", Scalar(pos, tag, anchor, _, val)) => {
+                if !is_core_int(&val) {
+                    self.err.add_error(Error::preprocess_error(&pos,
+                        format!("Value {:?} is not a valid integer", val)));
+                }
+                Scalar(pos, tag, anchor, Plain, val)
+            }
+            ("float", Scalar(pos, tag, anchor, _, val)) => {
+                if !is_core_int(&val) && !is_core_float(&val) {
+                    self.err.add_error(Error::preprocess_error(&pos,
+                        format!("Value {:?} is not a valid float", val)));
+                }
+                Scalar(pos, tag, anchor, Plain, val)
+            }
+            ("bool", Scalar(pos, tag, anchor, _, val)) => {
+                let val = match self.resolve_bool(&val) {
+                    Some(canonical) => canonical.to_string(),
+                    None => val,
+                };
+                if val != "true" && val != "false" {
+                    self.err.add_error(Error::preprocess_error(&pos,
+                        format!("Value {:?} is not a valid boolean, \
+                            expected `true` or `false`", val)));
+                }
+                Scalar(pos, tag, anchor, Plain, val)
+            }
+            ("map", ast) | ("seq", ast) | ("null", ast) | ("str", ast)
+            | ("int", ast) | ("float", ast) | ("bool", ast) => {
+                self.err.add_error(Error::preprocess_error(&ast.span(),
+                    format!("Tag !!{} can't be applied to {}", name, ast)));
+                ast
+            }
+            (_, ast) => return ast,
+        };
+        ast.with_tag(NonSpecific)
+    }
+}
+
+fn is_unpack(node: &Node) -> bool {
+    match *node {
+        P::Seq(..) => node.tag() == Some("!*Unpack"),
+        _ => false,
+    }
+}
+
+/// Checks if the node includes files, `!*IncludeMap` is checked only if
+/// `map` is set
+fn is_include(node: &Node, map: bool) -> bool {
+    match node.tag() {
+        Some("!*Include") | Some("!*IncludeSeq") => true,
+        Some("!*IncludeMap") => map,
+        _ => false,
+    }
+}
+
+fn is_core_tag(tag: &Tag, name: &str) -> bool {
+    match *tag {
+        GlobalTag(ref tag) => tag.starts_with(CORE_SCHEMA_PREFIX) &&
+                              &tag[CORE_SCHEMA_PREFIX.len()..] == name,
+        _ => false,
+    }
+}
+
+/// Checks null syntax of the YAML 1.2 core schema
+fn is_core_null(val: &str) -> bool {
+    match val {
+        "" | "~" | "null" | "Null" | "NULL" => true,
+        _ => false,
+    }
+}
+
+/// Checks boolean syntax of the YAML 1.2 core schema
+fn is_core_bool(val: &str) -> bool {
+    match val {
+        "true" | "True" | "TRUE" | "false" | "False" | "FALSE" => true,
+        _ => false,
+    }
+}
+
+/// Checks integer syntax of the YAML 1.2 core schema
+fn is_core_int(val: &str) -> bool {
+    let digits = |s: &str, radix| {
+        s.len() > 0 && s.chars().all(|c| c.is_digit(radix))
+    };
+    if val.starts_with("0x") {
+        digits(&val[2..], 16)
+    } else if val.starts_with("0o") {
+        digits(&val[2..], 8)
+    } else if val.starts_with('-') || val.starts_with('+') {
+        digits(&val[1..], 10)
+    } else {
+        digits(val, 10)
+    }
+}
+
+/// Checks float syntax of the YAML 1.2 core schema
+fn is_core_float(val: &str) -> bool {
+    match val {
+        ".inf" | "+.inf" | "-.inf" | ".Inf" | "+.Inf" | "-.Inf"
+        | ".INF" | "+.INF" | "-.INF" | ".nan" | ".NaN" | ".NAN" => true,
+        _ => val.contains(|c: char| c.is_digit(10)) &&
+             val.parse::<f64>().is_ok(),
+    }
+}
+
+/// Reads `%YAML` directive and returns version of the document
+///
+/// Versions 1.1 and 1.2 are supported, unknown minor versions are processed
+/// as the closest known one with a warning.
+fn yaml_version(directives: &[Directive], options: &Options,
+    err: &ErrorCollector)
+    -> Version
+{
+    let mut version = None;
+    for directive in directives.iter().filter(|d| d.name() == "YAML") {
+        if version.is_some() {
+            err.add_error(Error::preprocess_error(directive.pos(),
+                "Duplicate %YAML directive".to_string()));
+            continue;
+        }
+        let params = directive.params();
+        let mut numbers = params.get(0).unwrap_or(&"").splitn(2, '.')
+            .map(|x| x.parse::<u32>());
+        let parsed = match (params.len(), numbers.next(), numbers.next()) {
+            (1, Some(Ok(major)), Some(Ok(minor))) => (major, minor),
+            _ => {
+                err.add_error(Error::preprocess_error(directive.pos(),
+                    format!("Bad %YAML directive, expected version \
+                        like `1.2`, got {:?}", params.join(" "))));
+                continue;
+            }
+        };
+        version = Some(match parsed {
+            (1, 1) => Version::Yaml11,
+            (1, 2) => Version::Yaml12,
+            (1, minor) => {
+                let (known, name) = if minor < 1 {
+                    (Version::Yaml11, "1.1")
+                } else {
+                    (Version::Yaml12, "1.2")
+                };
+                options.warning(Error::warning(directive.pos(),
+                    format!("YAML version 1.{} is not supported, \
+                        processing as {}", minor, name)));
+                known
+            }
+            (major, minor) => {
+                err.add_error(Error::preprocess_error(directive.pos(),
+                    format!("YAML version {}.{} is not supported",
+                        major, minor)));
+                Version::Yaml12
+            }
+        });
+    }
+    return version.unwrap_or(Version::Yaml12);
 }
 
+/// Reads `%TAG` directives into a map from tag handle to tag prefix
+fn tag_handles<'a>(directives: &'a [Directive], err: &ErrorCollector)
+    -> BTreeMap<&'a str, &'a str>
+{
+    let mut handles = BTreeMap::new();
+    for directive in directives.iter().filter(|d| d.name() == "TAG") {
+        let params = directive.params();
+        if params.len() != 2 {
+            err.add_error(Error::preprocess_error(directive.pos(),
+                "%TAG directive requires a handle and a prefix".to_string()));
+            continue;
+        }
+        let (handle, prefix) = (params[0], params[1]);
+        let valid = handle == "!" || handle.len() >= 2 &&
+            handle.starts_with('!') && handle.ends_with('!') &&
+            !handle[1..handle.len()-1].contains('!');
+        if !valid {
+            err.add_error(Error::preprocess_error(directive.pos(),
+                format!("Bad tag handle {:?}, expected `!`, `!!` \
+                    or `!name!`", handle)));
+            continue;
+        }
+        if handles.contains_key(handle) {
+            err.add_error(Error::preprocess_error(directive.pos(),
+                format!("Tag handle {:?} is defined twice", handle)));
+            continue;
+        }
+        handles.insert(handle, prefix);
+    }
+    return handles;
+}
+
+/// Splits value of `!*Include` into file name and key path
+///
+/// The key path starts at the first colon after the extension of the file
+/// name, so colons in directories (`C:\x.yaml`) and before the extension
+/// (`a:b.yaml`) are a part of the file name. Returns `None` if there is no
+/// key path. The split only depends on the value, whether the file exists
+/// is up to the include handler.
+fn split_include_key(value: &str) -> Option<(&str, &str)> {
+    let name = value.rfind(|c| c == '/' || c == '\\')
+        .map(|idx| idx + 1).unwrap_or(0);
+    let ext = match value[name..].find('.') {
+        Some(idx) => name + idx,
+        None => return None,
+    };
+    let colon = match value[ext..].find(':') {
+        Some(idx) => ext + idx,
+        None => return None,
+    };
+    let (filename, key) = (&value[..colon], &value[colon+1..]);
+    if key.is_empty() {
+        return None;
+    }
+    return Some((filename, key));
+}
 
 ///  Preprocess AST
 ///
@@ -367,11 +1032,16 @@ impl<'a, 'b: 'a> Context<'a, 'b> {
 ///  * anchor substitution
 ///  * resolving merge keys `<<` and `!*Unpack`
 ///  * resolving includes `!*Include` and similar
+///  * applying `%YAML` and `%TAG` directives
 ///
 pub fn process(opt: &Options, doc: Document, err: &ErrorCollector) -> Ast {
     let mut ctx = Context {
         options: opt,
-        directives: doc.directives,
+        version: yaml_version(&doc.directives, opt, err),
+        tag_handles: tag_handles(&doc.directives, err),
+        alias_stack: Vec::new(),
+        expanded_nodes: 0,
+        expansion_aborted: false,
         err: err,
     };
     return ctx.process(&doc.root);
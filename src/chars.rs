@@ -43,3 +43,8 @@ fn test_printable() {
     assert!(is_printable('a'));
     assert!(is_printable('я'));
 }
+
+pub fn is_uri_char(ch: char) -> bool {
+    return is_tag_char(ch) || ch == '!' || ch == ','
+        || ch == '[' || ch == ']';
+}
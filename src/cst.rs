@@ -0,0 +1,523 @@
+//! Concrete syntax tree, used to edit configuration files in place
+//!
+//! Unlike `ast`, the tree keeps the original text of the document, so the
+//! comments and formatting of the parts which are not edited are preserved
+//! byte-for-byte.
+//!
+//! This is not a token-level tree: nodes only record the spans of values
+//! in the source text, and comments and whitespace have no nodes of their
+//! own. Every edit splices the new text into the source and parses the
+//! whole document again, so an edit costs O(size of the document).
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! use quire::cst::SyntaxTree;
+//!
+//! let mut tree = SyntaxTree::parse("config.yaml", &data)?;
+//! tree.set(&["version"], "1.2.3")?;
+//! tree.insert(&["dependencies"], "quire", "0.3")?;
+//! tree.remove(&["obsolete"])?;
+//! write!(file, "{}", tree)?;
+//! ```
+//!
+//! Paths are sequences of mapping keys, numeric components select
+//! items of sequences. Aliases are never followed.
+//!
+//! Values and keys are yaml text, i.e. they are inserted as is, so strings
+//! which might be ambiguous must be quoted by the caller. Only single-line
+//! values are supported.
+
+use std::rc::Rc;
+use std::fmt;
+
+use errors::Error;
+use parser::{parse, Node};
+use tokenizer::Span;
+
+
+/// Node of the concrete syntax tree
+///
+/// Span covers the value itself, i.e. tags, anchors and surrounding
+/// whitespace and comments are not included.
+#[derive(Debug)]
+pub enum SyntaxNode {
+    /// Mapping, pairs of key and value are in the order of the source
+    Map(Span, Vec<(SyntaxNode, SyntaxNode)>),
+    /// Sequence
+    Seq(Span, Vec<SyntaxNode>),
+    /// Scalar (including explicit null) and it's value
+    Scalar(Span, String),
+    /// Value that is omitted in the source
+    Null(Span),
+    /// Alias, the aliased node is not included
+    Alias(Span),
+}
+
+impl SyntaxNode {
+    fn from_node(node: &Node) -> SyntaxNode {
+        match *node {
+            Node::Map(_, _, ref children, _) => {
+                SyntaxNode::Map(node.span(), children.iter()
+                    .map(|&(ref k, ref v)| {
+                        (SyntaxNode::from_node(k), SyntaxNode::from_node(v))
+                    })
+                    .collect())
+            }
+            Node::Seq(_, _, ref children, _) => {
+                SyntaxNode::Seq(node.span(), children.iter()
+                    .map(SyntaxNode::from_node)
+                    .collect())
+            }
+            Node::Scalar(_, _, ref value, _, _) => {
+                SyntaxNode::Scalar(node.span(), value.clone())
+            }
+            Node::ImplicitNull(..) => SyntaxNode::Null(node.span()),
+            Node::Alias(..) => SyntaxNode::Alias(node.span()),
+        }
+    }
+    /// Span of the node in the source text
+    pub fn span(&self) -> &Span {
+        match *self {
+            SyntaxNode::Map(ref span, _) => span,
+            SyntaxNode::Seq(ref span, _) => span,
+            SyntaxNode::Scalar(ref span, _) => span,
+            SyntaxNode::Null(ref span) => span,
+            SyntaxNode::Alias(ref span) => span,
+        }
+    }
+}
+
+/// Syntax tree of a single yaml document, along with its source text
+///
+/// The tree is reparsed after each edit, so the edit that makes the document
+/// invalid is rejected and the tree is left intact.
+pub struct SyntaxTree {
+    filename: Rc<String>,
+    source: String,
+    root: SyntaxNode,
+}
+
+fn entry_index(pairs: &[(SyntaxNode, SyntaxNode)], key: &str)
+    -> Option<usize>
+{
+    // The last one of the duplicate keys is used, just like in `ast`
+    pairs.iter().rposition(|&(ref k, _)| match *k {
+        SyntaxNode::Scalar(_, ref value) => value == key,
+        _ => false,
+    })
+}
+
+fn child<'x>(node: &'x SyntaxNode, key: &str)
+    -> Result<&'x SyntaxNode, Error>
+{
+    let found = match *node {
+        SyntaxNode::Map(_, ref pairs) => {
+            entry_index(pairs, key).map(|idx| &pairs[idx].1)
+        }
+        SyntaxNode::Seq(_, ref items) => {
+            key.parse::<usize>().ok().and_then(|idx| items.get(idx))
+        }
+        SyntaxNode::Alias(ref span) => {
+            return Err(Error::edit_error(span,
+                format!("Can't follow alias to find {:?}", key)));
+        }
+        _ => None,
+    };
+    found.ok_or_else(|| Error::edit_error(node.span(),
+        format!("Key {:?} is not found", key)))
+}
+
+fn check_single_line(span: &Span, text: &str) -> Result<(), Error> {
+    if text.is_empty() || text.contains('\n') {
+        return Err(Error::edit_error(span,
+            format!("Expected non-empty single-line value, got {:?}",
+                    text)));
+    }
+    Ok(())
+}
+
+impl SyntaxTree {
+    /// Parse the yaml document
+    pub fn parse(filename: &str, data: &str) -> Result<SyntaxTree, Error> {
+        SyntaxTree::parse_source(Rc::new(filename.to_string()),
+                                 data.to_string())
+    }
+    fn parse_source(filename: Rc<String>, source: String)
+        -> Result<SyntaxTree, Error>
+    {
+        let root = parse(filename.clone(), &source,
+            |doc| SyntaxNode::from_node(&doc.root))?;
+        Ok(SyntaxTree {
+            filename: filename,
+            source: source,
+            root: root,
+        })
+    }
+    /// The root node of the document
+    pub fn root(&self) -> &SyntaxNode {
+        &self.root
+    }
+    /// Full text of the document, including all the edits
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+    /// Source text of the node
+    pub fn text(&self, node: &SyntaxNode) -> &str {
+        let span = node.span();
+        &self.source[span.start.offset..span.end.offset]
+    }
+    /// Find the node by path
+    pub fn get(&self, path: &[&str]) -> Option<&SyntaxNode> {
+        self.find(path).ok()
+    }
+    fn find(&self, path: &[&str]) -> Result<&SyntaxNode, Error> {
+        let mut node = &self.root;
+        for key in path {
+            node = child(node, key)?;
+        }
+        Ok(node)
+    }
+    fn is_flow(&self, span: &Span) -> bool {
+        match self.source[span.start.offset..].chars().next() {
+            Some('{') | Some('[') => true,
+            _ => false,
+        }
+    }
+    /// Start of the tags and anchors of the node which starts at `start`
+    fn properties_start(&self, mut start: usize) -> usize {
+        loop {
+            let spaces = self.source[..start].chars().rev()
+                .take_while(|&c| c == ' ' || c == '\t').count();
+            let before = &self.source[..start - spaces];
+            let word_start = before
+                .rfind(|c| " \t\r\n{[,".contains(c))
+                .map(|x| x + 1).unwrap_or(0);
+            match before[word_start..].chars().next() {
+                Some('&') | Some('!') => start = word_start,
+                _ => return start,
+            }
+        }
+    }
+    /// Returns a new tree with the text between `start` and `end` replaced
+    fn splice(&self, start: usize, end: usize, text: &str)
+        -> Result<SyntaxTree, Error>
+    {
+        let mut source = String::with_capacity(
+            self.source.len() - (end - start) + text.len());
+        source.push_str(&self.source[..start]);
+        source.push_str(text);
+        source.push_str(&self.source[end..]);
+        SyntaxTree::parse_source(self.filename.clone(), source)
+    }
+
+    /// Replace the value at `path`
+    ///
+    /// Tag and anchor of the node are kept
+    pub fn set(&mut self, path: &[&str], value: &str) -> Result<(), Error> {
+        let tree = {
+            let node = self.find(path)?;
+            let span = node.span();
+            check_single_line(span, value)?;
+            let (start, end) = (span.start.offset, span.end.offset);
+            let needs_space = start == end && !self.source[..start]
+                .ends_with(|c: char| c.is_whitespace());
+            if needs_space {
+                self.splice(start, end, &format!(" {}", value))?
+            } else {
+                self.splice(start, end, value)?
+            }
+        };
+        *self = tree;
+        Ok(())
+    }
+
+    /// Add a `key` to the end of the mapping at `path`
+    ///
+    /// It's an error if the key already exists
+    pub fn insert(&mut self, path: &[&str], key: &str, value: &str)
+        -> Result<(), Error>
+    {
+        let tree = {
+            let node = self.find(path)?;
+            let (span, pairs) = match *node {
+                SyntaxNode::Map(ref span, ref pairs) => (span, pairs),
+                _ => {
+                    return Err(Error::edit_error(node.span(),
+                        format!("Mapping expected to insert key {:?}", key)));
+                }
+            };
+            check_single_line(span, key)?;
+            check_single_line(span, value)?;
+            if entry_index(pairs, key).is_some() {
+                return Err(Error::edit_error(span,
+                    format!("Key {:?} already exists", key)));
+            }
+            let tree = if self.is_flow(span) {
+                match pairs.last() {
+                    Some(&(_, ref last)) => {
+                        let pos = last.span().end.offset;
+                        self.splice(pos, pos,
+                            &format!(", {}: {}", key, value))?
+                    }
+                    None => {
+                        // Right before the closing brace
+                        let pos = span.end.offset - 1;
+                        self.splice(pos, pos, &format!("{}: {}", key, value))?
+                    }
+                }
+            } else {
+                // Next line after the last value, so the trailing comment
+                // stays on it's own line
+                let indent = span.start.line_offset - 1;
+                let end = span.end.offset;
+                match self.source[end..].find('\n') {
+                    Some(nl) => {
+                        let pos = end + nl + 1;
+                        self.splice(pos, pos, &format!("{:indent$}{}: {}\n",
+                            "", key, value, indent=indent))?
+                    }
+                    None => {
+                        let pos = self.source.len();
+                        self.splice(pos, pos, &format!("\n{:indent$}{}: {}",
+                            "", key, value, indent=indent))?
+                    }
+                }
+            };
+            let mut new_path = path.to_vec();
+            new_path.push(key);
+            if tree.find(&new_path).is_err() {
+                return Err(Error::edit_error(span,
+                    format!("Key {:?} is parsed differently when inserted, \
+                        probably it must be quoted", key)));
+            }
+            tree
+        };
+        *self = tree;
+        Ok(())
+    }
+
+    /// Remove the key (the last component of the `path`) from the mapping
+    ///
+    /// Whole lines of the entry are removed from the block mapping,
+    /// including the trailing comment. If the key is the only one in the
+    /// mapping, the mapping is replaced by `{}`.
+    pub fn remove(&mut self, path: &[&str]) -> Result<(), Error> {
+        let tree = {
+            let (key, parent_path) = match path.split_last() {
+                Some((key, parent_path)) => (key, parent_path),
+                None => {
+                    return Err(Error::edit_error(self.root.span(),
+                        "The root node can't be removed".to_string()));
+                }
+            };
+            let parent = self.find(parent_path)?;
+            child(parent, key)?;
+            let (span, pairs) = match *parent {
+                SyntaxNode::Map(ref span, ref pairs) => (span, pairs),
+                _ => {
+                    return Err(Error::edit_error(parent.span(),
+                        format!("Only keys of the mapping can be removed, \
+                            can't remove {:?}", key)));
+                }
+            };
+            let idx = entry_index(pairs, key).unwrap();
+            let (ref k, ref v) = pairs[idx];
+            // Tags and anchors of the key are removed along with it
+            let start = self.properties_start(k.span().start.offset);
+            let next_start = pairs.get(idx + 1).map(|&(ref next, _)| {
+                self.properties_start(next.span().start.offset)
+            });
+            if pairs.len() == 1 {
+                self.splice(span.start.offset, span.end.offset, "{}")?
+            } else if self.is_flow(span) {
+                match next_start {
+                    Some(next_start) => self.splice(start, next_start, "")?,
+                    None => {
+                        self.splice(pairs[idx-1].1.span().end.offset,
+                            v.span().end.offset, "")?
+                    }
+                }
+            } else {
+                let line_start = self.source[..start].rfind('\n')
+                    .map(|x| x + 1).unwrap_or(0);
+                if self.source[line_start..start].trim().is_empty() {
+                    let end = v.span().end.offset;
+                    let line_end = self.source[end..].find('\n')
+                        .map(|x| end + x + 1)
+                        .unwrap_or(self.source.len());
+                    self.splice(line_start, line_end, "")?
+                } else {
+                    // Key shares the line with `- ` or `? `, so we remove
+                    // up to the next key
+                    match next_start {
+                        Some(next) => self.splice(start, next, "")?,
+                        None => {
+                            return Err(Error::edit_error(k.span(),
+                                format!("Key {:?} can't be removed", key)));
+                        }
+                    }
+                }
+            }
+        };
+        *self = tree;
+        Ok(())
+    }
+}
+
+impl fmt::Display for SyntaxTree {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(&self.source)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use errors::Error;
+    use super::SyntaxTree;
+
+    fn edit<F>(source: &str, f: F) -> String
+        where F: FnOnce(&mut SyntaxTree)
+    {
+        let mut tree = SyntaxTree::parse("<inline text>", source).unwrap();
+        f(&mut tree);
+        tree.to_string()
+    }
+
+    fn edit_err<F>(source: &str, f: F) -> String
+        where F: FnOnce(&mut SyntaxTree) -> Result<(), Error>
+    {
+        let mut tree = SyntaxTree::parse("<inline text>", source).unwrap();
+        let err = f(&mut tree).unwrap_err().to_string();
+        assert_eq!(tree.source(), source);
+        err
+    }
+
+    #[test]
+    fn test_untouched() {
+        let src = "# config\nversion: 1.0  # bump me\n\n\
+                   items:\n- a\n- {b: c}\n";
+        assert_eq!(edit(src, |_| {}), src);
+    }
+
+    #[test]
+    fn test_get() {
+        let tree = SyntaxTree::parse("<inline text>",
+            "a:\n  b: [x, 'y']\n").unwrap();
+        assert_eq!(tree.text(tree.get(&["a", "b", "1"]).unwrap()), "'y'");
+        assert_eq!(tree.text(tree.get(&["a"]).unwrap()), "b: [x, 'y']");
+        assert!(tree.get(&["a", "c"]).is_none());
+    }
+
+    #[test]
+    fn test_set() {
+        assert_eq!(edit("# config\nversion: 1.0  # bump me\nname: x\n",
+            |t| t.set(&["version"], "1.1").unwrap()),
+            "# config\nversion: 1.1  # bump me\nname: x\n");
+    }
+
+    #[test]
+    fn test_set_nested() {
+        assert_eq!(edit("deps:\n- name: a  # first\n  version: !!str 1\n",
+            |t| t.set(&["deps", "0", "version"], "2").unwrap()),
+            "deps:\n- name: a  # first\n  version: !!str 2\n");
+    }
+
+    #[test]
+    fn test_set_null() {
+        assert_eq!(edit("a:\nb: {x: }\n", |t| {
+                t.set(&["a"], "1").unwrap();
+                t.set(&["b", "x"], "2").unwrap();
+            }),
+            "a: 1\nb: {x: 2}\n");
+    }
+
+    #[test]
+    fn test_set_collection() {
+        assert_eq!(edit("a:\n  b: 1\n  c: 2\nd: 3\n",
+            |t| t.set(&["a"], "[1, 2]").unwrap()),
+            "a:\n  [1, 2]\nd: 3\n");
+    }
+
+    #[test]
+    fn test_insert_block() {
+        assert_eq!(edit("a:\n  b: 1  # comment\n# trailer\n",
+            |t| t.insert(&["a"], "c", "2").unwrap()),
+            "a:\n  b: 1  # comment\n  c: 2\n# trailer\n");
+    }
+
+    #[test]
+    fn test_insert_at_eof() {
+        assert_eq!(edit("a: 1", |t| t.insert(&[], "b", "2").unwrap()),
+            "a: 1\nb: 2");
+    }
+
+    #[test]
+    fn test_insert_flow() {
+        assert_eq!(edit("a: {x: 1}\nb: {}\n", |t| {
+                t.insert(&["a"], "y", "2").unwrap();
+                t.insert(&["b"], "z", "3").unwrap();
+            }),
+            "a: {x: 1, y: 2}\nb: {z: 3}\n");
+    }
+
+    #[test]
+    fn test_remove() {
+        assert_eq!(edit("a: 1\nb:  # comment\n  x: 1\nc: 3\n",
+            |t| t.remove(&["b"]).unwrap()),
+            "a: 1\nc: 3\n");
+    }
+
+    #[test]
+    fn test_remove_first_in_seq() {
+        assert_eq!(edit("- a: 1\n  b: 2\n",
+            |t| t.remove(&["0", "a"]).unwrap()),
+            "- b: 2\n");
+    }
+
+    #[test]
+    fn test_remove_with_properties() {
+        assert_eq!(edit("&x a: 1\nb: 2\n",
+            |t| t.remove(&["a"]).unwrap()),
+            "b: 2\n");
+        assert_eq!(edit("- !!str a: 1\n  b: 2\n",
+            |t| t.remove(&["0", "a"]).unwrap()),
+            "- b: 2\n");
+        assert_eq!(edit("{&x a: 1, !k b: 2, c: 3}", |t| {
+                t.remove(&["a"]).unwrap();
+                t.remove(&["c"]).unwrap();
+            }),
+            "{!k b: 2}");
+    }
+
+    #[test]
+    fn test_remove_flow() {
+        assert_eq!(edit("{a: 1, b: 2, c: 3}", |t| {
+                t.remove(&["b"]).unwrap();
+                t.remove(&["c"]).unwrap();
+            }),
+            "{a: 1}");
+    }
+
+    #[test]
+    fn test_remove_last_key() {
+        assert_eq!(edit("a:\n  b: 1\nc: 2\n",
+            |t| t.remove(&["a", "b"]).unwrap()),
+            "a:\n  {}\nc: 2\n");
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(edit_err("a: {b: 1}", |t| t.set(&["a", "c"], "1")),
+            "<inline text>:1:4-1:10: Edit Error: Key \"c\" is not found");
+        assert_eq!(edit_err("a: &x {b: 1}\nc: *x",
+            |t| t.set(&["c", "b"], "1")),
+            "<inline text>:2:4-2:6: Edit Error: \
+             Can't follow alias to find \"b\"");
+        assert_eq!(edit_err("a: 1", |t| t.insert(&[], "a", "2")),
+            "<inline text>:1:1-1:5: Edit Error: Key \"a\" already exists");
+        assert_eq!(edit_err("a: 1", |t| t.set(&["a"], "\"x")),
+            "<inline text>:1:4: Tokenizer Error: \
+             Unclosed double-quoted string");
+    }
+}
@@ -9,7 +9,7 @@ use super::ast::Tag;
 use super::ast::NullKind;
 use super::ast::Ast;
 use super::errors::{Error, ErrorCollector};
-use super::tokenizer::Pos;
+use super::tokenizer::Span;
 use self::ParserState::*;
 
 
@@ -64,9 +64,9 @@ enum ParserState {
     Node(Ast),
     Map(Vec<(String, Ast)>),  // used only in read_map_elt_key/elt_val
     Seq(Vec<Ast>),  // used only in read_seq_elt
-    ByteSeq(Pos, Vec<u8>),  // used for decoding Path
-    Byte(Pos, u8),     // used for decoding Path
-    Key(Pos, String),
+    ByteSeq(Span, Vec<u8>),  // used for decoding Path
+    Byte(Span, u8),     // used for decoding Path
+    Key(Span, String),
 }
 
 pub struct YamlDecoder {
@@ -92,7 +92,8 @@ impl YamlDecoder {
               E: Display
     {
         match self.state {
-            Node(A::Scalar(ref pos, _, _, ref val)) | Key(ref pos, ref val) => {
+            Node(A::Scalar(ref pos, _, _, _, ref val))
+            | Key(ref pos, ref val) => {
                 match FromStr::from_str(&val[..]) {
                     Ok(x) => Ok(x),
                     Err(err) => {
@@ -103,7 +104,7 @@ impl YamlDecoder {
                 }
             }
             Node(ref node) => {
-                return Err(Error::decode_error(&node.pos(), &self.path,
+                return Err(Error::decode_error(&node.span(), &self.path,
                     format!("Expected scalar, got {}", node)));
             }
             Byte(ref pos, _) => {
@@ -124,9 +125,9 @@ impl Decoder for YamlDecoder {
     type Error = Error;
     fn read_nil(&mut self) -> DecodeResult<()> {
         match self.state {
-            Node(A::Null(_, _, _)) => return Ok(()),
+            Node(A::Null(_, _, _, _)) => return Ok(()),
             Node(ref node) => {
-                self.err.add_error(Error::decode_error(&node.pos(), &self.path,
+                self.err.add_error(Error::decode_error(&node.span(), &self.path,
                     format!("Expected null")));
                 return Ok(())
             }
@@ -192,11 +193,12 @@ impl Decoder for YamlDecoder {
     fn read_str(&mut self) -> DecodeResult<String> {
         // TODO(tailhook) Is it fast enought?
         match self.state {
-            Node(A::Scalar(ref pos, _, _, ref val)) | Key(ref pos, ref val) => {
+            Node(A::Scalar(ref pos, _, _, _, ref val))
+            | Key(ref pos, ref val) => {
                 return Ok(val.clone());
             }
             Node(ref node) => {
-                return Err(Error::decode_error(&node.pos(), &self.path,
+                return Err(Error::decode_error(&node.span(), &self.path,
                     format!("Expected scalar, got {}", node)));
             }
             Byte(ref pos, _) => {
@@ -235,16 +237,21 @@ impl Decoder for YamlDecoder {
                             }
                         }
                         if idx.is_none() {
-                            return Err(Error::decode_error(&node.pos(),
+                            return Err(Error::decode_error(&node.span(),
                                 &self.path,
                                 format!("{} is not one of {:?}", tag, names)));
                         }
                         self.skip_tag = true;
                     }
-                    &Tag::GlobalTag(_) => unimplemented!(),
+                    &Tag::GlobalTag(ref tag) => {
+                        return Err(Error::decode_error(&node.span(),
+                            &self.path,
+                            format!("Global tag !<{}> can't be used to \
+                                select one of {:?}", tag, names)));
+                    }
                 }
             }
-            Node(A::Scalar(ref pos, _, _, ref value)) => {
+            Node(A::Scalar(ref pos, _, _, _, ref value)) => {
                 let programmatic_name = value.replace("-", "_");
                 for (i, name) in names.iter().enumerate() {
                     if *name == &value[..] ||
@@ -258,7 +265,7 @@ impl Decoder for YamlDecoder {
                 }
             }
             Node(ref node) => {
-                return Err(Error::decode_error(&node.pos(), &self.path,
+                return Err(Error::decode_error(&node.span(), &self.path,
                     format!("Scalar or tagged value expected")));
             }
             Byte(ref pos, _) => {
@@ -306,10 +313,10 @@ impl Decoder for YamlDecoder {
         where F: FnOnce(&mut Self) -> DecodeResult<T>
     {
         match self.state {
-            Node(A::Map(_, _, _)) => {}
-            Node(A::Null(ref pos, _, _)) => {
+            Node(A::Map(_, _, _, _)) => {}
+            Node(A::Null(ref pos, _, _, _)) => {
                 return f(&mut YamlDecoder {
-                    state: Node(A::Map(pos.clone(), Tag::NonSpecific,
+                    state: Node(A::Map(pos.clone(), Tag::NonSpecific, None,
                         Default::default())),
                     skip_tag: false,
                     err: self.err.clone(),
@@ -317,7 +324,7 @@ impl Decoder for YamlDecoder {
                 });
             }
             Node(ref node) => {
-                return Err(Error::decode_error(&node.pos(), &self.path,
+                return Err(Error::decode_error(&node.span(), &self.path,
                     "Mapping expected".to_string()));
             }
             Byte(_, _) => unimplemented!(),
@@ -332,12 +339,12 @@ impl Decoder for YamlDecoder {
         -> DecodeResult<T>
         where F: FnOnce(&mut Self) -> DecodeResult<T>
     {
-        if let Node(A::Map(ref pos, _, ref mut children)) = self.state {
+        if let Node(A::Map(ref pos, _, _, ref mut children)) = self.state {
             match children.remove(&name.to_string()) {
                 None => {
                     return f(&mut YamlDecoder {
                         state: Node(A::Null(pos.clone(), Tag::NonSpecific,
-                            NullKind::Implicit)),
+                            None, NullKind::Implicit)),
                         skip_tag: false,
                         err: self.err.clone(),
                         path: format!("{}.{}", self.path, name),
@@ -385,8 +392,8 @@ impl Decoder for YamlDecoder {
         where F: FnOnce(&mut Self, bool) -> Result<T, Error>
     {
         match self.state {
-            Node(A::Null(_, Tag::NonSpecific, _)) => f(self, false),
-            Node(A::Null(_, _, _)) if self.skip_tag => f(self, false),
+            Node(A::Null(_, Tag::NonSpecific, _, _)) => f(self, false),
+            Node(A::Null(_, _, _, _)) if self.skip_tag => f(self, false),
             Node(_) => f(self, true),
             Key(_, _) => unimplemented!(),
             Byte(_, _) => unimplemented!(),
@@ -399,12 +406,12 @@ impl Decoder for YamlDecoder {
         where F: FnOnce(&mut Self, usize) -> Result<T, Error>
     {
         let items = match self.state {
-            Node(A::Seq(_, _, ref mut children)) => {
+            Node(A::Seq(_, _, _, ref mut children)) => {
                 let mut ch = Default::default();
                 swap(children, &mut ch);
                 ch
             }
-            Node(A::Scalar(ref pos, _, _, ref val)) => {
+            Node(A::Scalar(ref pos, _, _, _, ref val)) => {
                 let bytes = val.as_bytes();
                 return f(&mut YamlDecoder {
                     state: ByteSeq(pos.clone(), bytes.to_vec()),
@@ -413,9 +420,9 @@ impl Decoder for YamlDecoder {
                     path: self.path.clone(),
                 }, bytes.len());
             }
-            Node(A::Null(_, _, _)) => Vec::new(),
+            Node(A::Null(_, _, _, _)) => Vec::new(),
             Node(ref node) => {
-                return Err(Error::decode_error(&node.pos(), &self.path,
+                return Err(Error::decode_error(&node.span(), &self.path,
                     "Sequence expected".to_string()));
             }
             Byte(_, _) => unimplemented!(),
@@ -470,14 +477,14 @@ impl Decoder for YamlDecoder {
         where F: FnOnce(&mut Self, usize) -> Result<T, Error>
     {
         let items = match self.state {
-            Node(A::Map(_, _, ref mut children)) => {
+            Node(A::Map(_, _, _, ref mut children)) => {
                 let mut ch = Default::default();
                 swap(children, &mut ch);
                 ch.into_iter().collect()
             }
-            Node(A::Null(_, _, _)) => Vec::new(),
+            Node(A::Null(_, _, _, _)) => Vec::new(),
             Node(ref node) => {
-                return Err(Error::decode_error(&node.pos(), &self.path,
+                return Err(Error::decode_error(&node.span(), &self.path,
                     "Mapping expected".to_string()));
             }
             Byte(_, _) => unimplemented!(),
@@ -500,7 +507,7 @@ impl Decoder for YamlDecoder {
         if let Map(ref mut vec) = self.state {
             let (ref key, ref val) = (*vec)[0];
             return f(&mut YamlDecoder {
-                state: Key(val.pos().clone(), key.clone()),
+                state: Key(val.span(), key.clone()),
                 skip_tag: false,
                 err: self.err.clone(),
                 path: self.path.clone() + ".",
@@ -527,7 +534,7 @@ impl Decoder for YamlDecoder {
 
     fn error(&mut self, err: &str) -> Error {
         let pos = match self.state {
-            Node(ref node) => node.pos().clone(),
+            Node(ref node) => node.span(),
             Byte(_, _) => unimplemented!(),
             Map(_) | Seq(_) | ByteSeq(_, _) => unimplemented!(),
             Key(ref pos, _) => pos.clone(),
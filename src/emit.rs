@@ -2,6 +2,8 @@ use std::io::Result as IoResult;
 use std::io::Error as IoError;
 use std::io::Write;
 use std::string::ToString;
+use std::borrow::Cow;
+use std::collections::HashSet;
 use rustc_serialize::{Encodable, Encoder};
 
 use super::parser::Node;
@@ -14,6 +16,8 @@ use super::ast::Tag::{LocalTag, GlobalTag, NonSpecific};
 use super::ast::Tag as AstTag;
 use super::ast::Ast;
 use super::ast::Ast as A;
+use super::ast::Anchor as AstAnchor;
+use super::ast::CORE_SCHEMA_PREFIX;
 use super::ast::NullKind::{Explicit, Implicit};
 
 pub type Tag<'a> = &'a str;
@@ -71,15 +75,26 @@ struct Context<'a> {
     stack: Vec<(State, usize)>,
     state: State,
     line: Line,
+    /// Anchors already written by `emit_ast`, aliases to other anchors
+    /// are expanded
+    defined_anchors: HashSet<String>,
 }
 
 
-fn tag_as_string<'x>(tag: &'x AstTag) -> Option<&'x str> {
-    return (match *tag {
+/// Returns tag as written after the leading exclamation mark
+///
+/// Global tags use either secondary handle `!!` for core schema tags or
+/// verbatim syntax `!<...>` for everything else
+fn tag_as_string<'x>(tag: &'x AstTag) -> Option<Cow<'x, str>> {
+    return match *tag {
         NonSpecific => None,
-        LocalTag(ref value) => Some(value),
-        GlobalTag(_) => unimplemented!(),
-    }).map(|t| &t[..]);
+        LocalTag(ref value) => Some(Cow::Borrowed(&value[..])),
+        GlobalTag(ref value) if value.starts_with(CORE_SCHEMA_PREFIX) => {
+            Some(Cow::Owned(format!("!{}",
+                &value[CORE_SCHEMA_PREFIX.len()..])))
+        }
+        GlobalTag(ref value) => Some(Cow::Owned(format!("<{}>", value))),
+    };
 }
 
 
@@ -91,6 +106,7 @@ impl<'a> Context<'a> {
             stack: Vec::new(),
             state: S::New,
             line: L::Start,
+            defined_anchors: HashSet::new(),
         };
     }
 
@@ -164,26 +180,30 @@ impl<'a> Context<'a> {
         return Ok(());
     }
 
+    /// Writes tag and anchor of the node, if any
+    ///
+    /// When `space` is true they are followed by a space, otherwise the
+    /// node continues on the next line (or with a space-separated null)
     pub fn emit_tag_anchor(&mut self, tag: Option<Tag>,
-        anchor: Option<Tag>, space: bool) -> IoResult<()> {
-        match tag {
-            Some(x) => {
-                self.stream.write(b"!")?;
-                self.stream.write(x.as_bytes())?;
-                if space {
-                    self.stream.write(b" ")?;
-                } else {
-                    self.line = L::Start;
-                    return self.stream.write(b"\n").map(|_| ());
-                }
-            }
-            None => {}
+        anchor: Option<Anchor>, space: bool) -> IoResult<()> {
+        if tag.is_none() && anchor.is_none() {
+            return Ok(());
         }
-        match anchor {
-            Some(_) => {
-                unimplemented!();
+        if let Some(x) = tag {
+            self.stream.write_all(b"!")?;
+            self.stream.write_all(x.as_bytes())?;
+            if anchor.is_some() {
+                self.stream.write_all(b" ")?;
             }
-            None => {}
+        }
+        if let Some(x) = anchor {
+            self.stream.write_all(b"&")?;
+            self.stream.write_all(x.as_bytes())?;
+        }
+        if space {
+            self.stream.write_all(b" ")?;
+        } else {
+            self.line = L::AfterScalar;
         }
         return Ok(());
     }
@@ -198,7 +218,13 @@ impl<'a> Context<'a> {
                 S::Fin }
             (S::New, Opcode::Null(tag, anchor, style)) => {
                 self.emit_tag_anchor(tag, anchor, false)?;
-                self.emit_null(false, style)?;
+                self.emit_null(tag.is_some() || anchor.is_some(), style)?;
+                self.ensure_line_start()?;
+                S::Fin }
+            (S::New, Opcode::Alias(name)) => {
+                self.stream.write_all(b"*")?;
+                self.stream.write_all(name.as_bytes())?;
+                self.line = L::AfterScalar;
                 self.ensure_line_start()?;
                 S::Fin }
             (S::New, Opcode::MapStart(tag, anchor)) => {
@@ -218,9 +244,18 @@ impl<'a> Context<'a> {
                 self.ensure_indented()?;
                 // TODO(tailhook) check for complex key
                 self.emit_tag_anchor(tag, anchor, false)?;
-                self.emit_null(false, style)?;
+                self.emit_null(tag.is_some() || anchor.is_some(), style)?;
                 self.ensure_line_start()?;
                 S::MapKey }
+            (S::MapKey, Opcode::Alias(name)) => {
+                // Alias can't be an implicit key, so explicit `? ` is used
+                // and the value is written on the next line
+                self.ensure_indented()?;
+                self.stream.write_all(b"? *")?;
+                self.stream.write_all(name.as_bytes())?;
+                self.line = L::AfterScalar;
+                self.ensure_indented()?;
+                S::MapSimpleKeyValue }
             (S::MapSimpleKeyValue, Opcode::Scalar(tag, anchor, style, value))
             => {
                 self.stream.write(b": ")?;
@@ -230,9 +265,14 @@ impl<'a> Context<'a> {
             (S::MapSimpleKeyValue, Opcode::Null(tag, anchor, style)) => {
                 self.stream.write(b": ")?;
                 self.emit_tag_anchor(tag, anchor, false)?;
-                self.emit_null(false, style)?;
+                self.emit_null(tag.is_some() || anchor.is_some(), style)?;
                 self.ensure_line_start()?;
                 S::MapKey }
+            (S::MapSimpleKeyValue, Opcode::Alias(name)) => {
+                self.stream.write_all(b": *")?;
+                self.stream.write_all(name.as_bytes())?;
+                self.line = L::AfterScalar;
+                S::MapKey }
             (S::MapSimpleKeyValue, Opcode::MapStart(tag, anchor)) => {
                 self.stream.write(b":")?;
                 if tag.is_some() || anchor.is_some() {
@@ -245,6 +285,10 @@ impl<'a> Context<'a> {
                 S::MapKey }
             (S::MapSimpleKeyValue, Opcode::SeqStart(tag, anchor)) => {
                 self.stream.write(b":")?;
+                if tag.is_some() || anchor.is_some() {
+                    self.stream.write_all(b" ")?;
+                    self.emit_tag_anchor(tag, anchor, false)?;
+                }
                 self.line = L::AfterScalar;
                 self.push_indent(S::MapKey, 0);
                 S::SeqItem }
@@ -269,15 +313,21 @@ impl<'a> Context<'a> {
                 self.emit_scalar(style, value)?;
                 S::SeqItem }
             (S::SeqItem, Opcode::MapStart(tag, anchor)) => {
-                self.emit_tag_anchor(tag, anchor, false)?;
-                if tag.is_some() || anchor.is_some() {
-                    self.ensure_line_start()?;
-                }
                 self.ensure_indented()?;
                 self.stream.write(b"- ")?;
-                self.line = L::AfterIndent;
+                if tag.is_some() || anchor.is_some() {
+                    self.emit_tag_anchor(tag, anchor, false)?;
+                } else {
+                    self.line = L::AfterIndent;
+                }
                 self.push_indent(S::SeqItem, 2);
                 S::MapKey }
+            (S::SeqItem, Opcode::Alias(name)) => {
+                self.ensure_indented()?;
+                self.stream.write_all(b"- *")?;
+                self.stream.write_all(name.as_bytes())?;
+                self.line = L::AfterScalar;
+                S::SeqItem }
             (S::SeqItem, Opcode::SeqEnd) => {
                 let nstate = self.pop_indent();
                 match nstate {
@@ -292,34 +342,33 @@ impl<'a> Context<'a> {
 
     pub fn emit_node(&mut self, node: &Node) -> IoResult<()> {
         match node {
-            &N::Map(tag, anchor, ref map, _) => {
-                self.emit(Opcode::MapStart(tag.map(|t| &t[1..]),
-                                        anchor))?;
+            &N::Map(ref tag, ref anchor, ref map, _) => {
+                self.emit(Opcode::MapStart(tag.as_ref().map(|t| &t[1..]),
+                                        anchor.as_ref().map(|a| &a[..])))?;
                 for (k, v) in map.iter() {
                     self.emit_node(k)?;
                     self.emit_node(v)?;
                 }
                 self.emit(Opcode::MapEnd)?;
             }
-            &N::Seq(tag, anchor, ref items, _) => {
-                self.emit(Opcode::SeqStart(tag.map(|t| &t[1..]),
-                                        anchor))?;
+            &N::Seq(ref tag, ref anchor, ref items, _) => {
+                self.emit(Opcode::SeqStart(tag.as_ref().map(|t| &t[1..]),
+                                        anchor.as_ref().map(|a| &a[..])))?;
                 for i in items.iter() {
                     self.emit_node(i)?;
                 }
                 self.emit(Opcode::SeqEnd)?;
             },
-            &N::Scalar(ref tag, _anchor, ref value, _) => {
-                // TODO(tailhook) fix anchor
-                self.emit(Opcode::Scalar(tag.map(|t| &t[1..]),
-                    None, ScalarStyle::Auto, value))?;
+            &N::Scalar(ref tag, ref anchor, ref value, _, _) => {
+                self.emit(Opcode::Scalar(tag.as_ref().map(|t| &t[1..]),
+                    anchor.as_ref().map(|a| &a[..]), ScalarStyle::Auto,
+                    value))?;
             }
-            &N::ImplicitNull(ref tag, ref _anchor, ref _token) => {
-                // TODO(tailhook) fix anchor
-                self.emit(Opcode::Null(tag.map(|t| &t[1..]),
-                    None, Null::Nothing))?;
+            &N::ImplicitNull(ref tag, ref anchor, ref _token) => {
+                self.emit(Opcode::Null(tag.as_ref().map(|t| &t[1..]),
+                    anchor.as_ref().map(|a| &a[..]), Null::Nothing))?;
             }
-            &N::Alias(name, _, _) => {
+            &N::Alias(ref name, _, _) => {
                 self.emit(Opcode::Alias(name))?;
             }
         }
@@ -327,9 +376,25 @@ impl<'a> Context<'a> {
     }
 
     pub fn emit_ast(&mut self, node: &Ast) -> IoResult<()> {
+        let anchor = match node.anchor() {
+            Some(&AstAnchor::Alias(ref name))
+            if self.defined_anchors.contains(name) => {
+                return self.emit(Opcode::Alias(name));
+            }
+            // The anchor itself might be lost, e.g. if it's defined in
+            // a merge key, so the first copy is emitted with the anchor
+            Some(&AstAnchor::Alias(ref name))
+            | Some(&AstAnchor::Defined(ref name)) => {
+                self.defined_anchors.insert(name.clone());
+                Some(&name[..])
+            }
+            None => None,
+        };
         match node {
-            &A::Map(_, ref tag, ref map) => {
-                self.emit(Opcode::MapStart(tag_as_string(tag), None))?;
+            &A::Map(_, ref tag, _, ref map) => {
+                let tag = tag_as_string(tag);
+                self.emit(Opcode::MapStart(tag.as_ref().map(|t| &t[..]),
+                                           anchor))?;
                 for (k, v) in map.iter() {
                     self.emit(Opcode::Scalar(None, None,
                         ScalarStyle::Auto, k))?;
@@ -337,23 +402,28 @@ impl<'a> Context<'a> {
                 }
                 self.emit(Opcode::MapEnd)?;
             }
-            &A::Seq(_, ref tag, ref items) => {
-                self.emit(Opcode::SeqStart(tag_as_string(tag), None))?;
+            &A::Seq(_, ref tag, _, ref items) => {
+                let tag = tag_as_string(tag);
+                self.emit(Opcode::SeqStart(tag.as_ref().map(|t| &t[..]),
+                                           anchor))?;
                 for i in items.iter() {
                     self.emit_ast(i)?;
                 }
                 self.emit(Opcode::SeqEnd)?;
             },
-            &A::Scalar(_, ref tag, _, ref value) => {
-                // TODO(tailhook) fix tag and anchor
-                self.emit(Opcode::Scalar(tag_as_string(tag), None,
-                                      ScalarStyle::Auto, value))?;
+            &A::Scalar(_, ref tag, _, _, ref value) => {
+                let tag = tag_as_string(tag);
+                self.emit(Opcode::Scalar(tag.as_ref().map(|t| &t[..]),
+                                         anchor, ScalarStyle::Auto, value))?;
             }
-            &A::Null(_, ref tag, ref kind) => {
-                self.emit(Opcode::Null(tag_as_string(tag), None, match *kind {
+            &A::Null(_, ref tag, _, ref kind) => {
+                let tag = tag_as_string(tag);
+                let style = match *kind {
                     Explicit => Null::Null,
                     Implicit => Null::Nothing,
-                }))?;
+                };
+                self.emit(Opcode::Null(tag.as_ref().map(|t| &t[..]), anchor,
+                                       style))?;
             }
         }
         return Ok(());
@@ -671,6 +741,12 @@ mod test {
         assert_yaml_eq_yaml("a: b\nc: d", "a: b\nc: d\n");
     }
 
+    #[test]
+    fn yaml_map_order() {
+        assert_yaml_eq_yaml("c: d\na: b\nb: {z: 1, y: 2}",
+                            "c: d\na: b\nb:\n  z: 1\n  y: 2\n");
+    }
+
     #[test]
     fn yaml_map_map() {
         assert_yaml_eq_yaml("a:\n b: c", "a:\n  b: c\n");
@@ -691,6 +767,36 @@ mod test {
         assert_yaml_eq_yaml("- a: b\n  c: d", "- a: b\n  c: d\n");
     }
 
+    #[test]
+    fn yaml_anchor_alias() {
+        assert_yaml_eq_yaml("a: &x 1\nb: *x", "a: &x 1\nb: *x\n");
+        assert_yaml_eq_yaml("- &y !Tag z\n- *y", "- !Tag &y z\n- *y\n");
+    }
+
+    #[test]
+    fn yaml_alias_key() {
+        let mut bytes = Vec::new();
+        parse(Rc::new("<inline test>".to_string()),
+            "a: &x k\n? *x\n: v\nb:\n- ? *x\n  : {c: d}", |doc| {
+                let mut ctx = Context::new(&mut bytes);
+                ctx.emit_node(&doc.root).unwrap();
+            }).unwrap();
+        assert_eq!(from_utf8(&bytes[..]).unwrap(),
+                   "a: &x k\n? *x\n: v\nb:\n- ? *x\n  :\n    c: d\n");
+    }
+
+    #[test]
+    fn yaml_anchor_map() {
+        assert_yaml_eq_yaml("a: &x\n  b: c\nd: *x",
+                            "a: &x\n  b: c\nd: *x\n");
+        assert_yaml_eq_yaml("- &x {b: c}\n- *x", "- &x\n  b: c\n- *x\n");
+    }
+
+    #[test]
+    fn yaml_anchor_list() {
+        assert_yaml_eq_yaml("a: &x\n- b\nc: *x", "a: &x\n- b\nc: *x\n");
+    }
+
     #[test]
     fn yaml_tag_map() {
         assert_yaml_eq_yaml("!Tag {a: b}", "!Tag\na: b\n");
@@ -736,6 +842,17 @@ mod test {
         assert_yaml_eq_yaml("!Tag { a: b, c: d }", "!Tag\na: b\nc: d\n");
     }
 
+    #[test]
+    fn yaml_global_tag() {
+        assert_yaml_eq_yaml("!<tag:example.com,2000:x> a",
+                            "!<tag:example.com,2000:x> a\n");
+    }
+
+    #[test]
+    fn yaml_core_schema_tag() {
+        assert_yaml_eq_yaml("!!set {a: b}", "!!set\na: b\n");
+    }
+
     #[test]
     fn yaml_tag_list() {
         assert_yaml_eq_yaml("!Tag [a, b, c]", "!Tag\n- a\n- b\n- c\n");
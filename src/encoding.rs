@@ -0,0 +1,303 @@
+//! Detection and decoding of the character encoding of the input
+//!
+//! The encoding is detected by the byte order mark or by the null bytes
+//! of the first character, as described in section 5.2 of the YAML
+//! specification. Everything is decoded to UTF-8, the byte order mark is
+//! stripped.
+
+use std::char;
+use std::cmp::min;
+use std::fmt;
+use std::io::{self, Read};
+use std::str;
+
+use self::Encoding::*;
+
+
+/// Character encoding of the input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Utf32Le,
+    Utf32Be,
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(match *self {
+            Utf8 => "UTF-8",
+            Utf16Le => "UTF-16LE",
+            Utf16Be => "UTF-16BE",
+            Utf32Le => "UTF-32LE",
+            Utf32Be => "UTF-32BE",
+        })
+    }
+}
+
+/// Detects encoding by (at most four) first bytes of the input
+///
+/// Returns the encoding and the length of the byte order mark
+pub fn detect(head: &[u8]) -> (Encoding, usize) {
+    if head.starts_with(&[0, 0, 0xFE, 0xFF]) {
+        return (Utf32Be, 4);
+    } else if head.len() >= 4 && head[..3] == [0, 0, 0] {
+        return (Utf32Be, 0);
+    } else if head.starts_with(&[0xFF, 0xFE, 0, 0]) {
+        return (Utf32Le, 4);
+    } else if head.len() >= 4 && head[1..4] == [0, 0, 0] {
+        return (Utf32Le, 0);
+    } else if head.starts_with(&[0xFE, 0xFF]) {
+        return (Utf16Be, 2);
+    } else if head.len() >= 2 && head[0] == 0 {
+        return (Utf16Be, 0);
+    } else if head.starts_with(&[0xFF, 0xFE]) {
+        return (Utf16Le, 2);
+    } else if head.len() >= 2 && head[1] == 0 {
+        return (Utf16Le, 0);
+    } else if head.starts_with(&[0xEF, 0xBB, 0xBF]) {
+        return (Utf8, 3);
+    }
+    return (Utf8, 0);
+}
+
+/// Reader which decodes the input to UTF-8
+///
+/// Invalid input is reported as `InvalidData` error, which includes
+/// the detected encoding and the offset of the offending byte
+pub struct DecodeReader<R> {
+    inner: R,
+    encoding: Encoding,
+    /// Bytes which are read but not decoded yet
+    input: Vec<u8>,
+    /// Offset of the first byte of the `input` in the stream
+    offset: usize,
+    /// Decoded bytes, the ones before `output_pos` are already returned
+    output: Vec<u8>,
+    output_pos: usize,
+    eof: bool,
+}
+
+impl<R: Read> DecodeReader<R> {
+    /// Reads the first bytes of the input to detect the encoding
+    pub fn new(mut inner: R) -> io::Result<DecodeReader<R>> {
+        let mut head = [0u8; 4];
+        let mut len = 0;
+        while len < head.len() {
+            match inner.read(&mut head[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let (encoding, bom) = detect(&head[..len]);
+        return Ok(DecodeReader {
+            inner: inner,
+            encoding: encoding,
+            input: head[bom..len].to_vec(),
+            offset: bom,
+            output: Vec::new(),
+            output_pos: 0,
+            eof: false,
+        });
+    }
+
+    fn error(&self, pos: usize) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData,
+            format!("invalid {} data at byte {}",
+                    self.encoding, self.offset + pos))
+    }
+
+    /// Decodes all complete characters of the `input`
+    fn decode(&mut self) -> io::Result<()> {
+        let used = match self.encoding {
+            Utf8 => match str::from_utf8(&self.input) {
+                Ok(text) => text.len(),
+                // Incomplete character at the end is decoded later
+                Err(ref e) if e.error_len().is_none() => e.valid_up_to(),
+                Err(e) => return Err(self.error(e.valid_up_to())),
+            },
+            Utf16Le | Utf16Be => self.decode_utf16()?,
+            Utf32Le | Utf32Be => self.decode_utf32()?,
+        };
+        if self.encoding == Utf8 {
+            self.output.extend_from_slice(&self.input[..used]);
+        }
+        self.input.drain(..used);
+        self.offset += used;
+        return Ok(());
+    }
+
+    fn push_char(&mut self, code: u32, pos: usize) -> io::Result<()> {
+        let ch = char::from_u32(code).ok_or_else(|| self.error(pos))?;
+        let mut buf = [0u8; 4];
+        self.output.extend_from_slice(ch.encode_utf8(&mut buf).as_bytes());
+        return Ok(());
+    }
+
+    fn decode_utf16(&mut self) -> io::Result<usize> {
+        let unit = |input: &[u8], pos: usize, enc: Encoding| -> u32 {
+            let (a, b) = (input[pos] as u32, input[pos+1] as u32);
+            if enc == Utf16Le { b << 8 | a } else { a << 8 | b }
+        };
+        let mut pos = 0;
+        while pos + 2 <= self.input.len() {
+            let first = unit(&self.input, pos, self.encoding);
+            if first >= 0xD800 && first <= 0xDBFF {
+                // Surrogate pair
+                if pos + 4 > self.input.len() {
+                    break;
+                }
+                let second = unit(&self.input, pos + 2, self.encoding);
+                if second < 0xDC00 || second > 0xDFFF {
+                    return Err(self.error(pos + 2));
+                }
+                let code = 0x10000 +
+                    ((first - 0xD800) << 10) + (second - 0xDC00);
+                self.push_char(code, pos)?;
+                pos += 4;
+            } else {
+                self.push_char(first, pos)?;
+                pos += 2;
+            }
+        }
+        return Ok(pos);
+    }
+
+    fn decode_utf32(&mut self) -> io::Result<usize> {
+        let mut pos = 0;
+        while pos + 4 <= self.input.len() {
+            let mut code = 0;
+            for i in 0..4 {
+                let byte = match self.encoding {
+                    Utf32Le => self.input[pos + 3 - i],
+                    _ => self.input[pos + i],
+                };
+                code = code << 8 | byte as u32;
+            }
+            self.push_char(code, pos)?;
+            pos += 4;
+        }
+        return Ok(pos);
+    }
+}
+
+impl<R: Read> Read for DecodeReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.output_pos == self.output.len() {
+            self.output.clear();
+            self.output_pos = 0;
+            if self.eof {
+                if self.input.len() > 0 {
+                    // Incomplete character at the end of file
+                    return Err(self.error(0));
+                }
+                return Ok(0);
+            }
+            let mut chunk = [0u8; 8192];
+            match self.inner.read(&mut chunk)? {
+                0 => self.eof = true,
+                n => self.input.extend_from_slice(&chunk[..n]),
+            }
+            self.decode()?;
+        }
+        let len = min(buf.len(), self.output.len() - self.output_pos);
+        buf[..len].copy_from_slice(
+            &self.output[self.output_pos..self.output_pos + len]);
+        self.output_pos += len;
+        return Ok(len);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, BufReader};
+    use super::{detect, DecodeReader};
+    use super::Encoding::*;
+
+    fn decode(data: &[u8]) -> Result<String, String> {
+        let mut result = String::new();
+        // Read byte by byte to check characters split between reads
+        DecodeReader::new(BufReader::with_capacity(1, data))
+            .and_then(|mut r| r.read_to_string(&mut result))
+            .map_err(|e| e.to_string())?;
+        return Ok(result);
+    }
+
+    fn utf16(text: &str, le: bool) -> Vec<u8> {
+        text.encode_utf16().flat_map(|u| {
+            let (hi, lo) = ((u >> 8) as u8, u as u8);
+            if le { vec![lo, hi] } else { vec![hi, lo] }
+        }).collect()
+    }
+
+    fn utf32(text: &str, le: bool) -> Vec<u8> {
+        text.chars().flat_map(|c| {
+            let c = c as u32;
+            let bytes = vec![(c >> 24) as u8, (c >> 16) as u8,
+                             (c >> 8) as u8, c as u8];
+            if le { bytes.into_iter().rev().collect() } else { bytes }
+        }).collect()
+    }
+
+    #[test]
+    fn detect_encoding() {
+        assert_eq!(detect(b""), (Utf8, 0));
+        assert_eq!(detect(b"a"), (Utf8, 0));
+        assert_eq!(detect(b"a: b"), (Utf8, 0));
+        assert_eq!(detect(b"\xEF\xBB\xBFa"), (Utf8, 3));
+        assert_eq!(detect(b"\xFE\xFF\x00a"), (Utf16Be, 2));
+        assert_eq!(detect(b"\x00a\x00:"), (Utf16Be, 0));
+        assert_eq!(detect(b"\xFF\xFEa\x00"), (Utf16Le, 2));
+        assert_eq!(detect(b"a\x00:\x00"), (Utf16Le, 0));
+        assert_eq!(detect(b"a\x00"), (Utf16Le, 0));
+        assert_eq!(detect(b"\x00\x00\xFE\xFF"), (Utf32Be, 4));
+        assert_eq!(detect(b"\x00\x00\x00a"), (Utf32Be, 0));
+        assert_eq!(detect(b"\xFF\xFE\x00\x00"), (Utf32Le, 4));
+        assert_eq!(detect(b"a\x00\x00\x00"), (Utf32Le, 0));
+    }
+
+    #[test]
+    fn decode_utf8() {
+        assert_eq!(decode(b""), Ok("".to_string()));
+        assert_eq!(decode("a: \u{439}\n".as_bytes()),
+                   Ok("a: \u{439}\n".to_string()));
+        assert_eq!(decode(b"\xEF\xBB\xBFa: b"), Ok("a: b".to_string()));
+        assert_eq!(decode(b"a: \xFF"),
+                   Err("invalid UTF-8 data at byte 3".to_string()));
+        assert_eq!(decode(b"a: \xD0"),
+                   Err("invalid UTF-8 data at byte 3".to_string()));
+    }
+
+    #[test]
+    fn decode_utf16() {
+        let text = "a: \u{439}\nb: \u{1F600}\n";
+        for &le in &[true, false] {
+            assert_eq!(decode(&utf16(text, le)), Ok(text.to_string()));
+            let with_bom = utf16(&format!("\u{FEFF}{}", text), le);
+            assert_eq!(decode(&with_bom), Ok(text.to_string()));
+        }
+        assert_eq!(decode(b"\xFF\xFEa\x00\x00\xD8b\x00"),
+                   Err("invalid UTF-16LE data at byte 6".to_string()));
+        assert_eq!(decode(b"\x00a\xDC\x00"),
+                   Err("invalid UTF-16BE data at byte 2".to_string()));
+        assert_eq!(decode(b"\x00a\x00"),
+                   Err("invalid UTF-16BE data at byte 2".to_string()));
+    }
+
+    #[test]
+    fn decode_utf32() {
+        let text = "a: \u{439}\nb: \u{1F600}\n";
+        for &le in &[true, false] {
+            assert_eq!(decode(&utf32(text, le)), Ok(text.to_string()));
+            let with_bom = utf32(&format!("\u{FEFF}{}", text), le);
+            assert_eq!(decode(&with_bom), Ok(text.to_string()));
+        }
+        assert_eq!(decode(b"a\x00\x00\x00\x00\xD8\x00\x00"),
+                   Err("invalid UTF-32LE data at byte 4".to_string()));
+        assert_eq!(decode(b"\x00\x00\x00a\x00\x11\x00\x00"),
+                   Err("invalid UTF-32BE data at byte 4".to_string()));
+    }
+}
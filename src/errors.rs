@@ -2,13 +2,59 @@ use std::io;
 use std::fmt;
 use std::rc::Rc;
 use std::slice::Iter;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::cell::RefCell;
 
-use super::tokenizer::{self, Pos};
+use super::tokenizer::{self, Pos, Span};
 
+/// Position of the error in the source file
+///
+/// Displayed as `file:line:col`, or as `file:line:col-line:col` if the end
+/// is known. The end position is exclusive and is equal to the start if
+/// only single position is known
 #[derive(Clone, Debug)]
-pub struct ErrorPos(String, usize, usize);
+pub struct ErrorPos(String, usize, usize, usize, usize);
+
+impl ErrorPos {
+    /// Name of the file where error occured
+    pub fn filename(&self) -> &str {
+        &self.0
+    }
+    /// Line and column where erroneous part starts
+    pub fn start(&self) -> (usize, usize) {
+        (self.1, self.2)
+    }
+    /// Line and column where erroneous part ends
+    pub fn end(&self) -> (usize, usize) {
+        (self.3, self.4)
+    }
+}
+
+impl<'a> From<&'a Pos> for ErrorPos {
+    fn from(pos: &'a Pos) -> ErrorPos {
+        ErrorPos((*pos.filename).clone(), pos.line, pos.line_offset,
+                 pos.line, pos.line_offset)
+    }
+}
+
+impl<'a> From<&'a Span> for ErrorPos {
+    fn from(span: &'a Span) -> ErrorPos {
+        ErrorPos((*span.start.filename).clone(),
+                 span.start.line, span.start.line_offset,
+                 span.end.line, span.end.line_offset)
+    }
+}
+
+impl fmt::Display for ErrorPos {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if (self.1, self.2) == (self.3, self.4) {
+            write!(fmt, "{}:{}:{}", self.0, self.1, self.2)
+        } else {
+            write!(fmt, "{}:{}:{}-{}:{}", self.0, self.1, self.2,
+                   self.3, self.4)
+        }
+    }
+}
 
 quick_error! {
     /// Single error when of parsing configuration file
@@ -20,27 +66,33 @@ quick_error! {
         OpenError(filename: PathBuf, err: io::Error) {
             display("{}: Error reading file: {}", filename.display(), err)
         }
+        /// Error reading a file included from `pos`
+        IncludeError(pos: ErrorPos, filename: PathBuf, err: io::Error) {
+            display("{pos}: Error reading included file {path}: {err}",
+                    pos=pos, path=filename.display(), err=err)
+        }
         TokenizerError(pos: ErrorPos, err: tokenizer::Error) {
-            display("{filename}:{line}:{offset}: Tokenizer Error: {err}",
-                    filename=pos.0, line=pos.1, offset=pos.2, err=err)
+            display("{}: Tokenizer Error: {}", pos, err)
         }
         ParseError(pos: ErrorPos, msg: String) {
-            display("{filename}:{line}:{offset}: Parse Error: {text}",
-                    filename=pos.0, line=pos.1, offset=pos.2, text=msg)
+            display("{}: Parse Error: {}", pos, msg)
         }
         ValidationError(pos: ErrorPos, msg: String) {
-            display("{filename}:{line}:{offset}: Validation Error: {text}",
-                    filename=pos.0, line=pos.1, offset=pos.2, text=msg)
+            display("{}: Validation Error: {}", pos, msg)
         }
         PreprocessError(pos: ErrorPos, msg: String) {
-            display("{filename}:{line}:{offset}: Preprocess Error: {text}",
-                    filename=pos.0, line=pos.1, offset=pos.2, text=msg)
+            display("{}: Preprocess Error: {}", pos, msg)
+        }
+        /// Error of editing the document with `cst::SyntaxTree`
+        EditError(pos: ErrorPos, msg: String) {
+            display("{}: Edit Error: {}", pos, msg)
+        }
+        /// Problem which doesn't fail parsing
+        Warning(pos: ErrorPos, msg: String) {
+            display("{}: Warning: {}", pos, msg)
         }
         DecodeError(pos: ErrorPos, path: String, msg: String) {
-            display("{filename}:{line}:{offset}: \
-                Decode error at {path}: {text}",
-                    filename=pos.0, line=pos.1, offset=pos.2,
-                    path=path, text=msg)
+            display("{}: Decode error at {}: {}", pos, path, msg)
         }
     }
 }
@@ -48,31 +100,66 @@ quick_error! {
 unsafe impl Send for Error {}
 
 impl Error {
-    pub fn parse_error(pos: &Pos, message: String) -> Error {
-        return Error::ParseError(
-            ErrorPos((*pos.filename).clone(), pos.line, pos.line_offset),
-            message);
+    /// Syntax error at `pos`
+    pub fn parse_error<P: Into<ErrorPos>>(pos: P, message: String) -> Error {
+        Error::ParseError(pos.into(), message)
     }
+    /// Error returned by the tokenizer
     pub fn tokenizer_error((pos, err): (Pos, tokenizer::Error)) -> Error {
-        return Error::TokenizerError(
-            ErrorPos((*pos.filename).clone(), pos.line, pos.line_offset),
-            err);
-    }
-    pub fn validation_error(pos: &Pos, message: String) -> Error {
-        return Error::ValidationError(
-            ErrorPos((*pos.filename).clone(), pos.line, pos.line_offset),
-            message);
-    }
-    pub fn decode_error(pos: &Pos, path: &String, message: String) -> Error {
-        return Error::DecodeError(
-            ErrorPos((*pos.filename).clone(), pos.line, pos.line_offset),
-            path.clone(),
-            message);
-    }
-    pub fn preprocess_error(pos: &Pos, message: String) -> Error {
-        return Error::PreprocessError(
-            ErrorPos((*pos.filename).clone(), pos.line, pos.line_offset),
-            message);
+        Error::TokenizerError(ErrorPos::from(&pos), err)
+    }
+    /// Error of validating the value at `pos`
+    pub fn validation_error<P: Into<ErrorPos>>(pos: P, message: String)
+        -> Error
+    {
+        Error::ValidationError(pos.into(), message)
+    }
+    /// Error of decoding the value at `pos`, `path` is where the value
+    /// is in the decoded structure
+    pub fn decode_error<P: Into<ErrorPos>>(pos: P, path: &String,
+        message: String)
+        -> Error
+    {
+        Error::DecodeError(pos.into(), path.clone(), message)
+    }
+    /// Error reading `filename` included at `pos`
+    pub fn include_error<P: Into<ErrorPos>>(pos: P, filename: &Path,
+        err: io::Error)
+        -> Error
+    {
+        Error::IncludeError(pos.into(), filename.to_path_buf(), err)
+    }
+    /// Error of processing includes, merge keys, aliases and tags
+    pub fn preprocess_error<P: Into<ErrorPos>>(pos: P, message: String)
+        -> Error
+    {
+        Error::PreprocessError(pos.into(), message)
+    }
+    /// Error of editing the document with `cst::SyntaxTree`
+    pub fn edit_error<P: Into<ErrorPos>>(pos: P, message: String)
+        -> Error
+    {
+        Error::EditError(pos.into(), message)
+    }
+    /// Problem which doesn't fail parsing, see `Options::warning_handler`
+    pub fn warning<P: Into<ErrorPos>>(pos: P, message: String) -> Error {
+        Error::Warning(pos.into(), message)
+    }
+    /// Position (the span) of the error, if known
+    ///
+    /// Use `Display` of the returned value to get `line:col-line:col` form
+    pub fn pos(&self) -> Option<&ErrorPos> {
+        match *self {
+            Error::OpenError(..) => None,
+            Error::IncludeError(ref pos, ..) => Some(pos),
+            Error::TokenizerError(ref pos, ..) => Some(pos),
+            Error::ParseError(ref pos, ..) => Some(pos),
+            Error::ValidationError(ref pos, ..) => Some(pos),
+            Error::PreprocessError(ref pos, ..) => Some(pos),
+            Error::DecodeError(ref pos, ..) => Some(pos),
+            Error::EditError(ref pos, ..) => Some(pos),
+            Error::Warning(ref pos, ..) => Some(pos),
+        }
     }
 }
 
@@ -131,6 +218,11 @@ impl ErrorCollector {
         self.0.borrow_mut().as_mut().unwrap().add_error(err)
     }
 
+    /// Returns true if any error is added to collector
+    pub fn has_errors(&self) -> bool {
+        !self.0.borrow().as_ref().unwrap().errors.is_empty()
+    }
+
     /// Adds fatal (final) error into collection and return error list
     pub fn into_fatal(&self, err: Error) -> ErrorList {
         let mut lst = self.0.borrow_mut().take().unwrap();
@@ -142,7 +234,7 @@ impl ErrorCollector {
     /// otherwise
     pub fn into_result<T>(&self, val: T) -> Result<T, ErrorList> {
         let lst = self.0.borrow_mut().take().unwrap();
-        if lst.errors.len() > 0 {
+        if !lst.errors.is_empty() {
             Err(lst)
         } else {
             Ok(val)
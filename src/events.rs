@@ -0,0 +1,393 @@
+//! Event-based (pull) parser
+//!
+//! Reports the structure of the documents as a flat sequence of events,
+//! without building `Node` or `Ast` trees, so it's suitable for filtering
+//! large data files. Events mirror the opcodes of the emitter.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! use quire::events::{read_events, Event};
+//!
+//! let scalars = read_events(Rc::new("data.yaml".into()), &data)
+//!     .filter(|e| match *e {
+//!         Ok(Event::Scalar(..)) => true,
+//!         _ => false,
+//!     })
+//!     .count();
+//! ```
+//!
+//! There is no separate event for mapping keys. Nodes between `MapStart`
+//! and the matching `MapEnd` alternate: the first one is a key, the next
+//! one is its value, and so on. Every key has a value (a missing one is
+//! reported as `Null`), so the number of nodes is always even. A node is
+//! either a single event or everything from `MapStart`/`SeqStart` to the
+//! matching end event, so complex keys are followed by their value after
+//! the end of the key. Like in the raw parser, tags are not resolved,
+//! explicit nulls (`~`, `null`) are scalars and merge keys (`<<`) and
+//! includes are not processed. Aliases are reported as is.
+
+use std::io::{self, BufRead};
+use std::rc::Rc;
+
+use ast::ScalarKind;
+use errors::Error;
+use parser::Parser;
+use parser::Event as RawEvent;
+use tokenizer::{Token, TokenReader, Pos, Span};
+use tokenizer::TokenType as T;
+
+/// Parser event
+///
+/// Tags are written as in the source (e.g. `!Tag`), anchors and aliases
+/// are without the `&` and `*` sigils
+#[derive(Debug)]
+pub enum Event {
+    /// Start of the document, with directives preceding it (e.g.
+    /// `%YAML 1.2`)
+    DocumentStart(Vec<String>),
+    /// End of the document
+    DocumentEnd,
+    /// Start of the mapping: tag, anchor and position of the first token
+    ///
+    /// It's followed by key and value nodes, alternating, see the
+    /// module documentation
+    MapStart(Option<String>, Option<String>, Pos),
+    /// End of the mapping, position right after the last token
+    MapEnd(Pos),
+    /// Start of the sequence: tag, anchor and position of the first token
+    SeqStart(Option<String>, Option<String>, Pos),
+    /// End of the sequence, position right after the last token
+    SeqEnd(Pos),
+    /// Scalar value: tag, anchor, kind, value and span of the value
+    Scalar(Option<String>, Option<String>, ScalarKind, String, Span),
+    /// Implicit null value, like in `a:`
+    Null(Option<String>, Option<String>, Pos),
+    /// Reference to the anchored node, the node itself is not repeated
+    Alias(String, Span),
+}
+
+/// Iterator over the events of the documents
+///
+/// The input is read as the events are requested. After an error is
+/// returned, iteration stops
+pub struct Events<'a, R> {
+    parser: Parser<'a, R>,
+    done: bool,
+}
+
+fn scalar_kind(tok: &Token) -> ScalarKind {
+    match tok.kind {
+        T::PlainString => ScalarKind::Plain,
+        _ => ScalarKind::Quoted,
+    }
+}
+
+fn span(tok: &Token) -> Span {
+    return Span::new(tok.start.clone(), tok.end.clone());
+}
+
+impl<'a, R: BufRead> Events<'a, R> {
+    fn new(reader: TokenReader<'a, R>) -> Events<'a, R> {
+        return Events {
+            parser: Parser::new(reader, false),
+            done: false,
+        };
+    }
+
+    fn tag(&self, index: Option<usize>) -> Option<String> {
+        index.map(|i| self.parser.token(i).value.to_string())
+    }
+
+    fn anchor(&self, index: Option<usize>) -> Option<String> {
+        index.map(|i| self.parser.token(i).value[1..].to_string())
+    }
+
+    fn convert(&self, event: RawEvent) -> Result<Event, Error> {
+        let event = match event {
+            RawEvent::DocumentStart(directives) => {
+                Event::DocumentStart(directives.into_iter()
+                    .map(|i| self.parser.token(i).value.to_string())
+                    .collect())
+            }
+            RawEvent::DocumentEnd => Event::DocumentEnd,
+            RawEvent::MapStart(tag, anchor, pos) => {
+                Event::MapStart(self.tag(tag), self.anchor(anchor), pos)
+            }
+            RawEvent::MapEnd(pos) => Event::MapEnd(pos),
+            RawEvent::SeqStart(tag, anchor, pos) => {
+                Event::SeqStart(self.tag(tag), self.anchor(anchor), pos)
+            }
+            RawEvent::SeqEnd(pos) => Event::SeqEnd(pos),
+            RawEvent::Scalar(tag, anchor, value, index) => {
+                let tok = self.parser.token(index);
+                Event::Scalar(self.tag(tag), self.anchor(anchor),
+                    scalar_kind(tok), value, span(tok))
+            }
+            RawEvent::Null(tag, anchor, pos) => {
+                Event::Null(self.tag(tag), self.anchor(anchor), pos)
+            }
+            RawEvent::Alias(index) => {
+                let tok = self.parser.token(index);
+                Event::Alias(tok.value[1..].to_string(), span(tok))
+            }
+            // Only returned after `Parser::recover`, which isn't called
+            RawEvent::Recover(pos, _, _) => {
+                return Err(Error::parse_error(&pos,
+                    "Unexpected recovery event".to_string()));
+            }
+        };
+        return Ok(event);
+    }
+}
+
+impl<'a, R: BufRead> Iterator for Events<'a, R> {
+    type Item = Result<Event, Error>;
+    fn next(&mut self) -> Option<Result<Event, Error>> {
+        if self.done {
+            return None;
+        }
+        match self.parser.next().map(|r| r.and_then(|e| self.convert(e))) {
+            Some(Ok(event)) => return Some(Ok(event)),
+            Some(Err(e)) => {
+                self.done = true;
+                return Some(Err(e));
+            }
+            None => return None,
+        }
+    }
+}
+
+/// Returns events of all the documents in the stream
+///
+/// Scalar values are copied out of `data` as the events are returned
+pub fn read_events<'a>(name: Rc<String>, data: &'a str)
+    -> Events<'a, io::Empty>
+{
+    return Events::new(TokenReader::from_text(name, data));
+}
+
+/// Returns events of the documents in the stream read from `reader`
+///
+/// The stream is read incrementally, while the events are iterated over.
+/// Reading errors are returned by the iterator as `Error::OpenError`
+pub fn read_events_from<R: BufRead>(name: Rc<String>, reader: R)
+    -> Events<'static, R>
+{
+    return Events::new(TokenReader::new(name, reader));
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+    use std::io::BufReader;
+
+    use errors::Error;
+    use parser::{parse_all, Node};
+    use super::{Event, read_events, read_events_from};
+
+    fn str(value: &Option<String>) -> Option<&str> {
+        value.as_ref().map(|x| &x[..])
+    }
+
+    fn format_props(tag: Option<&str>, anchor: Option<&str>, buf: &mut String)
+    {
+        if let Some(anchor) = anchor {
+            buf.push_str(&format!("&{} ", anchor));
+        }
+        if let Some(tag) = tag {
+            buf.push_str(&format!("{} ", tag));
+        }
+    }
+
+    /// Formats the node the same way as `format_events` does
+    fn format_node(node: &Node, buf: &mut String) {
+        match *node {
+            Node::Map(_, _, ref pairs, _) => {
+                format_props(node.tag(), node.anchor(), buf);
+                buf.push_str("{");
+                for &(ref k, ref v) in pairs {
+                    format_node(k, buf);
+                    buf.push_str(": ");
+                    format_node(v, buf);
+                    buf.push_str(", ");
+                }
+                buf.push_str("}");
+            }
+            Node::Seq(_, _, ref items, _) => {
+                format_props(node.tag(), node.anchor(), buf);
+                buf.push_str("[");
+                for item in items {
+                    format_node(item, buf);
+                    buf.push_str(", ");
+                }
+                buf.push_str("]");
+            }
+            Node::Scalar(_, _, ref value, _, _) => {
+                format_props(node.tag(), node.anchor(), buf);
+                buf.push_str(&format!("{:?}", value));
+            }
+            Node::ImplicitNull(..) => {
+                format_props(node.tag(), node.anchor(), buf);
+                buf.push_str("~");
+            }
+            Node::Alias(ref name, _, _) => buf.push_str(&format!("*{}", name)),
+        }
+    }
+
+    fn format_events<I>(events: I) -> Result<String, String>
+        where I: Iterator<Item=Result<Event, Error>>
+    {
+        let mut buf = String::new();
+        // `None` for sequences, `Some(is_key)` for mappings
+        let mut stack = Vec::new();
+        for event in events {
+            match event.map_err(|e| e.to_string())? {
+                Event::DocumentStart(_) => continue,
+                Event::DocumentEnd => {
+                    buf.push_str("\n");
+                    continue;
+                }
+                Event::MapStart(tag, anchor, _) => {
+                    format_props(str(&tag), str(&anchor), &mut buf);
+                    buf.push_str("{");
+                    stack.push(Some(true));
+                    continue;
+                }
+                Event::SeqStart(tag, anchor, _) => {
+                    format_props(str(&tag), str(&anchor), &mut buf);
+                    buf.push_str("[");
+                    stack.push(None);
+                    continue;
+                }
+                Event::MapEnd(_) => {
+                    stack.pop();
+                    buf.push_str("}");
+                }
+                Event::SeqEnd(_) => {
+                    stack.pop();
+                    buf.push_str("]");
+                }
+                Event::Scalar(tag, anchor, _, value, _) => {
+                    format_props(str(&tag), str(&anchor), &mut buf);
+                    buf.push_str(&format!("{:?}", value));
+                }
+                Event::Null(tag, anchor, _) => {
+                    format_props(str(&tag), str(&anchor), &mut buf);
+                    buf.push_str("~");
+                }
+                Event::Alias(name, _) => buf.push_str(&format!("*{}", name)),
+            }
+            match stack.last_mut() {
+                Some(&mut Some(ref mut is_key)) => {
+                    buf.push_str(if *is_key { ": " } else { ", " });
+                    *is_key = !*is_key;
+                }
+                Some(&mut None) => buf.push_str(", "),
+                None => {}
+            }
+        }
+        return Ok(buf);
+    }
+
+    fn parse_events(data: &str) -> Result<String, String> {
+        format_events(read_events(Rc::new("<inline text>".to_string()),
+                                  data))
+    }
+
+    fn parse_nodes(data: &str) -> Result<String, String> {
+        parse_all(Rc::new("<inline text>".to_string()), data, |doc| {
+            let mut buf = String::new();
+            format_node(&doc.root, &mut buf);
+            buf.push_str("\n");
+            buf
+        })
+        .map(|docs| docs.concat())
+        .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn same_as_parser() {
+        for &src in &[
+            "", "a", "'a'", "\"a\"", "a\nb\n", "a: b", "a: b\nc: d",
+            "a:\n  b: c\n  d:\ne:", "- a\n- b", "-\n- \n-",
+            "a:\n- b\n- c\nd: e", "- a: b\n  c: d\n- e",
+            "- - a\n  - b\n- c", "a: |\n  x\n  y\nb: >\n  z\n",
+            "- |\n  x\n- >-\n  y\n", "? a\n? b\n: c\n? [d]\n: e",
+            "[]", "{}", "[a, [b, c], {d: e}]", "{a: b, c: [d], e: {}}",
+            "{a, b: , ? c, ? : d}", "[a, b, ]", "{&k a: 1, b: {*k : 2}}",
+            "&a !T\nb: c\nd: *a", "a: &x 1\nb: *x", "a: !T\nb: !T c",
+            "- &x !T\n  a: b\n- *x", "a: !T\n  b: c",
+            "{a: !Tag , b: &x , c: *x, d: }", "[&a 1, *a, !Tag ]",
+            "%YAML 1.2\n---\na\n", "---\n---\n", "a\n...\n---\nb",
+            "a:\n  b: 1\n---\n- c:\n  - 2\n---\nd",
+            "# c\na: 1 # c\n# c\nb:\n  # c\n  c: 2\n",
+            "a: ~\nb: null\nc: 'null'",
+        ] {
+            assert_eq!(parse_events(src), parse_nodes(src), "{:?}", src);
+        }
+    }
+
+    #[test]
+    fn same_errors_as_parser() {
+        for &src in &[
+            "a: *x", "a: &x [*x]", "a: b\n c: d", "{a: b c}",
+            "a\n---\n- *a", "- a\nb: c", "[a b]", "{a: b]", "!T :",
+            "? a\n: b\n- c", "a: [1, 2", "{a: b", "{a: b,", "[a, {b",
+        ] {
+            assert_eq!(parse_events(src), parse_nodes(src), "{:?}", src);
+        }
+    }
+
+    #[test]
+    fn unclosed_flow() {
+        assert_eq!(parse_events("a: [1, 2"), Err("<inline text>:1:9: \
+            Parse Error: Expected comma `,` or colon `:`".to_string()));
+        assert_eq!(parse_events("{a: b"), Err("<inline text>:1:6: \
+            Parse Error: Expected comma `,` or closing bracket `}`"
+            .to_string()));
+    }
+
+    #[test]
+    fn positions() {
+        let data = "a:\n  - b\n  - 'c'\nd: {e: f}\n";
+        let positions = read_events(Rc::new("<inline text>".to_string()),
+                                    data)
+            .map(|e| match e.unwrap() {
+                Event::MapStart(_, _, pos) | Event::SeqStart(_, _, pos)
+                => format!("start {}", pos),
+                Event::MapEnd(pos) | Event::SeqEnd(pos)
+                => format!("end {}", pos),
+                Event::Scalar(_, _, _, value, span)
+                => format!("{} {}", value, span),
+                e => format!("{:?}", e),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(positions, vec![
+            "DocumentStart([])",
+            "start <inline text>:1:1:",
+            "a <inline text>:1:1-1:2",
+            "start <inline text>:2:3:",
+            "b <inline text>:2:5-2:6",
+            "c <inline text>:3:5-3:8",
+            "end <inline text>:3:8:",
+            "d <inline text>:4:1-4:2",
+            "start <inline text>:4:4:",
+            "e <inline text>:4:5-4:6",
+            "f <inline text>:4:8-4:9",
+            "end <inline text>:4:10:",
+            "end <inline text>:4:10:",
+            "DocumentEnd",
+        ]);
+    }
+
+    #[test]
+    fn reader() {
+        let data = "a: 1\n---\n- b\n...\n%YAML 1.2\n---\nc\n";
+        let docs = format_events(read_events_from(
+            Rc::new("<inline text>".to_string()),
+            BufReader::with_capacity(1, data.as_bytes())));
+        assert_eq!(docs, Ok("{\"a\": \"1\", }\n[\"b\", ]\n\"c\"\n"
+                            .to_string()));
+    }
+}
@@ -0,0 +1,438 @@
+//! Default include handler that reads files from the filesystem
+use std::rc::Rc;
+use std::io::Read;
+use std::fs::{File, read_dir};
+use std::path::{Path, PathBuf, Component};
+use indexmap::IndexMap;
+
+use ast::{self, Ast, Tag};
+use encoding::DecodeReader;
+use errors::{Error, ErrorCollector};
+use options::{Options, Include, DoInclude};
+use parser::parse_recover;
+use tokenizer::Pos;
+
+
+/// Resolves `filename` relative to the directory of the file which
+/// contains the include tag
+///
+/// The `.` and `..` components are removed lexically (without following
+/// symlinks), so that file names are stable for detecting include cycles
+pub fn resolve(pos: &Pos, filename: &str) -> PathBuf {
+    let base = Path::new(&pos.filename[..]).parent()
+        .unwrap_or(Path::new(""));
+    return normalize(&base.join(filename));
+}
+
+/// Removes `.` and `..` components of the path lexically
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                match result.components().next_back() {
+                    Some(Component::Normal(_)) => { result.pop(); }
+                    Some(Component::RootDir) => {}
+                    _ => result.push(".."),
+                }
+            }
+            _ => result.push(component.as_os_str()),
+        }
+    }
+    return result;
+}
+
+fn read_file(pos: &Pos, path: &Path, err: &ErrorCollector, opt: &Options)
+    -> Ast
+{
+    opt.add_dependency(path);
+    // The path is checked again, just before opening, and the resolved
+    // path is opened, so that a symlink can't be swapped in between
+    let real = match opt.check_include_root(pos, path, err) {
+        Some(real) => real,
+        None => return Ast::void(pos),
+    };
+    let mut body = String::new();
+    let res = File::open(&real)
+        .and_then(DecodeReader::new)
+        .and_then(|mut f| f.read_to_string(&mut body));
+    if let Err(e) = res {
+        err.add_error(Error::include_error(pos, path, e));
+        return Ast::void(pos);
+    }
+    let filename = Rc::new(format!("{}", path.display()));
+    return parse_recover(filename, &body, err,
+            |doc| { ast::process(opt, doc, err) })
+        .map_err(|e| err.add_error(e))
+        .unwrap_or_else(|_| Ast::void(pos));
+}
+
+/// Returns sorted list of `(star_match, path)` for files in `directory`
+/// that start with `prefix` and end with `suffix`
+///
+/// Like in shell, hidden files are matched only if `prefix` starts with
+/// a dot.
+fn list_files(pos: &Pos, directory: &str, prefix: &str, suffix: &str,
+    err: &ErrorCollector, opt: &Options)
+    -> Vec<(String, PathBuf)>
+{
+    let dir = resolve(pos, directory);
+    let mut result = Vec::new();
+    // Directory is a dependency too, as it's changed when files are added
+    opt.add_dependency(&dir);
+    let entries = match read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            err.add_error(Error::include_error(pos, &dir, e));
+            return result;
+        }
+    };
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                err.add_error(Error::include_error(pos, &dir, e));
+                continue;
+            }
+        };
+        let fname = entry.file_name();
+        let name = match fname.to_str() {
+            Some(name) => name,
+            None => continue,
+        };
+        if name.len() < prefix.len() + suffix.len() ||
+            !name.starts_with(prefix) || !name.ends_with(suffix) ||
+            prefix == "" && name.starts_with(".")
+        {
+            continue;
+        }
+        let key = &name[prefix.len()..name.len() - suffix.len()];
+        let path = dir.join(name);
+        if opt.check_include_root(pos, &path, err).is_some() {
+            result.push((key.to_string(), path));
+        }
+    }
+    result.sort();
+    return result;
+}
+
+/// Include handler which reads included files from the filesystem
+///
+/// Paths are relative to the file which contains the include tag. Nested
+/// includes are processed with the same options.
+pub fn include_file(pos: &Pos, incl: &Include,
+    err: &ErrorCollector, opt: &Options)
+    -> Ast
+{
+    match *incl {
+        Include::File { filename }
+        | Include::SubKey { filename, .. } => {
+            read_file(pos, &resolve(pos, filename), err, opt)
+        }
+        Include::Sequence { directory, prefix, suffix } => {
+            let items = list_files(pos, directory, prefix, suffix, err, opt)
+                .into_iter()
+                .map(|(_, path)| read_file(pos, &path, err, opt))
+                .collect();
+            Ast::Seq(pos.clone().into(), Tag::NonSpecific, None, items)
+        }
+        Include::Mapping { directory, prefix, suffix } => {
+            let items = list_files(pos, directory, prefix, suffix, err, opt)
+                .into_iter()
+                .map(|(key, path)| (key, read_file(pos, &path, err, opt)))
+                .collect::<IndexMap<_, _>>();
+            Ast::Map(pos.clone().into(), Tag::NonSpecific, None, items)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs::{File, create_dir_all, remove_dir_all};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use rustc_serialize::json::{Json, ToJson};
+
+    use errors::ErrorCollector;
+    use parser::parse;
+    use ast::process;
+    use validate::Anything;
+    use {Options, parse_config_with_deps};
+    use super::include_file;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = env::temp_dir().join(format!("quire-test-{}-{}",
+                name, ::std::process::id()));
+            remove_dir_all(&dir).ok();
+            create_dir_all(&dir).unwrap();
+            return TempDir(dir);
+        }
+        fn write(&self, name: &str, data: &str) -> &TempDir {
+            let path = self.0.join(name);
+            create_dir_all(path.parent().unwrap()).unwrap();
+            File::create(path).unwrap().write_all(data.as_bytes()).unwrap();
+            return self;
+        }
+        fn parse(&self, name: &str) -> Result<Json, String> {
+            let mut opt = Options::default();
+            opt.allow_fs_include();
+            return self.parse_with(name, &opt);
+        }
+        fn parse_with(&self, name: &str, opt: &Options)
+            -> Result<Json, String>
+        {
+            let err = ErrorCollector::new();
+            let path = self.0.join(name);
+            let mut body = String::new();
+            ::std::io::Read::read_to_string(
+                &mut File::open(&path).unwrap(), &mut body).unwrap();
+            let ast = parse(::std::rc::Rc::new(format!("{}", path.display())),
+                &body, |doc| { process(opt, doc, &err) })
+                .map_err(|e| err.into_fatal(e).to_string())?;
+            err.into_result(ast.to_json()).map_err(|e| e.to_string()
+                .replace(&format!("{}", self.0.display()), "<tmp>"))
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            remove_dir_all(&self.0).ok();
+        }
+    }
+
+    #[test]
+    fn relative_path() {
+        let dir = TempDir::new("relative");
+        dir.write("main.yaml", "x: !*Include sub/a.yaml")
+           .write("sub/a.yaml", "y: !*Include b.yaml")
+           .write("sub/b.yaml", "1");
+        assert_eq!(dir.parse("main.yaml"),
+            Ok(Json::from_str(r#"{"x": {"y": 1}}"#).unwrap()));
+    }
+
+    #[test]
+    fn sequence_sorted() {
+        let dir = TempDir::new("seq");
+        dir.write("main.yaml", "- !*Unpack [!*IncludeSeq conf.d/*.yaml]")
+           .write("conf.d/b.yaml", "2")
+           .write("conf.d/a.yaml", "1")
+           .write("conf.d/.c.yaml", "3")
+           .write("conf.d/d.yml", "4");
+        assert_eq!(dir.parse("main.yaml"),
+            Ok(Json::from_str(r#"[1, 2]"#).unwrap()));
+    }
+
+    #[test]
+    fn mapping() {
+        let dir = TempDir::new("map");
+        dir.write("main.yaml", "users: !*IncludeMap users/user-*.yaml")
+           .write("users/user-alice.yaml", "1")
+           .write("users/user-bob.yaml", "2");
+        assert_eq!(dir.parse("main.yaml"),
+            Ok(Json::from_str(r#"{"users": {"alice": 1, "bob": 2}}"#)
+               .unwrap()));
+    }
+
+    #[test]
+    fn missing_file() {
+        let dir = TempDir::new("missing");
+        dir.write("main.yaml", "x: !*Include a.yaml");
+        let res = dir.parse("main.yaml").unwrap_err();
+        assert!(res.starts_with("<tmp>/main.yaml:1:14: \
+            Error reading included file <tmp>/a.yaml: "), "{}", res);
+    }
+
+    #[test]
+    fn syntax_errors() {
+        let dir = TempDir::new("syntax");
+        dir.write("main.yaml", "x: !*Include a.yaml\ny: 2")
+           .write("a.yaml", "a: [1\nb: 1\nc: @\n");
+        assert_eq!(dir.parse("main.yaml"),
+            Err("<tmp>/a.yaml:2:1: Parse Error: \
+                Expected comma `,` or colon `:`\n\
+                <tmp>/a.yaml:3:4: Tokenizer Error: \
+                Characters '@' and '`' are not allowed\n".to_string()));
+    }
+
+    #[test]
+    fn colon_in_file_name() {
+        let dir = TempDir::new("colon");
+        dir.write("main.yaml", "x: !*Include a:b.yaml\ny: !*Include c.yaml:k")
+           .write("a:b.yaml", "v: 1")
+           .write("c.yaml", "k: 2");
+        assert_eq!(dir.parse("main.yaml"),
+            Ok(Json::from_str(r#"{"x": {"v": 1}, "y": 2}"#).unwrap()));
+    }
+
+    #[test]
+    fn cycle() {
+        let dir = TempDir::new("cycle");
+        dir.write("a.yaml", "x: !*Include b.yaml")
+           .write("b.yaml", "- !*Include sub/c.yaml")
+           .write("sub/c.yaml", "c: !*Include ../a.yaml");
+        assert_eq!(dir.parse("a.yaml"),
+            Err("<tmp>/sub/c.yaml:1:14: Preprocess Error: \
+                Include cycle detected: <tmp>/a.yaml -> \
+                <tmp>/b.yaml -> <tmp>/sub/c.yaml -> <tmp>/a.yaml\n"
+                .to_string()));
+    }
+
+    #[test]
+    fn glob_cycle() {
+        let dir = TempDir::new("glob-cycle");
+        dir.write("a.yaml", "x: !*IncludeSeq sub/*.yaml")
+           .write("sub/b.yaml", "!*IncludeMap ../*.yaml");
+        assert_eq!(dir.parse("a.yaml"),
+            Err("<tmp>/sub/b.yaml:1:14: Preprocess Error: \
+                Include cycle detected: <tmp>/a.yaml -> \
+                <tmp>/sub/b.yaml -> <tmp>/a.yaml\n".to_string()));
+    }
+
+    #[test]
+    fn max_depth() {
+        let dir = TempDir::new("depth");
+        dir.write("a.yaml", "!*Include b.yaml")
+           .write("b.yaml", "!*Include c.yaml")
+           .write("c.yaml", "!*Include d.yaml")
+           .write("d.yaml", "1");
+        let mut opt = Options::default();
+        opt.allow_fs_include().max_include_depth(3);
+        assert_eq!(dir.parse_with("a.yaml", &opt),
+            Ok(Json::from_str("1").unwrap()));
+        opt.max_include_depth(2);
+        assert_eq!(dir.parse_with("a.yaml", &opt),
+            Err("<tmp>/c.yaml:1:11: Preprocess Error: \
+                Maximum include depth of 2 exceeded\n".to_string()));
+    }
+
+    #[test]
+    fn root_allowed() {
+        let dir = TempDir::new("root-ok");
+        dir.write("main.yaml", "x: !*Include sub/a.yaml")
+           .write("sub/a.yaml", "!*IncludeSeq ./*.yml")
+           .write("sub/b.yml", "1");
+        let mut opt = Options::default();
+        opt.allow_fs_include().include_root(&dir.0);
+        assert_eq!(dir.parse_with("main.yaml", &opt),
+            Ok(Json::from_str(r#"{"x": [1]}"#).unwrap()));
+    }
+
+    #[test]
+    fn root_absolute() {
+        let dir = TempDir::new("root-abs");
+        dir.write("main.yaml", "x: !*Include /etc/passwd");
+        let mut opt = Options::default();
+        opt.allow_fs_include().include_root(&dir.0);
+        assert_eq!(dir.parse_with("main.yaml", &opt),
+            Err("<tmp>/main.yaml:1:14: Preprocess Error: \
+                Absolute include path \"/etc/passwd\" is not allowed\n"
+                .to_string()));
+    }
+
+    #[test]
+    fn root_parent_dir() {
+        let dir = TempDir::new("root-parent");
+        dir.write("sub/main.yaml", "x: !*IncludeSeq ../*.yaml")
+           .write("a.yaml", "1");
+        let mut opt = Options::default();
+        opt.allow_fs_include().include_root(dir.0.join("sub"));
+        assert_eq!(dir.parse_with("sub/main.yaml", &opt),
+            Err("<tmp>/sub/main.yaml:1:17: Preprocess Error: \
+                The /../ is not allowed in include path \"..\"\n"
+                .to_string()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn root_symlink() {
+        use std::os::unix::fs::symlink;
+        let dir = TempDir::new("root-symlink");
+        dir.write("root/main.yaml",
+                  "x: !*Include a.yaml\ny: !*IncludeMap ./*.yml")
+           .write("secret.yaml", "1");
+        symlink(dir.0.join("secret.yaml"), dir.0.join("root/a.yaml"))
+            .unwrap();
+        symlink(dir.0.join("secret.yaml"), dir.0.join("root/b.yml"))
+            .unwrap();
+        let mut opt = Options::default();
+        opt.allow_fs_include().include_root(dir.0.join("root"));
+        let err = dir.parse_with("root/main.yaml", &opt).unwrap_err();
+        let lines = err.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("<tmp>/root/main.yaml:1:14: \
+            Preprocess Error: Included path"), "{}", err);
+        assert!(lines[1].starts_with("<tmp>/root/main.yaml:2:17: \
+            Preprocess Error: Included path"), "{}", err);
+        assert!(lines[1].ends_with("secret.yaml\" is outside of the \
+            allowed directories"), "{}", err);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn root_symlink_swapped() {
+        use std::fs::remove_file;
+        use std::os::unix::fs::symlink;
+        let dir = TempDir::new("root-swap");
+        dir.write("root/main.yaml", "x: !*Include a.yaml")
+           .write("root/good.yaml", "1")
+           .write("secret.yaml", "2");
+        let root = dir.0.join("root");
+        let secret = dir.0.join("secret.yaml");
+        symlink(root.join("good.yaml"), root.join("a.yaml")).unwrap();
+        let mut opt = Options::default();
+        opt.include_root(&root);
+        opt.allow_include(move |pos, incl, err, opt| {
+            // Symlink is replaced after the include tag is checked
+            remove_file(root.join("a.yaml")).unwrap();
+            symlink(&secret, root.join("a.yaml")).unwrap();
+            include_file(pos, incl, err, opt)
+        });
+        let err = dir.parse_with("root/main.yaml", &opt).unwrap_err();
+        assert!(err.starts_with("<tmp>/root/main.yaml:1:14: \
+            Preprocess Error: Included path"), "{}", err);
+    }
+
+    #[test]
+    fn root_missing_file() {
+        let dir = TempDir::new("root-missing");
+        dir.write("main.yaml", "x: !*Include a.yaml");
+        let mut opt = Options::default();
+        opt.allow_fs_include().include_root(&dir.0);
+        let err = dir.parse_with("main.yaml", &opt).unwrap_err();
+        assert!(err.starts_with("<tmp>/main.yaml:1:14: \
+            Error reading included file <tmp>/a.yaml: "), "{}", err);
+    }
+
+    #[test]
+    fn dependencies() {
+        let dir = TempDir::new("deps");
+        dir.write("main.yaml", "- !*Include a.yaml\n\
+                                - !*Unpack [!*IncludeSeq conf.d/*.yaml]\n\
+                                - !*Include missing.yaml")
+           .write("a.yaml", "1")
+           .write("conf.d/b.yaml", "22");
+        let mut opt = Options::default();
+        opt.allow_fs_include();
+        let mut deps = Vec::new();
+        let res: Result<Vec<u32>, _> = parse_config_with_deps(
+            dir.0.join("main.yaml"), &Anything, &opt, &mut deps);
+        assert!(res.is_err());
+        let files = deps.iter()
+            .map(|d| (d.path.strip_prefix(&dir.0).unwrap().to_path_buf(),
+                      d.modified.is_some(), d.size))
+            .collect::<Vec<_>>();
+        let conf_d = files[2].2;
+        assert_eq!(files, vec![
+            (PathBuf::from("main.yaml"), true, 83),
+            (PathBuf::from("a.yaml"), true, 1),
+            (PathBuf::from("conf.d"), true, conf_d),
+            (PathBuf::from("conf.d/b.yaml"), true, 2),
+            (PathBuf::from("missing.yaml"), false, 0),
+        ]);
+    }
+}
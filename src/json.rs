@@ -1,6 +1,7 @@
 use std::str::FromStr;
 
 use std::collections::BTreeMap;
+use rustc_serialize::{Encodable, Encoder};
 use rustc_serialize::json::{ToJson, Json};
 use rustc_serialize::json::Json as J;
 
@@ -9,21 +10,26 @@ use super::ast::Ast as A;
 use super::ast::ScalarKind::{Quoted, Plain};
 
 
+/// Converts the ast to rustc_serialize's `Json`
+///
+/// Mapping keys are sorted, as `Json::Object` is a `BTreeMap` and can't
+/// keep the order of the source. Use the `Encodable` implementation
+/// (e.g. `json::encode(&ast)`) to keep the order.
 impl ToJson for Ast {
     fn to_json(&self) -> Json {
         return match *self {
-            A::Map(_, _, ref tm) => {
+            A::Map(_, _, _, ref tm) => {
                 let mut ob = BTreeMap::new();
                 for (k, v) in tm.iter() {
                     ob.insert(k.clone(), v.to_json());
                 }
                 J::Object(ob)
             },
-            A::Seq(_, _, ref lst) => {
+            A::Seq(_, _, _, ref lst) => {
                 J::Array(lst.iter().map(|ref val| val.to_json()).collect())
             }
-            A::Null(_, _, _) => J::Null,
-            A::Scalar(_, _, Plain, ref val) => {
+            A::Null(_, _, _, _) => J::Null,
+            A::Scalar(_, _, _, Plain, ref val) => {
                 match FromStr::from_str(val) {
                     Ok(x) => return J::U64(x),
                     Err(_) => {}
@@ -41,22 +47,52 @@ impl ToJson for Ast {
                 }
                 J::String(val.clone())
             }
-            A::Scalar(_, _, Quoted, ref val) => {
+            A::Scalar(_, _, _, Quoted, ref val) => {
                 J::String(val.clone())
             }
         };
     }
 }
 
+/// Encodes mappings in the order of the source, unlike `to_json()`
+///
+/// Values are the same as ones returned by `to_json()`
+impl Encodable for Ast {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        match *self {
+            A::Map(_, _, _, ref tm) => {
+                s.emit_map(tm.len(), |s| {
+                    for (idx, (k, v)) in tm.iter().enumerate() {
+                        s.emit_map_elt_key(idx, |s| k.encode(s))?;
+                        s.emit_map_elt_val(idx, |s| v.encode(s))?;
+                    }
+                    Ok(())
+                })
+            }
+            A::Seq(_, _, _, ref lst) => {
+                s.emit_seq(lst.len(), |s| {
+                    for (idx, v) in lst.iter().enumerate() {
+                        s.emit_seq_elt(idx, |s| v.encode(s))?;
+                    }
+                    Ok(())
+                })
+            }
+            _ => self.to_json().encode(s),
+        }
+    }
+}
+
 
 #[cfg(test)]
 mod test {
     use std::rc::Rc;
-    use rustc_serialize::json::ToJson;
+    use std::io::{BufRead, BufReader};
+    use rustc_serialize::json::{self, ToJson};
     use rustc_serialize::json as J;
-    use super::super::parser::parse;
+    use super::super::parser::{parse, parse_all, parse_reader};
+    use super::super::parser::{parse_recover, parse_reader_recover};
     use super::super::ast::process;
-    use ast::Ast;
+    use ast::{Ast, Tag};
     use errors::ErrorCollector;
     use {Options, Include};
 
@@ -71,6 +107,18 @@ mod test {
         assert_eq!(aj, bj);
     }
 
+    #[test]
+    fn test_encode_keeps_order() {
+        let err = ErrorCollector::new();
+        let ast = parse(Rc::new("<inline text>".to_string()),
+            "b: 1\na: [x, {d: ~, c: 'y'}]",
+            |doc| { process(&Options::default(), doc, &err) },
+            ).map_err(|e| err.into_fatal(e)).unwrap();
+        err.into_result(()).unwrap();
+        assert_eq!(json::encode(&ast).unwrap(),
+                   r#"{"b":1,"a":["x",{"d":null,"c":"y"}]}"#);
+    }
+
     #[test]
     fn test_to_json_1() {
         assert_yaml_eq_json("1", "1");
@@ -365,6 +413,87 @@ mod test {
             r#"{"a": "hello\nworld\n"}"#);
     }
 
+    #[test]
+    fn test_folded() {
+        assert_yaml_eq_json(
+            "a: >\n hello\n world\n",
+            r#"{"a": "hello world\n"}"#);
+    }
+
+    #[test]
+    fn test_folded_empty_lines() {
+        assert_yaml_eq_json(
+            "a: >\n\n a\n b\n\n c\n\n\n d\n\n",
+            r#"{"a": "\na b\nc\n\nd\n"}"#);
+    }
+
+    #[test]
+    fn test_folded_more_indented() {
+        assert_yaml_eq_json(
+            ">\n\n folded\n line\n\n next\n line\n   * bullet\n\n   \
+             * list\n   * lines\n\n last\n line\n",
+            "\"\\nfolded line\\nnext line\\n  * bullet\\n\\n  * list\\n  \
+             * lines\\n\\nlast line\\n\"");
+    }
+
+    #[test]
+    fn test_folded_crlf() {
+        assert_yaml_eq_json("a: >\r\n  x\r\n  y\r\n\r\n  z\r\n",
+            r#"{"a": "x y\nz\n"}"#);
+        assert_yaml_eq_json("a: >-\r\n  x\r\n    y\r\n  z\r\n",
+            r#"{"a": "x\n  y\nz"}"#);
+    }
+
+    #[test]
+    fn test_folded_in_list() {
+        assert_yaml_eq_json("- >\n  a\n  b\n- c", r#"["a b\n", "c"]"#);
+    }
+
+    #[test]
+    fn test_block_chomping() {
+        assert_yaml_eq_json("a: |-\n  x\n  y\n\nb: 1",
+            r#"{"a": "x\ny", "b": 1}"#);
+        assert_yaml_eq_json("a: |\n  x\n\n\nb: 1",
+            r#"{"a": "x\n", "b": 1}"#);
+        assert_yaml_eq_json("a: |+\n  x\n\n\nb: 1",
+            r#"{"a": "x\n\n\n", "b": 1}"#);
+        assert_yaml_eq_json("a: >-\n  x\n  y\n",
+            r#"{"a": "x y"}"#);
+        assert_yaml_eq_json("a: >+\n  x\n\n",
+            r#"{"a": "x\n\n"}"#);
+    }
+
+    #[test]
+    fn test_block_chomping_empty() {
+        assert_yaml_eq_json("a: |\nb: |-\n\nc: |+\n\nd: 1",
+            r#"{"a": "", "b": "", "c": "\n", "d": 1}"#);
+    }
+
+    #[test]
+    fn test_block_indentation_indicator() {
+        assert_yaml_eq_json("- |1\n  explicit\n- a: >2\n     x\n- |\n  y",
+            r#"[" explicit\n", {"a": " x\n"}, "y\n"]"#);
+        assert_yaml_eq_json("a: |2-\n   x\nb: |-2\n   y\n",
+            r#"{"a": " x", "b": " y"}"#);
+    }
+
+    #[test]
+    fn test_block_crlf() {
+        assert_yaml_eq_json("a: |\r\n  x\r\n\r\n  y\r\n",
+            r#"{"a": "x\n\ny\n"}"#);
+        assert_yaml_eq_json("a: |-\r\n  x\r\n", r#"{"a": "x"}"#);
+        assert_yaml_eq_json("a: |+\r\n  x\r\n\r\n\r\nb: 1",
+            r#"{"a": "x\n\n\n", "b": 1}"#);
+        assert_yaml_eq_json("a: |2\r\n   x\r\n  y\r\n",
+            r#"{"a": " x\ny\n"}"#);
+    }
+
+    #[test]
+    fn test_block_header_comment() {
+        assert_yaml_eq_json("a: |- # comment\n  x\n",
+            r#"{"a": "x"}"#);
+    }
+
     #[test]
     fn test_map_and_scalar() {
         assert_yaml_eq_json(
@@ -408,6 +537,54 @@ mod test {
         assert_yaml_eq_json("- &a hello\n- *a", r#"["hello", "hello"]"#);
     }
 
+    #[test]
+    fn test_alias_provenance() {
+        use ast::Anchor;
+        let err = ErrorCollector::new();
+        let ast = parse(Rc::new("<inline text>".to_string()),
+            "a: &x {b: 1}\nc: *x\nd: {<<: *x}",
+            |doc| { process(&Options::default(), doc, &err) },
+            ).map_err(|e| err.into_fatal(e)).unwrap();
+        err.into_result(()).unwrap();
+        match ast {
+            Ast::Map(_, _, None, ref map) => {
+                assert_eq!(map["a"].anchor(),
+                           Some(&Anchor::Defined("x".to_string())));
+                assert_eq!(map["c"].anchor(),
+                           Some(&Anchor::Alias("x".to_string())));
+                assert_eq!(map["d"].anchor(), None);
+                match map["d"] {
+                    Ast::Map(_, _, _, ref d) => {
+                        assert_eq!(d["b"].anchor(), None);
+                    }
+                    _ => unreachable!(),
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_flow_alias_anchor() {
+        assert_yaml_eq_json("_p: &primary {host: a}\n\
+                             x: {db: *primary, cache: &c {ttl: 5}, c: *c}",
+            r#"{"_p": {"host": "a"}, "x": {"db": {"host": "a"},
+                "cache": {"ttl": 5}, "c": {"ttl": 5}}}"#);
+    }
+
+    #[test]
+    fn test_flow_key_alias_anchor() {
+        assert_yaml_eq_json("{&k a: 1, b: {*k : 2}, !Tag c: 3}",
+            r#"{"a": 1, "b": {"a": 2}, "c": 3}"#);
+    }
+
+    #[test]
+    fn test_flow_empty_tagged_nodes() {
+        assert_yaml_eq_json("{a: !Tag , b: &x , c: *x, d: }",
+            r#"{"a": null, "b": null, "c": null, "d": null}"#);
+        assert_yaml_eq_json("[&a 1, *a, !Tag ]", r#"[1, 1, null]"#);
+    }
+
     #[test]
     fn test_unpack() {
         assert_yaml_eq_json("- !*Unpack [[hello]]\n", r#"["hello"]"#);
@@ -440,24 +617,49 @@ mod test {
         assert_yaml_eq_json(r#"a: "a\\.b""#, r#"{"a": "a\\.b"}"#);
     }
 
+    #[test]
+    fn hex_escapes() {
+        assert_yaml_eq_json(r#"a: "\x41\u00e9\U0001F600\x7a""#,
+            r#"{"a": "A\u00e9\ud83d\ude00z"}"#);
+    }
+
     #[test]
     fn yaml_tag_null_in_map() {
         assert_yaml_eq_json("x: \n  a: !Tag\n  b: x",
             r#"{"x": {"a": null, "b": "x"}}"#);
     }
 
-    fn assert_yaml_eq_json_incl(a: &'static str, inc_data: &'static str,
-                                b: &'static str)
+    fn parse_incl(a: &'static str, inc_data: &'static str)
+        -> Result<J::Json, String>
     {
         let mut opt = Options::default();
         opt.allow_include(|pos, incl, err, opt| {
             // any include is the same in example
+            let incl_file = |filename: String| {
+                parse(Rc::new(filename), inc_data,
+                    |doc| { process(&opt, doc, err) },
+                ).map_err(|e| err.add_error(e))
+                 .unwrap_or_else(|_| Ast::void(pos))
+            };
             match *incl {
-                Include::File { filename } => {
-                    parse(Rc::new(filename.to_string()), inc_data,
-                        |doc| { process(&opt, doc, err) },
-                    ).map_err(|e| err.add_error(e))
-                     .unwrap_or_else(|_| Ast::void(pos))
+                Include::File { filename }
+                | Include::SubKey { filename, .. } => {
+                    incl_file(filename.to_string())
+                }
+                Include::Sequence { directory, prefix, suffix } => {
+                    Ast::Seq(pos.clone().into(), Tag::NonSpecific, None,
+                        ["a", "b"].iter().map(|name| {
+                            incl_file(format!("{}/{}{}{}",
+                                directory, prefix, name, suffix))
+                        }).collect())
+                }
+                Include::Mapping { directory, prefix, suffix } => {
+                    Ast::Map(pos.clone().into(), Tag::NonSpecific, None,
+                        ["a", "b"].iter().map(|name| {
+                            (name.to_string(),
+                             incl_file(format!("{}/{}{}{}",
+                                directory, prefix, name, suffix)))
+                        }).collect())
                 }
             }
         });
@@ -465,8 +667,13 @@ mod test {
         let ast = parse(Rc::new("<inline text>".to_string()), a,
             |doc| { process(&opt, doc, &err) },
             ).map_err(|e| err.into_fatal(e)).unwrap();
-        err.into_result(()).unwrap();
-        let aj = ast.to_json();
+        err.into_result(ast.to_json()).map_err(|e| e.to_string())
+    }
+
+    fn assert_yaml_eq_json_incl(a: &'static str, inc_data: &'static str,
+                                b: &'static str)
+    {
+        let aj = parse_incl(a, inc_data).unwrap();
         let bj = J::Json::from_str(&b).unwrap();
         assert_eq!(aj, bj);
     }
@@ -496,6 +703,321 @@ mod test {
             r#"{"x": 7, "y": 1}"#);
     }
 
+    #[test]
+    fn test_incl_subkey() {
+        assert_yaml_eq_json_incl(
+            "x: !*Include 'y.yaml:b'",
+            "a: 1\nb: {c: 2}",
+            r#"{"x": {"c": 2}}"#);
+    }
+
+    #[test]
+    fn test_incl_subkey_merge() {
+        assert_yaml_eq_json_incl(
+            "x: 7\n<<: !*Include 'y.yaml:b'",
+            "a: 1\nb: {c: 2}",
+            r#"{"x": 7, "c": 2}"#);
+    }
+
+    #[test]
+    fn test_incl_subkey_unpack() {
+        assert_yaml_eq_json_incl(
+            "- !*Unpack [!*Include 'y.yaml:b']",
+            "a: 1\nb: [7, 8]",
+            r#"[7, 8]"#);
+    }
+
+    #[test]
+    fn test_incl_subkey_missing() {
+        assert_eq!(parse_incl("x: !*Include 'y.yaml:c'", "a: 1"),
+            Err("<inline text>:1:14: Preprocess Error: \
+                Key \"c\" is not found in \"y.yaml\"\n".to_string()));
+    }
+
+    #[test]
+    fn test_incl_colon_in_path() {
+        assert_yaml_eq_json_incl(
+            "x: !*Include 'C:\\y.yaml'\n\
+             z: !*Include 'y.yaml:'\n\
+             w: !*Include 'C:/y.yaml'\n\
+             v: !*Include 'a:b.yaml'",
+            "y: 1",
+            r#"{"x": {"y": 1}, "z": {"y": 1}, "w": {"y": 1},
+                "v": {"y": 1}}"#);
+    }
+
+    #[test]
+    fn test_incl_subkey_path() {
+        assert_yaml_eq_json_incl(
+            "x: !*Include 'y.yaml:a:b'\ny: !*Include 'y.yaml:a.b'",
+            "a: {b: {c: 2}}\na.b: 3",
+            r#"{"x": {"c": 2}, "y": 3}"#);
+        assert_eq!(parse_incl("x: !*Include 'y.yaml:a:c'", "a: {b: 1}"),
+            Err("<inline text>:1:14: Preprocess Error: \
+                Key \"a:c\" is not found in \"y.yaml\"\n".to_string()));
+        assert_eq!(parse_incl("x: !*Include 'y.yaml:a:b:c'", "a: {b: 1}"),
+            Err("<inline text>:1:14: Preprocess Error: \
+                Key \"a:b\" in \"y.yaml\" must be a mapping to include \
+                key \"a:b:c\" from it\n".to_string()));
+    }
+
+    #[test]
+    fn test_incl_subkey_not_a_map() {
+        assert_eq!(parse_incl("x: !*Include 'y.yaml:c'", "[1, 2]"),
+            Err("<inline text>:1:14: Preprocess Error: \
+                File \"y.yaml\" must contain a mapping to include \
+                key \"c\" from it\n".to_string()));
+    }
+
+    #[test]
+    fn test_incl_seq() {
+        assert_yaml_eq_json_incl(
+            "x: !*IncludeSeq 'y/*.yaml'",
+            "y: 1",
+            r#"{"x": [{"y": 1}, {"y": 1}]}"#);
+    }
+
+    #[test]
+    fn test_incl_seq_unpack() {
+        assert_yaml_eq_json_incl(
+            "- 0\n- !*Unpack [!*IncludeSeq 'y/*.yaml']",
+            "7",
+            r#"[0, 7, 7]"#);
+    }
+
+    #[test]
+    fn test_incl_seq_merge() {
+        assert_yaml_eq_json_incl(
+            "x: 7\n<<: !*IncludeSeq 'y/*.yaml'",
+            "y: 1",
+            r#"{"x": 7, "y": 1}"#);
+    }
+
+    #[test]
+    fn test_incl_seq_no_star() {
+        assert_eq!(parse_incl("x: !*IncludeSeq 'y/a.yaml'", "y: 1"),
+            Err("<inline text>:1:17: Preprocess Error: \
+                !*IncludeSeq requires a pattern with a single star \
+                in the file name, like `dir/*.yaml`, \
+                got \"y/a.yaml\"\n".to_string()));
+    }
+
+    #[test]
+    fn test_incl_glob_order() {
+        let mut opt = Options::default();
+        opt.allow_include(|pos, incl, err, opt| {
+            // Handler returns files unsorted
+            let names = ["b", "c", "a"];
+            let file = |name: &str| {
+                parse(Rc::new(format!("y/{}.yaml", name)), name,
+                    |doc| { process(&opt, doc, err) }).unwrap()
+            };
+            match *incl {
+                Include::Sequence { .. } => {
+                    Ast::Seq(pos.clone().into(), Tag::NonSpecific, None,
+                        names.iter().map(|name| file(name)).collect())
+                }
+                Include::Mapping { .. } => {
+                    Ast::Map(pos.clone().into(), Tag::NonSpecific, None,
+                        names.iter().map(|name| {
+                            (name.to_string(), file(name))
+                        }).collect())
+                }
+                _ => unreachable!(),
+            }
+        });
+        let err = ErrorCollector::new();
+        let ast = parse(Rc::new("<inline text>".to_string()),
+            "s: !*IncludeSeq 'y/*.yaml'\nm: !*IncludeMap 'y/*.yaml'",
+            |doc| { process(&opt, doc, &err) }).unwrap();
+        err.into_result(()).unwrap();
+        let children = match ast {
+            Ast::Map(_, _, _, children) => children,
+            _ => unreachable!(),
+        };
+        // Sequence is in the order of the handler, keys are sorted
+        assert_eq!(children["s"].to_json(),
+            J::Json::from_str(r#"["b", "c", "a"]"#).unwrap());
+        match children["m"] {
+            Ast::Map(_, _, _, ref items) => {
+                assert_eq!(items.keys().collect::<Vec<_>>(),
+                           vec!["a", "b", "c"]);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_incl_map() {
+        assert_yaml_eq_json_incl(
+            "x: !*IncludeMap 'y/*.yaml'",
+            "y: 1",
+            r#"{"x": {"a": {"y": 1}, "b": {"y": 1}}}"#);
+    }
+
+    #[test]
+    fn test_incl_map_merge() {
+        assert_yaml_eq_json_incl(
+            "x: 7\n<<: !*IncludeMap 'y/*.yaml'",
+            "1",
+            r#"{"x": 7, "a": 1, "b": 1}"#);
+    }
+
+    #[test]
+    fn test_incl_map_merge_duplicate() {
+        assert_eq!(parse_incl("a: 7\n<<: !*IncludeMap 'y/*.yaml'", "1"),
+            Err("<inline text>:2:18: Preprocess Error: \
+                Key \"a\" included from \"y/*.yaml\" \
+                is already defined at <inline text>:1:1\n".to_string()));
+        assert_eq!(parse_incl("<<: [!*IncludeMap 'y/*.yaml']\nb: 7", "1"),
+            Err("<inline text>:1:19: Preprocess Error: \
+                Key \"b\" included from \"y/*.yaml\" \
+                is already defined at <inline text>:2:1\n".to_string()));
+    }
+
+    #[test]
+    fn test_incl_map_two_stars() {
+        assert_eq!(parse_incl("x: !*IncludeMap 'y/*/*.yaml'", "1"),
+            Err("<inline text>:1:17: Preprocess Error: \
+                !*IncludeMap requires a pattern with a single star \
+                in the file name, like `dir/*.yaml`, \
+                got \"y/*/*.yaml\"\n".to_string()));
+    }
+
+    #[test]
+    fn test_incl_cycle() {
+        assert_eq!(parse_incl("x: !*Include 'y.yaml'",
+                              "y: !*Include 'y.yaml'"),
+            Err("y.yaml:1:14: Preprocess Error: \
+                Include cycle detected: y.yaml -> y.yaml\n".to_string()));
+    }
+
+    #[test]
+    fn test_complex_key_flow_seq() {
+        assert_yaml_eq_json("? [a, b]\n: 1\n? [c, 'd e', '']\n: 2",
+            r#"{"[a, b]": 1, "[c, \"d e\", \"\"]": 2}"#);
+    }
+
+    #[test]
+    fn test_complex_key_block() {
+        assert_yaml_eq_json("? - a\n  - b\n: - 1\n  - 2\nc: 3",
+            r#"{"[a, b]": [1, 2], "c": 3}"#);
+    }
+
+    #[test]
+    fn test_complex_key_scalar() {
+        assert_yaml_eq_json("? a\n: 1\n? b\nc: 2\n?\n: 3",
+            r#"{"a": 1, "b": null, "c": 2, "": 3}"#);
+    }
+
+    #[test]
+    fn test_complex_key_map() {
+        assert_yaml_eq_json("? {y: [2], x: 1, z}\n: v",
+            r#"{"{x: 1, y: [2], z: null}": "v"}"#);
+    }
+
+    #[test]
+    fn test_complex_key_map_value() {
+        assert_yaml_eq_json("? a\n: b: 1\n  c: 2",
+            r#"{"a": {"b": 1, "c": 2}}"#);
+    }
+
+    #[test]
+    fn test_complex_key_nested() {
+        assert_yaml_eq_json("top:\n  ? [a]\n  : 1\n  b: 2\nc:\n- ? x\n  : 3",
+            r#"{"top": {"[a]": 1, "b": 2}, "c": [{"x": 3}]}"#);
+    }
+
+    #[test]
+    fn test_complex_key_flow() {
+        assert_yaml_eq_json("{? a: 1, [b, c]: 2, ? [d], {e: f}: 3}",
+            r#"{"a": 1, "[b, c]": 2, "[d]": null, "{e: f}": 3}"#);
+    }
+
+    #[test]
+    fn test_duplicate_key_last_wins() {
+        let err = ErrorCollector::new();
+        let mut options = Options::default();
+        options.warn_duplicate_keys().warning_handler(|_| {});
+        let ast = parse(Rc::new("<inline text>".to_string()),
+            "a: 1\nb: 2\na: 3",
+            |doc| { process(&options, doc, &err) },
+            ).map_err(|e| err.into_fatal(e)).unwrap();
+        err.into_result(()).unwrap();
+        assert_eq!(ast.to_json(),
+                   J::Json::from_str(r#"{"a": 3, "b": 2}"#).unwrap());
+    }
+
+    #[test]
+    fn test_complex_key_alias() {
+        assert_yaml_eq_json("- &x [a, b]\n- ? *x\n  : 1",
+            r#"[["a", "b"], {"[a, b]": 1}]"#);
+    }
+
+    #[test]
+    fn test_complex_key_scalar_types() {
+        assert_yaml_eq_json("? [null, ~, 'null', 1, '1', a, 'b']\n: 1\n\
+                             ? {x: }\n: 2\n? {x: 'null'}\n: 3",
+            r#"{"[null, null, \"null\", 1, \"1\", a, b]": 1,
+                "{x: null}": 2, "{x: \"null\"}": 3}"#);
+    }
+
+    #[test]
+    fn test_complex_key_ambiguous() {
+        let err = ErrorCollector::new();
+        let res = parse(Rc::new("<inline text>".to_string()),
+            "'[a, b]': 1\n? [a, b]\n: 2",
+            |doc| { process(&Options::default(), doc, &err) },
+            ).map_err(|e| err.into_fatal(e))
+            .and_then(|ast| err.into_result(ast));
+        assert_eq!(res.map_err(|e| e.to_string()).unwrap_err(),
+            "<inline text>:2:3: Preprocess Error: Key \"[a, b]\" is \
+            ambiguous, it matches both a scalar and a non-scalar key, \
+            previously defined at <inline text>:1:1\n");
+    }
+
+    #[test]
+    fn test_core_schema_tags() {
+        assert_yaml_eq_json("a: !!str 123\nb: !!str null\nc: !!int '12'\n\
+                             d: !!float '1.5'\ne: !!str\nf: !!null ''",
+            r#"{"a": "123", "b": "null", "c": 12, "d": 1.5, "e": "",
+                "f": null}"#);
+    }
+
+    #[test]
+    fn test_core_schema_collections() {
+        assert_yaml_eq_json("!!map\na: !!seq [1, 2]",
+            r#"{"a": [1, 2]}"#);
+    }
+
+    #[test]
+    fn test_tag_directive() {
+        assert_yaml_eq_json("%TAG !e! tag:example.com,2000:\n---\n\
+                             a: !e!x 1\nb: !<tag:example.com,2000:y> 2",
+            r#"{"a": 1, "b": 2}"#);
+    }
+
+    #[test]
+    fn test_tag_directive_secondary() {
+        assert_yaml_eq_json("%TAG !! tag:example.com,2000:\n---\n\
+                             a: !!str 1",
+            r#"{"a": 1}"#);
+    }
+
+    #[test]
+    fn test_yaml_1_1_booleans() {
+        assert_yaml_eq_json("%YAML 1.1\n---\na: yes\nb: Off\nc: 'yes'\n\
+                             d: !!bool on\ne: !!str no",
+            r#"{"a": "true", "b": "false", "c": "yes", "d": "true",
+                "e": "no"}"#);
+    }
+
+    #[test]
+    fn test_yaml_1_2_booleans() {
+        assert_yaml_eq_json("%YAML 1.2\n---\na: yes\nb: off",
+            r#"{"a": "yes", "b": "off"}"#);
+    }
+
     #[test]
     fn test_doc_start() {
         assert_yaml_eq_json("---\nx: 1", r#"{"x": 1}"#);
@@ -531,4 +1053,175 @@ mod test {
         assert_yaml_eq_json("- t\n...\nhell@", r#"["t"]"#);
     }
 
+    fn parse_stream(a: &'static str) -> Result<Vec<J::Json>, String> {
+        let err = ErrorCollector::new();
+        let asts = parse_all(Rc::new("<inline text>".to_string()), a,
+            |doc| { process(&Options::default(), doc, &err) },
+            ).map_err(|e| err.into_fatal(e).to_string())?;
+        err.into_result(asts.iter().map(|a| a.to_json()).collect())
+            .map_err(|e| e.to_string())
+    }
+
+    fn assert_stream_eq_json(a: &'static str, b: &'static str) {
+        let aj = J::Json::Array(parse_stream(a).unwrap());
+        let bj = J::Json::from_str(&b).unwrap();
+        assert_eq!(aj, bj);
+    }
+
+    #[test]
+    fn test_stream() {
+        assert_stream_eq_json("a: 1\n---\nb: [1, 2]\n...\n---\n- x\n",
+            r#"[{"a": 1}, {"b": [1, 2]}, ["x"]]"#);
+    }
+
+    #[test]
+    fn test_stream_nested() {
+        assert_stream_eq_json("a:\n  b: 1\n---\n- c:\n  - 2\n---\nd",
+            r#"[{"a": {"b": 1}}, [{"c": [2]}], "d"]"#);
+    }
+
+    #[test]
+    fn test_stream_plain() {
+        assert_stream_eq_json("a\nb\n---\nc\n...\nd",
+            r#"["a b", "c", "d"]"#);
+    }
+
+    #[test]
+    fn test_stream_empty_docs() {
+        assert_stream_eq_json("", "[]");
+        assert_stream_eq_json("# comment\n", "[]");
+        assert_stream_eq_json("---\n---\n", "[null, null]");
+        assert_stream_eq_json("---\na:\n---\n- \n",
+            r#"[{"a": null}, [null]]"#);
+    }
+
+    #[test]
+    fn test_stream_aliases() {
+        assert_stream_eq_json("a: &x 1\nb: *x\n---\n&x 2\n---\n- &x 3\n- *x",
+            r#"[{"a": 1, "b": 1}, 2, [3, 3]]"#);
+        assert_eq!(parse_stream("a: &x 1\n---\nb: *x"),
+            Err("<inline text>:3:4: Parse Error: \
+                Unknown alias \"x\"\n".to_string()));
+    }
+
+    fn parse_reader_stream<R: BufRead>(reader: R)
+        -> Result<Vec<J::Json>, String>
+    {
+        let err = ErrorCollector::new();
+        let asts = parse_reader(Rc::new("<inline text>".to_string()), reader,
+            |doc| { process(&Options::default(), doc, &err) },
+            ).map_err(|e| err.into_fatal(e).to_string())?;
+        err.into_result(asts.iter().map(|a| a.to_json()).collect())
+            .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn test_reader_stream() {
+        for &src in &[
+            "", "# comment\n", "---\n---\n", "---\na:\n---\n- \n",
+            "a: 1\n---\nb: [1, 2]\n...\n---\n- x\n",
+            "a:\n  b: 1\n---\n- c:\n  - 2\n---\nd",
+            "a\nb\n---\nc\n...\nd",
+            "%YAML 1.1\n---\na: yes\n...\n%YAML 1.2\n---\nb: yes\n",
+            "a: |\n  text\n---\nb: >\n  text\n...\n",
+            "a: &x 1\nb: *x\n---\n&x 2\n---\n- &x 3\n- *x",
+            "a: 1\r\n---\r\nb: 2\rc: 3\r---\n...\n",
+        ] {
+            assert_eq!(parse_reader_stream(src.as_bytes()), parse_stream(src));
+            let reader = BufReader::with_capacity(1, src.as_bytes());
+            assert_eq!(parse_reader_stream(reader), parse_stream(src));
+        }
+    }
+
+    #[test]
+    fn test_reader_stream_errors() {
+        assert_eq!(parse_reader_stream("a: &x 1\n---\nb: *x".as_bytes()),
+            Err("<inline text>:3:4: Parse Error: \
+                Unknown alias \"x\"\n".to_string()));
+        let src = "a: 1\n---\n# c\n---\nb: \"x";
+        assert_eq!(parse_reader_stream(src.as_bytes()),
+            Err("<inline text>:5:4: Tokenizer Error: \
+                Unclosed double-quoted string\n".to_string()));
+        assert!(parse_reader_stream(&b"a: 1\n---\nb: \xff\n"[..])
+            .unwrap_err()
+            .starts_with("<inline text>: Error reading file: "));
+    }
+
+    #[test]
+    fn test_single_doc_rejects_stream() {
+        let err = ErrorCollector::new();
+        let res = parse(Rc::new("<inline text>".to_string()), "a\n---\nb",
+            |doc| { process(&Options::default(), doc, &err) });
+        assert_eq!(res.map_err(|e| e.to_string()).unwrap_err(),
+            "<inline text>:2:1: Parse Error: \
+            Expected document end, got DocumentStart");
+    }
+
+    fn parse_recovering(src: &str) -> (J::Json, String) {
+        let err = ErrorCollector::new();
+        let ast = parse_recover(Rc::new("<inline text>".to_string()), src,
+            &err, |doc| { process(&Options::default(), doc, &err) },
+            ).unwrap();
+        return (ast.to_json(), err.unwrap().to_string());
+    }
+
+    #[test]
+    fn test_recover() {
+        assert_eq!(parse_recovering("a: [1, {b: c}]\nd: e"),
+            (J::Json::from_str(r#"{"a": [1, {"b": "c"}], "d": "e"}"#)
+             .unwrap(), "".to_string()));
+        let (json, errors) = parse_recovering("a: 1\nb: [1, 2\nc: 3\n\
+            d:\n  e: 1\n   f: 2\n  g: 3\nh: *x\ni: @\nj: &x 4\n");
+        assert_eq!(json, J::Json::from_str(
+            r#"{"a": 1, "c": 3, "d": {"e": "1 f", "g": 3}, "j": 4}"#)
+            .unwrap());
+        assert_eq!(errors, "\
+            <inline text>:3:1: Parse Error: \
+                Expected comma `,` or colon `:`\n\
+            <inline text>:6:5: Parse Error: \
+                Expected mapping key or unindent, got Indent\n\
+            <inline text>:8:4: Parse Error: Unknown alias \"x\"\n\
+            <inline text>:9:4: Tokenizer Error: \
+                Characters '@' and '`' are not allowed\n");
+    }
+
+    #[test]
+    fn test_reader_recover() {
+        let err = ErrorCollector::new();
+        let src = "a: [1\n---\nb: 2\nc: 'x\n---\n- *x\n- 3\n";
+        let asts = parse_reader_recover(Rc::new("<inline text>".to_string()),
+            src.as_bytes(), &err,
+            |doc| { process(&Options::default(), doc, &err) },
+            ).unwrap();
+        let json = asts.iter().map(|a| a.to_json()).collect::<Vec<_>>();
+        assert_eq!(J::Json::Array(json), J::Json::from_str(
+            r#"[{}, {"b": 2}, [3]]"#).unwrap());
+        assert_eq!(err.unwrap().to_string(), "\
+            <inline text>:2:1: Parse Error: \
+                Expected comma `,` or colon `:`\n\
+            <inline text>:4:4: Tokenizer Error: \
+                Unclosed quoted string\n\
+            <inline text>:6:3: Parse Error: Unknown alias \"x\"\n");
+    }
+
+    #[test]
+    fn test_recover_skipped_root() {
+        assert_eq!(parse_recovering("a: ---\n!t\n@"), (J::Json::Null, "\
+            <inline text>:1:4: Parse Error: \
+                Expected document end, got DocumentStart\n\
+            <inline text>:3:1: Tokenizer Error: \
+                Characters '@' and '`' are not allowed\n".to_string()));
+        assert_eq!(parse_recovering("- ---\n!t\n*"), (J::Json::Null, "\
+            <inline text>:1:3: Parse Error: \
+                Expected document end, got DocumentStart\n\
+            <inline text>:3:1: Tokenizer Error: \
+                Alias name requires at least one character\n".to_string()));
+        assert_eq!(parse_recovering("------\n!<>\n:"), (J::Json::Null, "\
+            <inline text>:1:4: Parse Error: \
+                Expected document end, got DocumentStart\n\
+            <inline text>:3:1: Parse Error: \
+                Expected scalar, sequence or mapping, got MappingValue\n"
+            .to_string()));
+    }
+
 }
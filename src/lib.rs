@@ -34,24 +34,33 @@ extern crate regex;
 extern crate humantime;
 extern crate humannum;
 extern crate num_traits;
+extern crate indexmap;
 #[macro_use] extern crate quick_error;
 
-pub use sky::{parse_config, parse_string};
-pub use options::{Options, Include};
-pub use errors::{Error, ErrorList, ErrorCollector};
-pub use tokenizer::{Pos};
-pub use parser::{parse as raw_parse};
+pub use sky::{parse_config, parse_config_with_deps, parse_string};
+pub use sky::{parse_config_stream, parse_string_stream, parse_reader_stream};
+pub use options::{Options, Include, Dependency};
+pub use errors::{Error, ErrorPos, ErrorList, ErrorCollector};
+pub use tokenizer::{Pos, Span};
+pub use parser::{parse as raw_parse, parse_all as raw_parse_all};
+pub use parser::{parse_reader as raw_parse_reader};
+pub use parser::{parse_recover as raw_parse_recover};
+pub use parser::{parse_reader_recover as raw_parse_reader_recover};
 pub use emit::{emit_ast, emit_object};
 pub use special_cases::De;
 
 mod chars;
+mod encoding;
 mod errors;
 mod tokenizer;
 mod options;
+mod include;
 mod parser;
 mod json;
 mod emit;
 pub mod ast;
+pub mod cst;
+pub mod events;
 mod decode;
 pub mod validate;
 mod sky;
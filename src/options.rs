@@ -1,37 +1,225 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::mem::replace;
+use std::time::SystemTime;
+use std::path::{Path, PathBuf, Component};
+
 use ast::Ast;
 use errors::{Error, ErrorCollector};
+use include::{include_file, resolve, normalize};
 use tokenizer::Pos;
 
 /// Function that handles file include
 pub type IncludeHandler<'a> =
     Fn(&Pos, &Include, &ErrorCollector, &Options) -> Ast + 'a;
 
+/// Function that handles warnings, i.e. problems that don't fail parsing
+pub type WarningHandler<'a> = Fn(&Error) + 'a;
+
 /// The kind of include tag that encountered in config
 pub enum Include<'a> {
     /// Looks like `!Include some/file.yaml`
     File { filename: &'a str },
-    // TODO(tailhook)
-    // /// Looks like `!*Include some/file.yaml:some_key`
-    // SubKey { filename: &'a str, key: &'a str },
-    // /// Looks like `!*IncludeSeq some/*.yaml`
-    // Sequence { directory: &'a str, prefix: &'a str, suffix: &'a str },
-    // /// Looks like `!*IncludeMap some/*.yaml`.
-    // /// Everything matched by star is used as a key
-    // Mapping { directory: &'a str, prefix: &'a str, suffix: &'a str },
+    /// Looks like `!*Include some/file.yaml:some_key`
+    ///
+    /// Handler should return the whole document, the key is looked up
+    /// by the library itself. Key may be a path of colon-separated keys
+    /// of nested mappings, like `parent:child`
+    SubKey { filename: &'a str, key: &'a str },
+    /// Looks like `!*IncludeSeq some/*.yaml`
+    ///
+    /// Handler should return a sequence of documents of all the files
+    /// in `directory` which names start with `prefix` and end with `suffix`,
+    /// sorted by the file name
+    Sequence { directory: &'a str, prefix: &'a str, suffix: &'a str },
+    /// Looks like `!*IncludeMap some/*.yaml`.
+    ///
+    /// Handler should return a mapping of documents of all the files
+    /// matched like in `Sequence`. Everything matched by star is used as
+    /// a key, keys are sorted by the library
+    Mapping { directory: &'a str, prefix: &'a str, suffix: &'a str },
+}
+
+/// A file which configuration was read from
+#[derive(Debug, Clone)]
+pub struct Dependency {
+    /// Path of the file (or directory for glob includes)
+    pub path: PathBuf,
+    /// Modification time or `None` if file could not be read
+    pub modified: Option<SystemTime>,
+    /// Size of the file in bytes or zero if file could not be read
+    pub size: u64,
 }
 
 /// Options for parsing configuration file
 pub struct Options<'a> {
     include_handler: Box<IncludeHandler<'a>>,
+    max_include_depth: usize,
+    /// Names of the files which are being included right now
+    include_stack: RefCell<Vec<Rc<String>>>,
+    include_roots: Vec<PathBuf>,
+    dependencies: RefCell<Option<Vec<Dependency>>>,
+    warning_handler: Box<WarningHandler<'a>>,
+    max_alias_depth: usize,
+    max_expanded_nodes: usize,
+    duplicate_keys_are_warnings: bool,
 }
 
 pub trait DoInclude {
     fn include(&self, pos: &Pos, _: &Include, err: &ErrorCollector) -> Ast;
+    fn check_include_root(&self, pos: &Pos, path: &Path,
+        err: &ErrorCollector) -> Option<PathBuf>;
+}
+
+pub trait AliasLimits {
+    fn alias_depth_limit(&self) -> usize;
+    fn expanded_nodes_limit(&self) -> usize;
+}
+
+impl<'a> AliasLimits for Options<'a> {
+    fn alias_depth_limit(&self) -> usize {
+        self.max_alias_depth
+    }
+    fn expanded_nodes_limit(&self) -> usize {
+        self.max_expanded_nodes
+    }
+}
+
+pub trait DuplicateKeys {
+    fn duplicate_key(&self, pos: &Pos, message: String,
+                     err: &ErrorCollector);
+}
+
+impl<'a> DuplicateKeys for Options<'a> {
+    fn duplicate_key(&self, pos: &Pos, message: String,
+                     err: &ErrorCollector)
+    {
+        if self.duplicate_keys_are_warnings {
+            self.warning(Error::warning(pos, message));
+        } else {
+            err.add_error(Error::preprocess_error(pos, message));
+        }
+    }
 }
 
 impl<'a> DoInclude for Options<'a> {
     fn include(&self, pos: &Pos, incl: &Include, err: &ErrorCollector) -> Ast {
-        (self.include_handler)(pos, incl, err, self)
+        let path = match *incl {
+            Include::File { filename } => filename,
+            Include::SubKey { filename, .. } => filename,
+            Include::Sequence { directory, .. } => directory,
+            Include::Mapping { directory, .. } => directory,
+        };
+        if !self.check_include_path(pos, path, err) {
+            return Ast::void(pos);
+        }
+        {
+            let stack = self.include_stack.borrow();
+            // Files which are being read, the last one contains the tag
+            let open = stack.iter().chain(Some(&pos.filename))
+                .collect::<Vec<_>>();
+            let cycle = open.iter().position(|f| {
+                includes(pos, incl, &normalize(Path::new(&f[..])))
+            });
+            if let Some(idx) = cycle {
+                let chain = open[idx..].iter()
+                    .chain(Some(&open[idx]))
+                    .map(|f| &f[..])
+                    .collect::<Vec<_>>();
+                err.add_error(Error::preprocess_error(pos,
+                    format!("Include cycle detected: {}",
+                        chain.join(" -> "))));
+                return Ast::void(pos);
+            }
+            if stack.len() >= self.max_include_depth {
+                err.add_error(Error::preprocess_error(pos,
+                    format!("Maximum include depth of {} exceeded",
+                        self.max_include_depth)));
+                return Ast::void(pos);
+            }
+        }
+        self.include_stack.borrow_mut().push(pos.filename.clone());
+        let result = (self.include_handler)(pos, incl, err, self);
+        self.include_stack.borrow_mut().pop();
+        return result;
+    }
+    /// Checks that `path` is inside one of the include roots
+    ///
+    /// Symlinks are resolved before checking, and the resolved path is
+    /// returned. It's the path which should be opened, so that symlinks
+    /// can't be replaced after the check. Paths which can't be resolved
+    /// (e.g. don't exist) are rejected.
+    fn check_include_root(&self, pos: &Pos, path: &Path,
+        err: &ErrorCollector) -> Option<PathBuf>
+    {
+        if self.include_roots.len() == 0 {
+            return Some(path.to_path_buf());
+        }
+        let real = match path.canonicalize() {
+            Ok(real) => real,
+            Err(e) => {
+                err.add_error(Error::include_error(pos, path, e));
+                return None;
+            }
+        };
+        for root in &self.include_roots {
+            match root.canonicalize() {
+                Ok(ref root) if real.starts_with(root) => return Some(real),
+                _ => {}
+            }
+        }
+        err.add_error(Error::preprocess_error(pos,
+            format!("Included path {:?} is outside of the allowed \
+                directories", real)));
+        return None;
+    }
+}
+
+/// Returns true if the include tag at `pos` would read the `file`
+fn includes(pos: &Pos, incl: &Include, file: &Path) -> bool {
+    match *incl {
+        Include::File { filename }
+        | Include::SubKey { filename, .. } => {
+            resolve(pos, filename) == file
+        }
+        Include::Sequence { directory, prefix, suffix }
+        | Include::Mapping { directory, prefix, suffix } => {
+            let name = match file.file_name().and_then(|n| n.to_str()) {
+                Some(name) => name,
+                None => return false,
+            };
+            file.parent() == Some(&resolve(pos, directory)) &&
+                name.len() >= prefix.len() + suffix.len() &&
+                name.starts_with(prefix) && name.ends_with(suffix) &&
+                !(prefix == "" && name.starts_with("."))
+        }
+    }
+}
+
+impl<'a> Options<'a> {
+    fn check_include_path(&self, pos: &Pos, value: &str,
+        err: &ErrorCollector) -> bool
+    {
+        if self.include_roots.len() == 0 {
+            return true;
+        }
+        let path = Path::new(value);
+        if path.is_absolute() {
+            err.add_error(Error::preprocess_error(pos,
+                format!("Absolute include path {:?} is not allowed",
+                    value)));
+            return false;
+        }
+        for cmp in path.components() {
+            if cmp == Component::ParentDir {
+                err.add_error(Error::preprocess_error(pos,
+                    format!("The /../ is not allowed in include path {:?}",
+                        value)));
+                return false;
+            }
+        }
+        return self.check_include_root(pos, &resolve(pos, value), err)
+            .is_some();
     }
 }
 
@@ -50,6 +238,14 @@ impl<'a> Options<'a> {
     pub fn default() -> Options<'a> {
         Options {
             include_handler: Box::new(unsupported_include),
+            max_include_depth: 32,
+            include_stack: RefCell::new(Vec::new()),
+            include_roots: Vec::new(),
+            dependencies: RefCell::new(None),
+            warning_handler: Box::new(|_| {}),
+            max_alias_depth: 32,
+            max_expanded_nodes: 100_000,
+            duplicate_keys_are_warnings: false,
         }
     }
     /// Enables including files using specified handler function for reading
@@ -61,4 +257,100 @@ impl<'a> Options<'a> {
         self.include_handler = Box::new(f);
         self
     }
+    /// Sets maximum depth of nested includes (default is 32)
+    ///
+    /// Include cycles are detected by file name regardless of this
+    /// setting, but the limit still protects from cycles where the same
+    /// file is included under different names
+    pub fn max_include_depth(&mut self, depth: usize) -> &mut Options<'a> {
+        self.max_include_depth = depth;
+        self
+    }
+    /// Sets maximum nesting of aliases (default is 32)
+    ///
+    /// I.e. how deep alias may refer to a node containing another alias
+    pub fn max_alias_depth(&mut self, depth: usize) -> &mut Options<'a> {
+        self.max_alias_depth = depth;
+        self
+    }
+    /// Sets maximum number of nodes produced by expanding aliases
+    /// (default is 100000)
+    ///
+    /// This protects from documents like "billion laughs", which are small
+    /// but expand into a huge tree because of nested aliases
+    pub fn max_expanded_nodes(&mut self, num: usize) -> &mut Options<'a> {
+        self.max_expanded_nodes = num;
+        self
+    }
+    /// Restricts includes to the specified directory
+    ///
+    /// May be called multiple times to allow several directories. Once any
+    /// root is set, absolute paths and `..` are not allowed in include
+    /// tags, and included files (after resolving symlinks) must be inside
+    /// one of the roots. Paths that can't be resolved are rejected too.
+    /// This applies to any include handler.
+    pub fn include_root<P: AsRef<Path>>(&mut self, path: P)
+        -> &mut Options<'a>
+    {
+        self.include_roots.push(path.as_ref().to_path_buf());
+        self
+    }
+    /// Records a file which configuration depends on
+    ///
+    /// Should be called by include handler before reading a file. Does
+    /// nothing unless dependencies are collected (see
+    /// `parse_config_with_deps`). Missing files are recorded too, so that
+    /// configuration may be reloaded when they appear.
+    pub fn add_dependency(&self, path: &Path) {
+        if let Some(ref mut deps) = *self.dependencies.borrow_mut() {
+            let meta = path.metadata().ok();
+            deps.push(Dependency {
+                path: path.to_path_buf(),
+                modified: meta.as_ref().and_then(|m| m.modified().ok()),
+                size: meta.as_ref().map(|m| m.len()).unwrap_or(0),
+            });
+        }
+    }
+    /// Sets a function which receives warnings
+    ///
+    /// Warnings are `Error::Warning` values. By default they are ignored
+    pub fn warning_handler<F>(&mut self, f: F) -> &mut Options<'a>
+        where F: Fn(&Error) + 'a
+    {
+        self.warning_handler = Box::new(f);
+        self
+    }
+    /// Reports duplicate mapping keys as warnings instead of errors
+    ///
+    /// The last value of the duplicate key is used in this case. Keys
+    /// which come from merge keys `<<` are never considered duplicates,
+    /// except keys included by `!*IncludeMap` which are also defined in
+    /// the mapping itself (its own value is used).
+    pub fn warn_duplicate_keys(&mut self) -> &mut Options<'a> {
+        self.duplicate_keys_are_warnings = true;
+        self
+    }
+    /// Reports a warning using the handler set by `warning_handler`
+    pub fn warning(&self, warning: Error) {
+        (self.warning_handler)(&warning)
+    }
+    /// Enables including files from the filesystem
+    ///
+    /// File names are resolved relative to the file containing include
+    /// directive. Nested includes are processed with the same options.
+    pub fn allow_fs_include(&mut self) -> &mut Options<'a> {
+        self.allow_include(include_file)
+    }
+}
+
+/// Runs `f` collecting all files added by `Options::add_dependency`
+pub fn collect_dependencies<T, F>(options: &Options, f: F)
+    -> (T, Vec<Dependency>)
+    where F: FnOnce() -> T
+{
+    let old = replace(&mut *options.dependencies.borrow_mut(),
+                      Some(Vec::new()));
+    let result = f();
+    let deps = replace(&mut *options.dependencies.borrow_mut(), old);
+    return (result, deps.unwrap());
 }
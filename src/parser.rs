@@ -1,44 +1,111 @@
+use std::borrow::Cow;
+use std::io::BufRead;
 use std::iter::Peekable;
+use std::mem;
+use std::path::PathBuf;
 use std::str::Chars;
 use std::rc::Rc;
 use std::fmt::Debug;
 use std::fmt::Error as FormatError;
 use std::fmt::{Formatter};
-use std::cmp::{Ordering};
-use std::cmp::Ordering::{Less, Equal, Greater};
-use std::collections::BTreeMap;
+use std::cmp::Ordering;
+use std::char;
+use std::cmp::Ordering::{Equal};
+use std::collections::{BTreeMap, HashSet, VecDeque};
 
-use super::tokenizer::{Token, Pos};
-use super::errors::Error;
-use super::tokenizer::tokenize;
+use super::tokenizer::{Token, TokenReader, Pos, Span};
+use super::errors::{Error, ErrorCollector};
 use super::tokenizer::TokenType as T;
 use self::Node::*;
 
-type Aliases<'x> = BTreeMap<&'x str, Node<'x>>;
+type Aliases<'x> = BTreeMap<Cow<'x, str>, Rc<Node<'x>>>;
 
-pub struct TokenIter<'a> {
+/// Iterator over the tokens read by `TokenReader`
+///
+/// Only the tokens starting at `base` are kept, i.e. the ones which are
+/// not released yet. Tokens are read lazily, never past the end of file
+/// or an error.
+pub struct TokenIter<'a, R> {
+    reader: TokenReader<'a, R>,
+    name: Rc<String>,
+    tokens: VecDeque<Rc<Token<'a>>>,
+    /// Index of the first token in `tokens`
+    base: usize,
+    /// Index of the next token
     index: usize,
-    tokens: &'a[Token<'a>],
-    eof_token: &'a Token<'a>,
+    /// Error for the marker token, which is the last one in `tokens`
+    error: Option<Error>,
+    /// The error is a reading error, no tokens can follow it
+    fatal: bool,
+    block_indent: usize,
+    /// End of the last significant token consumed
+    last_end: Pos,
+    /// Start of the last token consumed, which is first on its line
+    line_start: Option<Pos>,
+    /// Marker token is returned by `peek`
+    touched: bool,
 }
 
-impl<'a> TokenIter<'a> {
-    fn new<'x>(src: &'x Vec<Token<'x>>) -> TokenIter<'x> {
-        let last = src.last().expect("Non-empty document expected");
-        assert!(last.kind == T::Eof);
+impl<'a, R: BufRead> TokenIter<'a, R> {
+    pub fn new(reader: TokenReader<'a, R>) -> TokenIter<'a, R> {
+        let name = reader.name();
         return TokenIter {
+            reader: reader,
+            name: name.clone(),
+            tokens: VecDeque::new(),
+            base: 0,
             index: 0,
-            tokens: &src[..],
-            eof_token: last,
+            error: None,
+            fatal: false,
+            block_indent: 0,
+            last_end: Pos {
+                filename: name,
+                indent: 0,
+                line: 1,
+                line_start: true,
+                line_offset: 1,
+                offset: 0,
+            },
+            line_start: None,
+            touched: false,
         };
     }
-    fn peek(&mut self, index: usize) -> &'a Token<'a> {
+
+    /// Reads the next token, errors are replaced by the marker token
+    fn pull(&mut self) {
+        let pos = self.last_end.clone();
+        let tok = match self.reader.next_token() {
+            Ok(Some(Ok(tok))) => tok,
+            Ok(Some(Err((pos, err)))) => {
+                self.error = Some(Error::tokenizer_error((pos.clone(), err)));
+                marker(T::Error, pos)
+            }
+            Ok(None) => marker(T::Eof, pos),
+            Err(err) => {
+                self.error = Some(Error::OpenError(
+                    PathBuf::from(&self.name[..]), err));
+                self.fatal = true;
+                marker(T::Error, pos)
+            }
+        };
+        self.tokens.push_back(Rc::new(tok));
+    }
+
+    pub fn peek(&mut self, index: usize) -> Rc<Token<'a>> {
         let mut num = index;
-        for idx in self.index..self.tokens.len() {
-            let tok = self.tokens.get(idx).unwrap();
+        let mut idx = self.index - self.base;
+        loop {
+            if idx == self.tokens.len() {
+                self.pull();
+            }
+            let tok = self.tokens[idx].clone();
             match tok.kind {
-                T::Whitespace | T::Comment => continue,
-                T::Eof => return self.eof_token,
+                T::Whitespace | T::Comment => {}
+                T::Eof => return tok,
+                T::Error => {
+                    self.touched = true;
+                    return tok;
+                }
                 _ => {
                     if num == 0 {
                         return tok;
@@ -46,24 +113,108 @@ impl<'a> TokenIter<'a> {
                     num -= 1;
                 }
             }
+            idx += 1;
         }
-        return self.eof_token;
     }
 
-    fn next(&mut self) -> Option<&'a Token<'a>> {
+    /// Index of the next token
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Token at the `index`, which must be kept by the iterator
+    pub fn get(&self, index: usize) -> &Token<'a> {
+        return &self.tokens[index - self.base];
+    }
+
+    /// Indentation of the node containing the last returned token
+    ///
+    /// This is what explicit indentation indicator of block scalar is
+    /// relative to
+    pub fn block_indent(&self) -> usize {
+        self.block_indent
+    }
+
+    /// End of the last significant token returned
+    pub fn last_end(&self) -> Pos {
+        self.last_end.clone()
+    }
+
+    pub fn next(&mut self) -> Option<Rc<Token<'a>>> {
         loop {
-            let tok = match self.tokens.get(self.index) {
-                None => return None,
-                Some(tok) => tok,
-            };
-            self.index += 1;
+            if self.index - self.base == self.tokens.len() {
+                self.pull();
+            }
+            let tok = self.tokens[self.index - self.base].clone();
             match tok.kind {
-                T::Whitespace | T::Comment => continue,
+                T::Whitespace | T::Comment => {
+                    self.index += 1;
+                    continue;
+                }
                 T::Eof => return None,
-                _ => return Some(tok),
+                T::Error if self.fatal => return None,
+                T::Error => self.error = None,
+                T::Indent | T::Tag | T::Anchor => {}
+                T::SequenceEntry | T::MappingKey => {
+                    self.block_indent = tok.start.line_offset - 1;
+                }
+                T::MappingValue => self.block_indent = tok.start.indent,
+                _ => self.block_indent = 0,
+            }
+            self.index += 1;
+            if tok.start.line_start {
+                self.line_start = Some(tok.start.clone());
             }
+            if is_significant(&&*tok) {
+                self.last_end = tok.end.clone();
+            }
+            return Some(tok);
         }
     }
+
+    /// Takes the error of the marker token, the marker itself is kept
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.error.take()
+    }
+
+    /// Drops the consumed tokens and the input before the next token
+    pub fn release(&mut self) {
+        let consumed = self.index - self.base;
+        self.tokens.drain(..consumed);
+        self.base = self.index;
+        let offset = match self.tokens.get(self.index - self.base) {
+            Some(tok) => tok.start.offset,
+            None => match self.tokens.back() {
+                Some(tok) => tok.end.offset,
+                None => return,
+            },
+        };
+        self.reader.release(offset);
+    }
+
+    /// Tokenizes the rest of the input again in the block context
+    ///
+    /// Used when flow collections are skipped by the error recovery
+    pub fn restart_block(&mut self) {
+        let tok = self.peek(0);
+        if tok.kind == T::Eof || tok.kind == T::Error && self.fatal {
+            return;
+        }
+        let consumed = self.index - self.base;
+        self.tokens.truncate(consumed);
+        self.error = None;
+        self.reader.restart(&tok.start);
+    }
+}
+
+/// Zero-width token, which is not read from the input
+fn marker<'a>(kind: T, pos: Pos) -> Token<'a> {
+    return Token {
+        kind: kind,
+        start: pos.clone(),
+        end: pos,
+        value: "".into(),
+    };
 }
 
 fn process_newline<'x>(iter: &mut Peekable<Chars<'x>>, res: &mut String,
@@ -97,7 +248,31 @@ fn process_newline<'x>(iter: &mut Peekable<Chars<'x>>, res: &mut String,
     }
 }
 
-fn plain_value<'a>(tok: &Token<'a>) -> Result<String, Error> {
+/// Reads `digits` hex digits of the `\x`, `\u` or `\U` escape
+fn hex_escape<'x>(tok: &Token, iter: &mut Peekable<Chars<'x>>,
+    kind: char, digits: usize)
+    -> Result<char, Error>
+{
+    let mut code = 0;
+    for i in 0..digits {
+        match iter.peek().and_then(|c| c.to_digit(16)) {
+            Some(digit) => code = code*16 + digit,
+            None => {
+                return Err(Error::parse_error(&tok.start,
+                    format!("Escape \\{} requires {} hex digits, got {}",
+                        kind, digits, i)));
+            }
+        }
+        iter.next();
+    }
+    return char::from_u32(code).ok_or_else(|| {
+        Error::parse_error(&tok.start,
+            format!("Escape \\{} specifies invalid code point {:#x}",
+                kind, code))
+    });
+}
+
+pub fn plain_value<'a>(tok: &Token<'a>) -> Result<String, Error> {
     let mut res = String::with_capacity(tok.value.len());
     match tok.kind {
         T::PlainString => {
@@ -143,14 +318,14 @@ fn plain_value<'a>(tok: &Token<'a>) -> Result<String, Error> {
                             Some('_') => res.push('\u{a0}'),
                             Some('L') => res.push('\u{2028}'),
                             Some('P') => res.push('\u{2029}'),
-                            Some('x') => {
-                                unimplemented!();
-                            },
-                            Some('u') => {
-                                unimplemented!();
-                            },
-                            Some('U') => {
-                                unimplemented!();
+                            Some(x @ 'x') | Some(x @ 'u') | Some(x @ 'U') => {
+                                let digits = match x {
+                                    'x' => 2,
+                                    'u' => 4,
+                                    _ => 8,
+                                };
+                                res.push(hex_escape(tok, &mut iter, x,
+                                                    digits)?);
                             },
                             Some('\n') => {
                                 escaped_space = res.len();
@@ -193,36 +368,155 @@ fn plain_value<'a>(tok: &Token<'a>) -> Result<String, Error> {
                 }
             }
         }
-        T::Literal => {
-            let mut lines = tok.value.split('\n');
-            let fline = lines.next().unwrap();
-            if fline.trim() == "|" {
-                let mut indent = 0;
-                for line in lines {
-                    if indent == 0 {
-                        let trimmed = line.trim_left_matches(' ');
-                        indent = line.len() - trimmed.len();
-                    }
-                    if line.len() < indent {
-                        assert!(line[..].chars().all(|x| x == ' '));
-                    } else {
-                        res.push_str(&line[indent..line.len()]);
-                    }
-                    res.push('\n');
+        _ => unreachable!(),
+    }
+    return Ok(res);
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Chomping {
+    Strip,
+    Clip,
+    Keep,
+}
+
+/// Returns value of literal or folded block scalar
+///
+/// The `parent_indent` is the indentation of the node containing the
+/// scalar, explicit indentation indicator is relative to it
+fn block_value<'a>(tok: &Token<'a>, parent_indent: usize)
+    -> Result<String, Error>
+{
+    let folded = tok.kind == T::Folded;
+    let (header, body) = match tok.value.find('\n') {
+        Some(idx) => (&tok.value[..idx], Some(&tok.value[idx+1..])),
+        None => (&tok.value[..], None),
+    };
+    let mut chomping = None;
+    let mut indent = None;
+    let mut hiter = header[1..].chars();
+    loop {
+        match hiter.next() {
+            Some('-') if chomping.is_none() => {
+                chomping = Some(Chomping::Strip);
+            }
+            Some('+') if chomping.is_none() => {
+                chomping = Some(Chomping::Keep);
+            }
+            Some(x) if x.is_digit(10) && x != '0' && indent.is_none() => {
+                indent = Some(parent_indent +
+                              x.to_digit(10).unwrap() as usize);
+            }
+            None => break,
+            Some(' ') | Some('\t') | Some('\r') => {
+                let rest = hiter.as_str().trim();
+                if rest == "" || rest.starts_with("#") {
+                    break;
                 }
-            } else {
-                unimplemented!();
+                return Err(Error::parse_error(&tok.start,
+                    format!("Invalid block scalar header {:?}", header)));
+            }
+            Some(_) => {
+                return Err(Error::parse_error(&tok.start,
+                    format!("Invalid block scalar header {:?}", header)));
             }
         }
-        T::Folded => {
-            unimplemented!();
+    }
+    let mut res = String::with_capacity(tok.value.len());
+    // None before the first non-empty line, otherwise whether
+    // the previous non-empty line may be folded
+    let mut prev_normal = None;
+    let mut empty_lines = 0;
+    let mut offset = header.len() + 1;
+    for (idx, line) in body.into_iter().flat_map(|b| b.split('\n'))
+                           .enumerate()
+    {
+        let line_offset = offset;
+        offset += line.len() + 1;
+        // The `\r` of a `\r\n` line break isn't a part of the line
+        let line = if line.ends_with('\r') {
+            &line[..line.len() - 1]
+        } else {
+            line
+        };
+        let spaces = line.len() - line.trim_left_matches(' ').len();
+        if spaces == line.len() &&
+            (folded || indent.map(|x| spaces <= x).unwrap_or(true))
+        {
+            empty_lines += 1;
+            continue;
+        }
+        let indent = *indent.get_or_insert(spaces);
+        if spaces < indent {
+            let mut pos = tok.start.clone();
+            pos.line += idx + 1;
+            pos.line_start = true;
+            pos.line_offset = spaces + 1;
+            pos.indent = spaces;
+            pos.offset += line_offset + spaces;
+            return Err(Error::parse_error(&pos,
+                "Less indented line in a block scalar".to_string()));
+        }
+        let line = &line[indent..];
+        // More indented lines are not folded
+        let normal = folded &&
+            !line.starts_with(' ') && !line.starts_with('\t');
+        match prev_normal {
+            None => {}
+            Some(true) if normal && empty_lines == 0 => res.push(' '),
+            Some(true) if normal => {}
+            Some(_) => res.push('\n'),
+        }
+        for _ in 0..empty_lines {
+            res.push('\n');
+        }
+        res.push_str(line);
+        prev_normal = Some(normal);
+        empty_lines = 0;
+    }
+    match chomping.unwrap_or(Chomping::Clip) {
+        Chomping::Strip => {}
+        Chomping::Clip => {
+            if prev_normal.is_some() {
+                res.push('\n');
+            }
+        }
+        Chomping::Keep => {
+            if prev_normal.is_some() {
+                res.push('\n');
+            }
+            for _ in 0..empty_lines {
+                res.push('\n');
+            }
         }
-        _ => unreachable!(),
     }
     return Ok(res);
 }
 
-pub struct Directive<'a>(&'a Token<'a>);
+pub struct Directive<'a>(Token<'a>);
+
+impl<'a> Debug for Directive<'a> {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), FormatError> {
+        write!(fmt, "<Directive {}>", self.0.value)
+    }
+}
+
+impl<'a> Directive<'a> {
+    /// Position of the directive in the source
+    pub fn pos(&self) -> &Pos {
+        &self.0.start
+    }
+    /// Name of the directive without percent sign, e.g. `TAG`
+    pub fn name(&self) -> &str {
+        self.0.value[1..].split_whitespace().next().unwrap_or("")
+    }
+    /// Parameters of the directive (trailing comment is stripped)
+    pub fn params(&self) -> Vec<&str> {
+        self.0.value[1..].split_whitespace().skip(1)
+            .take_while(|x| !x.starts_with('#'))
+            .collect()
+    }
+}
 
 pub struct Document<'a> {
     pub directives: Vec<Directive<'a>>,
@@ -231,23 +525,41 @@ pub struct Document<'a> {
 
 #[derive(Clone)]
 pub enum Node<'a> {
-    Map(Option<&'a str>, Option<&'a str>,
-        BTreeMap<Node<'a>, Node<'a>>, &'a[Token<'a>]),
-    Seq(Option<&'a str>, Option<&'a str>, Vec<Node<'a>>, &'a[Token<'a>]),
-    Scalar(Option<&'a str>, Option<&'a str>, String, &'a Token<'a>),
+    /// Mapping, pairs are in the order of the source, duplicate keys
+    /// are preserved so they can be reported by the preprocessor
+    Map(Option<Cow<'a, str>>, Option<Cow<'a, str>>,
+        Vec<(Node<'a>, Node<'a>)>, Span),
+    Seq(Option<Cow<'a, str>>, Option<Cow<'a, str>>, Vec<Node<'a>>, Span),
+    /// Scalar with the kind of the token it's written as
+    Scalar(Option<Cow<'a, str>>, Option<Cow<'a, str>>, String, T, Span),
     // Explicit null is a Scalar at this state of parsing
-    ImplicitNull(Option<&'a str>, Option<&'a str>, Pos),
-    Alias(&'a str, &'a Token<'a>, Box<Node<'a>>),
+    ImplicitNull(Option<Cow<'a, str>>, Option<Cow<'a, str>>, Pos),
+    /// Reference to anchored node, the node is shared between all aliases
+    Alias(Cow<'a, str>, Span, Rc<Node<'a>>),
+}
+
+fn _rank(node: &Node) -> u8 {
+    match *node {
+        ImplicitNull(_, _, _) => 0,
+        Scalar(_, _, _, _, _) => 1,
+        Seq(_, _, _, _) => 2,
+        Map(_, _, _, _) => 3,
+        Alias(_, _, _) => 4,
+    }
 }
 
+/// Compares nodes structurally
+///
+/// Aliases are not expanded (so comparison is cheap even for malicious
+/// input), they are compared by the name of the anchor
 fn _compare(a: &Node, b: &Node) -> Ordering {
     match (a, b) {
-        (&Scalar(_, _, ref a, _), &Scalar(_, _, ref b, _))
+        (&Alias(ref a, _, _), &Alias(ref b, _, _)) => a.cmp(b),
+        (&Scalar(_, _, ref a, _, _), &Scalar(_, _, ref b, _, _))
         => a.cmp(b),
-        (&ImplicitNull(_, _, _), &Scalar(_, _, _, _)) => Less,
-        (&Scalar(_, _, _, _), &ImplicitNull(_, _, _)) => Greater,
-        (&ImplicitNull(_, _, _), &ImplicitNull(_, _, _)) => Equal,
-        _ => unimplemented!(),
+        (&Seq(_, _, ref a, _), &Seq(_, _, ref b, _)) => a.cmp(b),
+        (&Map(_, _, ref a, _), &Map(_, _, ref b, _)) => a.cmp(b),
+        (a, b) => _rank(a).cmp(&_rank(b)),
     }
 }
 
@@ -266,12 +578,53 @@ impl<'a> PartialOrd for Node<'a> {
 impl<'a> Eq for Node<'a> {}
 impl<'a> PartialEq for Node<'a> {
     fn eq(&self, other: &Node) -> bool {
-        return match (self, other) {
-            (&Scalar(_, _, ref a, _), &Scalar(_, _, ref b, _)) => a == b,
-            (&ImplicitNull(_, _, _), &Scalar(_, _, _, _)) => false,
-            (&Scalar(_, _, _, _), &ImplicitNull(_, _, _)) => false,
-            (&ImplicitNull(_, _, _), &ImplicitNull(_, _, _)) => true,
-            _ => unimplemented!(),
+        return _compare(self, other) == Equal;
+    }
+}
+
+fn is_significant(tok: &&Token) -> bool {
+    match tok.kind {
+        T::Whitespace | T::Comment | T::Indent | T::Unindent
+        | T::Eof | T::DocumentStart | T::DocumentEnd => false,
+        _ => true,
+    }
+}
+
+impl<'a> Node<'a> {
+    /// Tag of the node as written in the source, e.g. `!Tag`
+    pub fn tag(&self) -> Option<&str> {
+        match *self {
+            Map(ref tag, _, _, _) | Seq(ref tag, _, _, _)
+            | Scalar(ref tag, _, _, _, _) | ImplicitNull(ref tag, _, _) => {
+                tag.as_ref().map(|x| &x[..])
+            }
+            Alias(..) => None,
+        }
+    }
+
+    /// Name of the anchor defined at the node, without the `&` sigil
+    pub fn anchor(&self) -> Option<&str> {
+        match *self {
+            Map(_, ref anchor, _, _) | Seq(_, ref anchor, _, _)
+            | Scalar(_, ref anchor, _, _, _)
+            | ImplicitNull(_, ref anchor, _) => {
+                anchor.as_ref().map(|x| &x[..])
+            }
+            Alias(..) => None,
+        }
+    }
+
+    /// Span of the node in the source text
+    ///
+    /// Tag and anchor are not included, as well as whitespace and comments
+    /// around collections
+    pub fn span(&self) -> Span {
+        match *self {
+            Map(_, _, _, ref span) | Seq(_, _, _, ref span)
+            | Scalar(_, _, _, _, ref span) | Alias(_, ref span, _) => {
+                span.clone()
+            }
+            ImplicitNull(_, _, ref pos) => Span::from(pos.clone()),
         }
     }
 }
@@ -279,463 +632,1038 @@ impl<'a> PartialEq for Node<'a> {
 impl<'a> Debug for Node<'a> {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), FormatError> {
         match self {
-            &Scalar(_, _, ref a, _) => write!(fmt, "<Scalar {}>", a),
+            &Scalar(_, _, ref a, _, _) => write!(fmt, "<Scalar {}>", a),
             &ImplicitNull(_, _, _) => write!(fmt, "<Null>"),
-            _ => unimplemented!(),
+            &Seq(_, _, ref a, _) => write!(fmt, "<Seq {:?}>", a),
+            &Map(_, _, ref a, _) => write!(fmt, "<Map {:?}>", a),
+            &Alias(ref name, _, _) => write!(fmt, "<Alias {}>", name),
         }
     }
 }
+/// Parser event, tokens are referenced by their index
+///
+/// Tokens of the event are kept by the parser until the next event is
+/// requested
+#[derive(Debug)]
+pub enum Event {
+    /// Start of the document, with directives preceding it
+    DocumentStart(Vec<usize>),
+    /// End of the document
+    DocumentEnd,
+    /// Start of the mapping: tag, anchor and position of the first token
+    MapStart(Option<usize>, Option<usize>, Pos),
+    /// End of the mapping, position right after the last token
+    MapEnd(Pos),
+    /// Start of the sequence, same as `MapStart`
+    SeqStart(Option<usize>, Option<usize>, Pos),
+    /// End of the sequence, same as `MapEnd`
+    SeqEnd(Pos),
+    /// Scalar value: tag, anchor, value and the scalar token
+    Scalar(Option<usize>, Option<usize>, String, usize),
+    /// Implicit null value, like in `a:`
+    Null(Option<usize>, Option<usize>, Pos),
+    /// Reference to the anchored node
+    Alias(usize),
+    /// The entry starting at the position is skipped after an error,
+    /// along with the number of unfinished collections. Root node is
+    /// parsed again if the flag is set
+    Recover(Pos, usize, bool),
+}
 
+/// Part of the document being parsed, kept on the stack
+///
+/// Frames contain offset of the first token of the node
+#[derive(Clone)]
+enum Frame {
+    /// Root node of the document is expected
+    Root,
+    /// Root node of the document is started
+    Document,
+    /// The node was indented, so unindent is expected after it
+    Unindent(usize),
+    /// Anchor is defined when the node is finished
+    Anchor(usize, String),
+    /// Block sequence
+    Seq(usize),
+    /// Block mapping, next key is expected
+    MapKey(usize),
+    /// Block mapping, value of the explicit key `? key` may follow
+    MapExplicitKey(usize),
+    /// Block mapping, colon and value are expected
+    MapValue(usize),
+    /// Flow sequence, next item is expected
+    FlowSeqItem(usize),
+    /// Flow sequence, comma or closing bracket is expected
+    FlowSeqNext(usize),
+    /// Flow mapping, next key is expected
+    FlowMapKey(usize),
+    /// Flow mapping, colon, comma or closing bracket is expected
+    FlowMapColon(usize),
+    /// Flow mapping, comma or closing bracket is expected
+    FlowMapNext(usize),
+}
 
-fn parse_list<'x>(tokiter: &mut TokenIter<'x>, aliases: &mut Aliases<'x>,
-    tag: Option<&'x str>, anchor: Option<&'x str>)
-    -> Result<Node<'x>, Error>
-{
-    let begin = tokiter.index;
-    let mut children = Vec::new();
-    loop {
-        let marker = tokiter.peek(0);
-        match marker.kind {
-            T::SequenceEntry => {}
-            _ => break,
+impl Frame {
+    fn is_flow(&self) -> bool {
+        match *self {
+            Frame::FlowSeqItem(_) | Frame::FlowSeqNext(_)
+            | Frame::FlowMapKey(_) | Frame::FlowMapColon(_)
+            | Frame::FlowMapNext(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Offset of the first token of the frame
+    fn offset(&self) -> usize {
+        match *self {
+            Frame::Root | Frame::Document => 0,
+            Frame::Unindent(offset) | Frame::Anchor(offset, _) => offset,
+            Frame::Seq(start) | Frame::MapKey(start)
+            | Frame::MapExplicitKey(start) | Frame::MapValue(start)
+            | Frame::FlowSeqItem(start) | Frame::FlowSeqNext(start)
+            | Frame::FlowMapKey(start) | Frame::FlowMapColon(start)
+            | Frame::FlowMapNext(start) => start,
+        }
+    }
+}
+
+/// Pull parser, which reads the input as the events are requested
+///
+/// Iteration stops after an error, unless `recover` is called
+pub struct Parser<'a, R> {
+    tokiter: TokenIter<'a, R>,
+    stack: Vec<Frame>,
+    /// Anchors defined so far in the current document
+    anchors: HashSet<String>,
+    pending: VecDeque<Event>,
+    error: Option<Error>,
+    /// Only a single document is expected
+    single: bool,
+    /// Offset of the root node and whether it's finished
+    root_start: usize,
+    root_done: bool,
+    /// Number of flow collections open, and where the entry containing
+    /// the outermost one starts
+    flow_level: usize,
+    flow_entry: Option<Pos>,
+    done: bool,
+}
+
+impl<'a, R: BufRead> Parser<'a, R> {
+    /// Creates a parser of a stream, or of a single document if `single`
+    /// is set
+    pub fn new(reader: TokenReader<'a, R>, single: bool) -> Parser<'a, R> {
+        return Parser {
+            tokiter: TokenIter::new(reader),
+            stack: Vec::new(),
+            anchors: HashSet::new(),
+            pending: VecDeque::new(),
+            error: None,
+            single: single,
+            root_start: 0,
+            root_done: false,
+            flow_level: 0,
+            flow_entry: None,
+            done: false,
         };
-        tokiter.next().unwrap();
-        let tok = tokiter.peek(0);
+    }
+
+    /// Token referenced by the last event
+    pub fn token(&self, index: usize) -> &Token<'a> {
+        return self.tokiter.get(index);
+    }
+
+    fn error(&self, tok: &Token, message: &str) -> Result<(), Error> {
+        return Err(Error::parse_error(&tok.start, message.to_string()));
+    }
+
+    /// Offset of the first token of the collection
+    fn start(&mut self) -> usize {
+        return self.tokiter.peek(0).start.offset;
+    }
+
+    /// Start of the line, where the next token is
+    fn entry_pos(&mut self) -> Pos {
+        let tok = self.tokiter.peek(0);
+        if !tok.start.line_start {
+            if let Some(ref pos) = self.tokiter.line_start {
+                if pos.line == tok.start.line {
+                    return pos.clone();
+                }
+            }
+        }
+        return tok.start.clone();
+    }
+
+    /// Consumes the opening bracket of the flow collection
+    fn start_flow(&mut self) {
+        if self.flow_level == 0 {
+            self.flow_entry = Some(self.entry_pos());
+        }
+        self.flow_level += 1;
+        self.tokiter.next();
+    }
+
+    fn maybe_anchor(&mut self) -> Option<usize> {
+        if self.tokiter.peek(0).kind != T::Anchor {
+            return None;
+        }
+        self.tokiter.next();
+        return Some(self.tokiter.index() - 1);
+    }
+
+    fn maybe_tag(&mut self) -> Option<usize> {
+        if self.tokiter.peek(0).kind != T::Tag {
+            return None;
+        }
+        self.tokiter.next();
+        return Some(self.tokiter.index() - 1);
+    }
+
+    fn alias(&mut self) -> Result<(), Error> {
+        let tok = self.tokiter.peek(0);
+        let name = &tok.value[1..];
+        if !self.anchors.contains(name) {
+            return Err(Error::parse_error(&tok.start,
+                format!("Unknown alias {:?}", name)));
+        }
+        self.tokiter.next();
+        self.pending.push_back(Event::Alias(self.tokiter.index() - 1));
+        return self.node_done();
+    }
+
+    fn null(&mut self, tag: Option<usize>, anchor: Option<usize>, pos: &Pos)
+        -> Result<(), Error>
+    {
+        self.pending.push_back(Event::Null(tag, anchor, pos.clone()));
+        return self.node_done();
+    }
+
+    /// Consumes the scalar token, `value` is found before that, so the
+    /// token is left intact if the value is invalid
+    fn scalar(&mut self, tag: Option<usize>, anchor: Option<usize>,
+        value: String)
+        -> Result<(), Error>
+    {
+        self.tokiter.next();
+        let index = self.tokiter.index() - 1;
+        self.pending.push_back(Event::Scalar(tag, anchor, value, index));
+        return self.node_done();
+    }
+
+    fn start_map(&mut self, tag: Option<usize>, anchor: Option<usize>,
+        frame: Frame)
+    {
+        let tok = self.tokiter.peek(0);
+        self.pending.push_back(Event::MapStart(tag, anchor,
+                                               tok.start.clone()));
+        self.stack.push(frame);
+    }
+
+    fn start_seq(&mut self, tag: Option<usize>, anchor: Option<usize>,
+        frame: Frame)
+    {
+        let tok = self.tokiter.peek(0);
+        self.pending.push_back(Event::SeqStart(tag, anchor,
+                                               tok.start.clone()));
+        self.stack.push(frame);
+    }
+
+    fn end_collection(&mut self) {
+        if self.stack.pop().map(|frame| frame.is_flow()).unwrap_or(false) {
+            self.flow_level -= 1;
+            if self.flow_level == 0 {
+                self.flow_entry = None;
+            }
+        }
+    }
+
+    fn end_map(&mut self) -> Result<(), Error> {
+        self.end_collection();
+        self.pending.push_back(Event::MapEnd(self.tokiter.last_end()));
+        return self.node_done();
+    }
+
+    fn end_seq(&mut self) -> Result<(), Error> {
+        self.end_collection();
+        self.pending.push_back(Event::SeqEnd(self.tokiter.last_end()));
+        return self.node_done();
+    }
+
+    /// Defines anchor and checks unindent after the node is finished
+    fn node_done(&mut self) -> Result<(), Error> {
+        loop {
+            match self.stack.last() {
+                Some(&Frame::Unindent(_)) => {
+                    let end = self.tokiter.peek(0);
+                    if end.kind != T::Unindent {
+                        return Err(Error::parse_error(&end.start,
+                            format!("Expected unindent, got {:?}",
+                                    end.kind)));
+                    }
+                    self.tokiter.next();
+                }
+                Some(&Frame::Anchor(_, ref name)) => {
+                    self.anchors.insert(name.clone());
+                }
+                Some(&Frame::Document) => {
+                    self.root_done = true;
+                    return Ok(());
+                }
+                _ => return Ok(()),
+            }
+            self.stack.pop();
+        }
+    }
+
+    /// Returns value of the scalar token which is next to be consumed
+    fn scalar_value(&self, tok: &Token) -> Result<String, Error> {
         match tok.kind {
-            T::SequenceEntry if tok.start.indent == marker.start.indent => {
-                children.push(ImplicitNull(None, None, marker.end.clone()));
+            T::Literal | T::Folded => {
+                block_value(tok, self.tokiter.block_indent())
             }
-            T::DocumentEnd | T::Eof | T::Unindent => {
-                children.push(ImplicitNull(None, None, tok.start.clone()));
-                break;
+            _ => plain_value(tok),
+        }
+    }
+
+    /// Starts a block node
+    fn node(&mut self) -> Result<(), Error> {
+        let mut indent = None;
+        let mut tok = self.tokiter.peek(0);
+        if tok.kind == T::Indent {  // Indent in list is before tag
+            self.tokiter.next();
+            indent = Some(tok.start.offset);
+        }
+        let anchor = self.maybe_anchor();
+        let tag = self.maybe_tag();
+        tok = self.tokiter.peek(0);
+        if indent.is_none() && tok.kind == T::Indent {
+            // Otherwise indent is after tag
+            self.tokiter.next();
+            indent = Some(tok.start.offset);
+            tok = self.tokiter.peek(0);
+        }
+        if let Some(index) = anchor {
+            let tok = self.tokiter.get(index);
+            let frame = Frame::Anchor(tok.start.offset,
+                                      tok.value[1..].to_string());
+            self.stack.push(frame);
+        }
+        if let Some(offset) = indent {
+            self.stack.push(Frame::Unindent(offset));
+        }
+        match tok.kind {
+            T::PlainString | T::SingleString | T::DoubleString
+            | T::Literal | T::Folded => {
+                // Only one-line scalars are allowed to be mapping keys
+                let val = self.tokiter.peek(1);
+                if tok.start.line == tok.end.line &&
+                    val.kind == T::MappingValue &&
+                    val.start.line == tok.end.line
+                {
+                    let start = self.start();
+                    self.start_map(tag, anchor, Frame::MapKey(start));
+                    return Ok(());
+                }
+                let value = self.scalar_value(&tok)?;
+                return self.scalar(tag, anchor, value);
             }
+            T::Eof | T::Unindent | T::DocumentStart | T::DocumentEnd => {
+                return self.null(tag, anchor, &tok.start);
+            }
+            T::SequenceEntry => {
+                let start = self.start();
+                self.start_seq(tag, anchor, Frame::Seq(start));
+                return Ok(());
+            }
+            T::MappingKey => {
+                let start = self.start();
+                self.start_map(tag, anchor, Frame::MapKey(start));
+                return Ok(());
+            }
+            T::FlowSeqStart => {
+                let start = self.start();
+                self.start_seq(tag, anchor, Frame::FlowSeqItem(start));
+                self.start_flow();
+                return Ok(());
+            }
+            T::FlowMapStart => {
+                let start = self.start();
+                self.start_map(tag, anchor, Frame::FlowMapKey(start));
+                self.start_flow();
+                return Ok(());
+            }
+            T::Alias => return self.alias(),
             _ => {
-                let value = match parse_node(tokiter, aliases) {
-                    Ok(value) => value,
-                    Err(err) => return Err(err),
-                };
-                children.push(value);
+                return Err(Error::parse_error(&tok.start,
+                    format!("Expected scalar, sequence or mapping, got {:?}",
+                            tok.kind)));
             }
         }
     }
-    return Ok(Seq(tag, anchor, children,
-        &tokiter.tokens[begin..tokiter.index]));
-}
 
-fn parse_map<'x>(tokiter: &mut TokenIter<'x>, aliases: &mut Aliases<'x>,
-    tag: Option<&'x str>, anchor: Option<&'x str>)
-    -> Result<Node<'x>, Error>
-{
-    let begin = tokiter.index;
-    let mut children = BTreeMap::new();
-    loop {
-        // TODO(tailhook) implement complex keys
-        // TODO(tailhook) implement aliases and anchors
-        let ktoken = tokiter.peek(0);
-        let key = match ktoken.kind {
-            T::Eof => break,
-            T::DocumentEnd => break,
-            T::Unindent => break,
-            T::PlainString | T::SingleString | T::DoubleString
-            => Scalar(None, None, plain_value(ktoken)?, ktoken),
-            _ => return Err(Error::parse_error(&ktoken.start,
-                format!("Expected mapping key or unindent, got {:?}",
-                        ktoken.kind))),
+    /// Starts a flow node
+    fn flow_node(&mut self) -> Result<(), Error> {
+        let anchor = self.maybe_anchor();
+        let tag = self.maybe_tag();
+        if let Some(index) = anchor {
+            let tok = self.tokiter.get(index);
+            let frame = Frame::Anchor(tok.start.offset,
+                                      tok.value[1..].to_string());
+            self.stack.push(frame);
+        }
+        let tok = self.tokiter.peek(0);
+        match tok.kind {
+            T::PlainString | T::SingleString | T::DoubleString => {
+                let value = plain_value(&tok)?;
+                return self.scalar(tag, anchor, value);
+            }
+            T::FlowSeqStart => {
+                let start = self.start();
+                self.start_seq(tag, anchor, Frame::FlowSeqItem(start));
+                self.start_flow();
+                return Ok(());
+            }
+            T::FlowMapStart => {
+                let start = self.start();
+                self.start_map(tag, anchor, Frame::FlowMapKey(start));
+                self.start_flow();
+                return Ok(());
+            }
+            T::Alias => return self.alias(),
+            T::FlowEntry | T::FlowMapEnd | T::FlowSeqEnd | T::MappingValue
+            if tag.is_some() || anchor.is_some() => {
+                // Node with only tag or anchor, like in `{a: !tag , b: &x }`
+                return self.null(tag, anchor, &tok.start);
+            }
+            _ => return self.error(&tok,
+                "Expected plain string, sequence or mapping"),
+        }
+    }
+
+    /// Starts the next document, or finishes if there are no more
+    fn document(&mut self) -> Result<(), Error> {
+        if self.tokiter.peek(0).kind == T::Eof && !self.single {
+            self.done = true;
+            return Ok(());
+        }
+        self.anchors.clear();
+        // Directives and the `---` marker
+        let mut directives = Vec::new();
+        loop {
+            match self.tokiter.peek(0).kind {
+                T::Directive => {
+                    self.tokiter.next();
+                    directives.push(self.tokiter.index() - 1);
+                }
+                T::DocumentStart => {
+                    self.tokiter.next();
+                    break;
+                }
+                _ => break,
+            }
+        }
+        self.pending.push_back(Event::DocumentStart(directives));
+        self.stack.push(Frame::Root);
+        self.root_done = false;
+        return Ok(());
+    }
+
+    fn root(&mut self) -> Result<(), Error> {
+        self.root_start = self.tokiter.peek(0).start.offset;
+        self.replace_frame(Frame::Document);
+        return self.node();
+    }
+
+    /// Reads tokens until at least one event is produced
+    ///
+    /// Tokenizer error is returned instead of the parser error it causes
+    fn step(&mut self) -> Result<(), Error> {
+        if self.tokiter.peek(0).kind == T::Error {
+            if let Some(err) = self.tokiter.take_error() {
+                return Err(err);
+            }
+        }
+        self.tokiter.touched = false;
+        let result = self.step_frame();
+        if let Err(err) = result {
+            if self.tokiter.touched {
+                let tok = self.tokiter.tokens.back().unwrap().clone();
+                let before = err.pos().map(|pos| {
+                    pos.start() < (tok.start.line, tok.start.line_offset)
+                }).unwrap_or(false);
+                if !before {
+                    if let Some(err) = self.tokiter.take_error() {
+                        return Err(err);
+                    }
+                }
+            }
+            return Err(err);
+        }
+        return Ok(());
+    }
+
+    fn step_frame(&mut self) -> Result<(), Error> {
+        let frame = match self.stack.last() {
+            None => return self.document(),
+            Some(frame) => frame.clone(),
         };
-        tokiter.next().unwrap();
-        let delim = tokiter.peek(0);
+        match frame {
+            Frame::Root => self.root(),
+            Frame::Document => self.document_end(),
+            Frame::Seq(start) => self.seq_item(start),
+            Frame::MapKey(start) => self.map_key(start),
+            Frame::MapExplicitKey(start) => self.map_explicit_key(start),
+            Frame::MapValue(start) => self.map_value(start),
+            Frame::FlowSeqItem(start) => self.flow_seq_item(start),
+            Frame::FlowSeqNext(start) => self.flow_seq_next(start),
+            Frame::FlowMapKey(start) => self.flow_map_key(start),
+            Frame::FlowMapColon(start) => self.flow_map_colon(start),
+            Frame::FlowMapNext(start) => self.flow_map_next(start),
+            // Removed by `node_done` as soon as the node is finished
+            Frame::Unindent(_) | Frame::Anchor(..) => unreachable!(),
+        }
+    }
+
+    fn replace_frame(&mut self, frame: Frame) {
+        *self.stack.last_mut().unwrap() = frame;
+    }
+
+    fn document_end(&mut self) -> Result<(), Error> {
+        let tok = self.tokiter.peek(0);
+        match tok.kind {
+            T::Eof => {}
+            T::DocumentStart if !self.single => {}
+            T::DocumentEnd => { self.tokiter.next(); }
+            _ => {
+                return Err(Error::parse_error(&tok.start,
+                    format!("Expected document end, got {:?}", tok.kind)));
+            }
+        }
+        self.stack.pop();
+        self.pending.push_back(Event::DocumentEnd);
+        if self.single {
+            // Anything after the end of the document is ignored
+            self.done = true;
+        }
+        return Ok(());
+    }
+
+    fn seq_item(&mut self, _start: usize) -> Result<(), Error> {
+        let marker = self.tokiter.peek(0);
+        if marker.kind != T::SequenceEntry {
+            return self.end_seq();
+        }
+        self.tokiter.next();
+        let tok = self.tokiter.peek(0);
+        match tok.kind {
+            T::SequenceEntry if tok.start.indent == marker.start.indent => {
+                return self.null(None, None, &marker.end);
+            }
+            T::DocumentStart | T::DocumentEnd | T::Eof | T::Unindent => {
+                self.null(None, None, &tok.start)?;
+                return self.end_seq();
+            }
+            _ => return self.node(),
+        }
+    }
+
+    fn map_key(&mut self, start: usize) -> Result<(), Error> {
+        let ktoken = self.tokiter.peek(0);
+        match ktoken.kind {
+            T::Eof | T::DocumentStart | T::DocumentEnd | T::Unindent => {
+                return self.end_map();
+            }
+            T::PlainString | T::SingleString | T::DoubleString => {
+                let value = plain_value(&ktoken)?;
+                self.replace_frame(Frame::MapValue(start));
+                return self.scalar(None, None, value);
+            }
+            T::MappingKey => {
+                self.tokiter.next();
+                self.replace_frame(Frame::MapExplicitKey(start));
+                match self.tokiter.peek(0).kind {
+                    T::MappingValue | T::MappingKey | T::Eof | T::Unindent
+                    | T::DocumentStart | T::DocumentEnd
+                    => return self.null(None, None, &ktoken.end),
+                    _ => return self.node(),
+                }
+            }
+            _ => {
+                return Err(Error::parse_error(&ktoken.start,
+                    format!("Expected mapping key or unindent, got {:?}",
+                            ktoken.kind)));
+            }
+        }
+    }
+
+    fn map_explicit_key(&mut self, start: usize) -> Result<(), Error> {
+        let tok = self.tokiter.peek(0);
+        if tok.kind != T::MappingValue {
+            // Explicit key without a value
+            self.replace_frame(Frame::MapKey(start));
+            return self.null(None, None, &tok.start);
+        }
+        return self.map_value(start);
+    }
+
+    fn map_value(&mut self, start: usize) -> Result<(), Error> {
+        let delim = self.tokiter.peek(0);
         match delim.kind {
             T::MappingValue => {}
-            T::Eof => return Err(Error::parse_error(&delim.start,
-                "Unexpected end of file, expected mapping value".to_string())),
-            _ => return Err(Error::parse_error(&delim.start,
-                "Expected colon `:` which denotes mapping value".to_string())),
-        }
-        tokiter.next().unwrap();
-        let tok = tokiter.peek(0);
-        let tok_ahead = tokiter.peek(1);
+            T::Eof => return self.error(&delim,
+                "Unexpected end of file, expected mapping value"),
+            _ => return self.error(&delim,
+                "Expected colon `:` which denotes mapping value"),
+        }
+        self.tokiter.next();
+        self.replace_frame(Frame::MapKey(start));
+        let tok = self.tokiter.peek(0);
+        let tok_ahead = self.tokiter.peek(1);
         match tok.kind {
             T::SequenceEntry if tok.start.line > delim.end.line => {
                 // Allow sequences on the same indentation level as a key in
                 // mapping
-                let canchor = maybe_parse_anchor(tokiter);
-                let tag = maybe_parse_tag(tokiter);
-                let value = match parse_list(tokiter, aliases, tag, canchor) {
-                    Ok(value) => value,
-                    Err(err) => return Err(err),
-                };
-                if children.insert(key, value).is_some() {
-                    return Err(Error::parse_error(&ktoken.start,
-                        "Duplicate key".to_string()));
-                }
+                let start = self.start();
+                self.start_seq(None, None, Frame::Seq(start));
+                return Ok(());
             }
             T::Eof | T::Unindent => {
-                children.insert(key, ImplicitNull(
-                    None, None, tok.start.clone()));
-                break;
+                self.null(None, None, &tok.start)?;
+                return self.end_map();
             }
             T::Tag if tok_ahead.start.line > tok.end.line &&
                 tok_ahead.kind != T::Indent =>
             {
-                let value = ImplicitNull(maybe_parse_tag(tokiter), None, delim.end.clone());
-                if children.insert(key, value).is_some() {
-                    return Err(Error::parse_error(&ktoken.start,
-                        "Duplicate key".to_string()));
-                }
-                continue;
+                let tag = self.maybe_tag();
+                return self.null(tag, None, &delim.end);
             }
             _ if tok.start.line == delim.end.line ||
                  tok.start.indent > delim.end.indent => {
-                let value = match parse_node(tokiter, aliases) {
-                    Ok(value) => value,
-                    Err(err) => return Err(err),
-                };
-                if children.insert(key, value).is_some() {
-                    return Err(Error::parse_error(&ktoken.start,
-                        "Duplicate key".to_string()));
-                }
-            }
-            _ => {
-                let value = ImplicitNull(None, None, delim.end.clone());
-                if children.insert(key, value).is_some() {
-                    return Err(Error::parse_error(&ktoken.start,
-                        "Duplicate key".to_string()));
-                }
-                continue;
+                return self.node();
             }
+            _ => return self.null(None, None, &delim.end),
         }
     }
-    return Ok(Map(tag, anchor, children,
-        &tokiter.tokens[begin..tokiter.index]));
-}
 
-fn parse_flow_list<'x>(tokiter: &mut TokenIter<'x>, aliases: &mut Aliases<'x>,
-    tag: Option<&'x str>, anchor: Option<&'x str>)
-    -> Result<Node<'x>, Error>
-{
-    let begin = tokiter.index;
-    let mut children = Vec::new();
-    tokiter.next();
-    loop {
-        let tok = tokiter.peek(0);
+    fn flow_seq_item(&mut self, start: usize) -> Result<(), Error> {
+        if self.tokiter.peek(0).kind == T::FlowSeqEnd {
+            self.tokiter.next();
+            return self.end_seq();
+        }
+        self.replace_frame(Frame::FlowSeqNext(start));
+        return self.flow_node();
+    }
+
+    fn flow_seq_next(&mut self, start: usize) -> Result<(), Error> {
+        let tok = self.tokiter.peek(0);
         match tok.kind {
             T::FlowSeqEnd => {
-                tokiter.next();
-                break;
+                self.tokiter.next();
+                return self.end_seq();
             }
-            _ => {
-                let value = match parse_flow_node(tokiter, aliases) {
-                    Ok(value) => value,
-                    Err(err) => return Err(err),
-                };
-                children.push(value);
+            T::FlowEntry => {
+                self.tokiter.next();
+                self.replace_frame(Frame::FlowSeqItem(start));
+                return Ok(());
             }
-        }
-        let tok = tokiter.next().unwrap();
-        match tok.kind {
-            T::FlowSeqEnd => break,
-            T::FlowEntry => continue,
-            _ => return Err(Error::parse_error(&tok.start,
-                "Expected comma `,` or colon `:`".to_string())),
+            _ => return self.error(&tok, "Expected comma `,` or colon `:`"),
         }
     }
-    return Ok(Seq(tag, anchor, children,
-        &tokiter.tokens[begin..tokiter.index]));
-}
 
-fn parse_flow_map<'x>(tokiter: &mut TokenIter<'x>, aliases: &mut Aliases<'x>,
-    tag: Option<&'x str>, anchor: Option<&'x str>)
-    -> Result<Node<'x>, Error>
-{
-    let begin = tokiter.index;
-    let mut children = BTreeMap::new();
-    tokiter.next();
-    loop {
-        // TODO(tailhook) implement complex keys
-        // TODO(tailhook) implement aliases and anchors
-        let ktoken = tokiter.next().unwrap();
-        let key = match ktoken.kind {
-            T::FlowMapEnd => break,
+    fn flow_map_key(&mut self, start: usize) -> Result<(), Error> {
+        let ktoken = self.tokiter.peek(0);
+        match ktoken.kind {
+            T::FlowMapEnd => {
+                self.tokiter.next();
+                return self.end_map();
+            }
+            T::MappingKey => {
+                self.tokiter.next();
+                self.replace_frame(Frame::FlowMapColon(start));
+                match self.tokiter.peek(0).kind {
+                    T::MappingValue | T::FlowEntry | T::FlowMapEnd
+                    => return self.null(None, None, &ktoken.end),
+                    _ => return self.flow_node(),
+                }
+            }
             T::PlainString | T::SingleString | T::DoubleString
-            => Scalar(None, None, plain_value(ktoken)?, ktoken),
-            _ => return Err(Error::parse_error(&ktoken.start,
-                "Expected next mapping key or or closing bracket `}`"
-                .to_string())),
-        };
+            | T::FlowSeqStart | T::FlowMapStart
+            | T::Anchor | T::Tag | T::Alias
+            => {
+                self.replace_frame(Frame::FlowMapColon(start));
+                return self.flow_node();
+            }
+            _ => return self.error(&ktoken,
+                "Expected next mapping key or or closing bracket `}`"),
+        }
+    }
 
-        let tok = tokiter.next().unwrap();
+    fn flow_map_colon(&mut self, start: usize) -> Result<(), Error> {
+        let tok = self.tokiter.peek(0);
         match tok.kind {
             T::FlowMapEnd => {
                 // Value is null
-                if children.insert(key,
-                    ImplicitNull(None, None, tok.start.clone())).is_some() {
-                    return Err(Error::parse_error(&ktoken.start,
-                        "Duplicate key".to_string()));
-                }
-                break;
+                self.tokiter.next();
+                self.null(None, None, &tok.start)?;
+                return self.end_map();
             }
             T::FlowEntry => {
                 // Value is null
-                if children.insert(key,
-                    ImplicitNull(None, None, tok.start.clone())).is_some() {
-                    return Err(Error::parse_error(&ktoken.start,
-                        "Duplicate key".to_string()));
-                }
-                continue;
+                self.tokiter.next();
+                self.replace_frame(Frame::FlowMapKey(start));
+                return self.null(None, None, &tok.start);
             }
-            T::MappingValue => {}
-            _ => return Err(Error::parse_error(&tok.start,
-                "Expected comma `,`, colon `:` or closing bracket `}`"
-                .to_string())),
+            T::MappingValue => { self.tokiter.next(); }
+            _ => return self.error(&tok,
+                "Expected comma `,`, colon `:` or closing bracket `}`"),
         }
-        let value = match parse_flow_node(tokiter, aliases) {
-            Ok(value) => value,
-            Err(err) => return Err(err),
-        };
-        if children.insert(key, value).is_some() {
-            return Err(Error::parse_error(&ktoken.start,
-                "Duplicate key".to_string()));
+        self.replace_frame(Frame::FlowMapNext(start));
+        let vtoken = self.tokiter.peek(0);
+        match vtoken.kind {
+            T::FlowEntry | T::FlowMapEnd => {
+                return self.null(None, None, &vtoken.start);
+            }
+            _ => return self.flow_node(),
         }
-        let tok = tokiter.next().unwrap();
+    }
+
+    fn flow_map_next(&mut self, start: usize) -> Result<(), Error> {
+        let tok = self.tokiter.peek(0);
         match tok.kind {
-            T::FlowMapEnd => break,
-            T::FlowEntry => continue,
-            _ => return Err(Error::parse_error(&tok.start,
-                "Expected comma `,` or closing bracket `}`".to_string())),
+            T::FlowMapEnd => {
+                self.tokiter.next();
+                return self.end_map();
+            }
+            T::FlowEntry => {
+                self.tokiter.next();
+                self.replace_frame(Frame::FlowMapKey(start));
+                return Ok(());
+            }
+            _ => return self.error(&tok,
+                "Expected comma `,` or closing bracket `}`"),
         }
     }
-    return Ok(Map(tag, anchor, children,
-        &tokiter.tokens[begin..tokiter.index]));
-}
 
-fn parse_flow_node<'x>(tokiter: &mut TokenIter<'x>, aliases: &mut Aliases<'x>)
-    -> Result<Node<'x>, Error>
-{
-    let res = _parse_flow_node(tokiter, aliases)?;
-    match &res {
-        &Map(_, Some(anchor), _, _)
-        => { aliases.insert(anchor, res.clone()); }
-        &Map(_, None, _, _) => {}
-        &Seq(_, Some(anchor), _, _)
-        => { aliases.insert(anchor, res.clone()); }
-        &Seq(_, None, _, _) => {}
-        &Scalar(_, Some(anchor), _, _)
-        => { aliases.insert(anchor, res.clone()); }
-        &Scalar(_, None, _, _) => {}
-        &ImplicitNull(_, Some(anchor), _)
-        => { aliases.insert(anchor, res.clone()); }
-        &ImplicitNull(_, None, _) => {}
-        &Alias(_, _, _) => {}
-    }
-    Ok(res)
-}
-
-fn _parse_flow_node<'x>(tokiter: &mut TokenIter<'x>, aliases: &mut Aliases<'x>)
-    -> Result<Node<'x>, Error>
-{
-    let anchor = maybe_parse_anchor(tokiter);
-    let tag = maybe_parse_tag(tokiter);
-    let tok = tokiter.peek(0);
-    match tok.kind {
-        T::PlainString | T::SingleString | T::DoubleString => {
-            tokiter.next();
-            return Ok(Scalar(tag, anchor, plain_value(tok)?, tok));
-        }
-        T::FlowSeqStart => {
-            return parse_flow_list(tokiter, aliases, tag, anchor);
-        }
-        T::FlowMapStart => {
-            return parse_flow_map(tokiter, aliases, tag, anchor);
-        }
-        T::Alias => {
-            tokiter.next();
-            match aliases.get(&tok.value[1..]) {
-                Some(x) => return Ok(x.clone()),
-                None => {
-                    return Err(Error::parse_error(&tok.start,
-                        format!("Unknown alias {:?}", &tok.value[1..])));
+    /// Skips the entry containing the error returned by the iterator
+    ///
+    /// The entry is the line where the error is, along with the following
+    /// lines indented deeper. For errors inside a flow collection the entry
+    /// where the outermost bracket is opened is skipped. Unfinished nodes
+    /// started in the entry are dropped and parsing continues after it.
+    /// Returns false if there is nothing to skip.
+    pub fn recover(&mut self) -> bool {
+        if self.tokiter.fatal && self.tokiter.error.is_none() {
+            // Reading error is returned already
+            return false;
+        }
+        let entry = match self.flow_entry.take() {
+            Some(pos) => pos,
+            None => self.entry_pos(),
+        };
+        let mut changed = false;
+        let mut collections = 0;
+        let mut brackets = 0;
+        let mut unindents = 0;
+        loop {
+            match self.stack.last() {
+                Some(frame) if frame.is_flow() => brackets += 1,
+                Some(frame) if frame.offset() > entry.offset => {}
+                _ => break,
+            }
+            match self.stack.pop() {
+                Some(Frame::Unindent(_)) => unindents += 1,
+                Some(Frame::Anchor(..)) => {}
+                _ => collections += 1,
+            }
+            changed = true;
+        }
+        // The node is left unfinished, so its frame isn't needed
+        loop {
+            match self.stack.last() {
+                Some(&Frame::Unindent(_)) => unindents += 1,
+                Some(&Frame::Anchor(..)) => {}
+                _ => break,
+            }
+            self.stack.pop();
+            changed = true;
+        }
+        let mut drop_root = false;
+        match self.stack.last().cloned() {
+            Some(Frame::MapValue(start))
+            | Some(Frame::MapExplicitKey(start)) => {
+                self.replace_frame(Frame::MapKey(start));
+                changed = true;
+            }
+            Some(Frame::Document)
+            if !self.root_done || self.root_start >= entry.offset => {
+                self.replace_frame(Frame::Root);
+                self.root_done = false;
+                drop_root = true;
+                changed = true;
+            }
+            _ => {}
+        }
+        loop {
+            let tok = self.tokiter.peek(0);
+            match tok.kind {
+                T::Eof => break,
+                // Error is not returned yet, it's reported after this one
+                T::Error if self.tokiter.error.is_some() => break,
+                T::Error => {}
+                _ if tok.start.line > entry.line && tok.start.line_start &&
+                     tok.start.indent <= entry.indent => break,
+                T::Indent => unindents += 1,
+                T::Unindent if unindents > 0 => unindents -= 1,
+                T::FlowSeqStart | T::FlowMapStart => brackets += 1,
+                T::FlowSeqEnd | T::FlowMapEnd if brackets > 0 => {
+                    brackets -= 1;
                 }
+                _ => {}
             }
+            self.tokiter.next();
+            changed = true;
         }
-        _ => return Err(Error::parse_error(&tok.start,
-            "Expected plain string, sequence or mapping".to_string())),
-    };
+        if brackets > 0 {
+            self.tokiter.restart_block();
+        }
+        // Unindents of the skipped lines
+        for _ in 0..unindents {
+            if self.tokiter.peek(0).kind != T::Unindent {
+                break;
+            }
+            self.tokiter.next();
+        }
+        if !changed {
+            return false;
+        }
+        self.flow_level = 0;
+        self.pending.push_back(Event::Recover(entry, collections,
+                                              drop_root));
+        self.done = false;
+        return true;
+    }
 }
 
-fn maybe_parse_anchor<'x>(tokiter: &mut TokenIter<'x>) -> Option<&'x str> {
-    let tok = tokiter.peek(0);
-    let mut anchor = None;
-    match tok.kind {
-        T::Anchor => {
-            anchor = Some(&tok.value[1..]);
-            tokiter.next();
+impl<'a, R: BufRead> Iterator for Parser<'a, R> {
+    type Item = Result<Event, Error>;
+    fn next(&mut self) -> Option<Result<Event, Error>> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(Ok(event));
+            }
+            if let Some(err) = self.error.take() {
+                return Some(Err(err));
+            }
+            if self.done {
+                return None;
+            }
+            self.tokiter.release();
+            if let Err(err) = self.step() {
+                self.error = Some(err);
+                self.done = true;
+            }
         }
-        _ => {}
     }
-    return anchor;
 }
 
-fn maybe_parse_tag<'x>(tokiter: &mut TokenIter<'x>) -> Option<&'x str> {
-    let tok = tokiter.peek(0);
-    let mut tag = None;
-    match tok.kind {
-        T::Tag => {
-            tag = Some(tok.value);
-            tokiter.next();
-        }
-        _ => {}
+/// Node which is not finished yet: tag, anchor, position of the first
+/// token and the items
+enum Partial<'a> {
+    /// Also has a key which has no value yet
+    Map(Option<Cow<'a, str>>, Option<Cow<'a, str>>, Pos,
+        Vec<(Node<'a>, Node<'a>)>, Option<Node<'a>>),
+    Seq(Option<Cow<'a, str>>, Option<Cow<'a, str>>, Pos, Vec<Node<'a>>),
+}
+
+/// Text of the token without the first character, like `&` of the anchor
+fn strip_sigil<'a>(tok: &Token<'a>) -> Cow<'a, str> {
+    match tok.value {
+        Cow::Borrowed(value) => Cow::Borrowed(&value[1..]),
+        Cow::Owned(ref value) => Cow::Owned(value[1..].to_string()),
     }
-    return tag;
 }
-fn parse_node<'x>(tokiter: &mut TokenIter<'x>, aliases: &mut Aliases<'x>)
-    -> Result<Node<'x>, Error>
-{
-    let res = _parse_node(tokiter, aliases)?;
-    match &res {
-        &Map(_, Some(anchor), _, _)
-        => { aliases.insert(anchor, res.clone()); }
-        &Map(_, None, _, _) => {}
-        &Seq(_, Some(anchor), _, _)
-        => { aliases.insert(anchor, res.clone()); }
-        &Seq(_, None, _, _) => {}
-        &Scalar(_, Some(anchor), _, _)
-        => { aliases.insert(anchor, res.clone()); }
-        &Scalar(_, None, _, _) => {}
-        &ImplicitNull(_, Some(anchor), _)
-        => { aliases.insert(anchor, res.clone()); }
-        &ImplicitNull(_, None, _) => {}
-        &Alias(_, _, _) => {}
-    }
-    Ok(res)
-}
-
-fn _parse_node<'x>(tokiter: &mut TokenIter<'x>, aliases: &mut Aliases<'x>)
-    -> Result<Node<'x>, Error>
-{
-    let mut indent = false;
-    let mut tok = tokiter.peek(0);
-
-    if tok.kind == T::Indent {  // Indent in list is before tag
-        tokiter.next();
-        indent = true;
-    }
-    let anchor = maybe_parse_anchor(tokiter);
-    let mut tag = maybe_parse_tag(tokiter);
-    tok = tokiter.peek(0);
-    if !indent && tok.kind == T::Indent {  // Otherwise indent is after tag
-        tokiter.next();
-        tok = tokiter.peek(0);
-        indent = true;
-    }
-    let result = match tok.kind {
-        T::PlainString | T::SingleString | T::DoubleString
-        | T::Literal | T::Folded => {
-            if tok.start.line == tok.end.line {
-                // Only one-line scalars are allowed to be mapping keys
-                let val = tokiter.peek(1);
-                if val.kind == T::MappingValue &&
-                        val.start.line == tok.end.line
-                {
-                    parse_map(tokiter, aliases, tag, anchor)
-                } else {
-                    tokiter.next();
-                    Ok(Scalar(tag, anchor, plain_value(tok)?, tok))
+
+/// Builds the document from the events as they are read
+///
+/// Data of the tokens is copied into the nodes (it's borrowed if the
+/// tokens borrow the input), so the parser doesn't need to keep tokens
+/// after the event is added.
+struct Builder<'a> {
+    aliases: Aliases<'a>,
+    directives: Vec<Directive<'a>>,
+    stack: Vec<Partial<'a>>,
+    root: Option<Node<'a>>,
+}
+
+impl<'a> Builder<'a> {
+    fn new() -> Builder<'a> {
+        return Builder {
+            aliases: Aliases::new(),
+            directives: Vec::new(),
+            stack: Vec::new(),
+            root: None,
+        };
+    }
+
+    /// Adds the event, the tokens referenced by it are taken from `parser`
+    fn add<R: BufRead>(&mut self, event: Event, parser: &Parser<'a, R>) {
+        let tag = |index: Option<usize>| {
+            index.map(|i| parser.token(i).value.clone())
+        };
+        let anchor = |index: Option<usize>| {
+            index.map(|i| strip_sigil(parser.token(i)))
+        };
+        let node = match event {
+            Event::DocumentStart(indices) => {
+                self.directives = indices.into_iter()
+                    .map(|i| Directive(parser.token(i).clone()))
+                    .collect();
+                return;
+            }
+            Event::DocumentEnd => return,
+            Event::MapStart(t, a, pos) => {
+                self.stack.push(Partial::Map(tag(t), anchor(a), pos,
+                                             Vec::new(), None));
+                return;
+            }
+            Event::SeqStart(t, a, pos) => {
+                self.stack.push(Partial::Seq(tag(t), anchor(a), pos,
+                                             Vec::new()));
+                return;
+            }
+            Event::MapEnd(end) => match self.stack.pop() {
+                Some(Partial::Map(t, a, start, pairs, _)) => {
+                    Map(t, a, pairs, Span::new(start, end))
                 }
-            } else {
-                tokiter.next();
-                Ok(Scalar(tag, anchor, plain_value(tok)?, tok))
+                _ => unreachable!(),
+            },
+            Event::SeqEnd(end) => match self.stack.pop() {
+                Some(Partial::Seq(t, a, start, items)) => {
+                    Seq(t, a, items, Span::new(start, end))
+                }
+                _ => unreachable!(),
+            },
+            Event::Scalar(t, a, value, index) => {
+                let tok = parser.token(index);
+                Scalar(tag(t), anchor(a), value, tok.kind,
+                       Span::new(tok.start.clone(), tok.end.clone()))
             }
+            Event::Null(t, a, pos) => ImplicitNull(tag(t), anchor(a), pos),
+            Event::Alias(index) => {
+                let tok = parser.token(index);
+                let name = strip_sigil(tok);
+                // Parser checks that the anchor is defined
+                let node = self.aliases.get(&name[..]).unwrap().clone();
+                Alias(name, Span::new(tok.start.clone(), tok.end.clone()),
+                      node)
+            }
+            Event::Recover(entry, collections, drop_root) => {
+                self.recover(entry, collections, drop_root);
+                return;
+            }
+        };
+        match node {
+            Map(_, Some(ref name), _, _) | Seq(_, Some(ref name), _, _)
+            | Scalar(_, Some(ref name), _, _, _)
+            | ImplicitNull(_, Some(ref name), _)
+            => {
+                self.aliases.insert(name.clone(), Rc::new(node.clone()));
+            }
+            _ => {}
         }
-        T::Eof | T::Unindent => {
-            Ok(ImplicitNull(tag, anchor, tok.start.clone()))
-        }
-        T::SequenceEntry => {
-            parse_list(tokiter, aliases, tag, anchor)
-        }
-        T::FlowSeqStart => {
-            parse_flow_list(tokiter, aliases, tag, anchor)
+        match self.stack.last_mut() {
+            Some(&mut Partial::Map(_, _, _, ref mut pairs, ref mut key)) => {
+                match key.take() {
+                    Some(key) => pairs.push((key, node)),
+                    None => *key = Some(node),
+                }
+            }
+            Some(&mut Partial::Seq(_, _, _, ref mut items)) => {
+                items.push(node);
+            }
+            None => self.root = Some(node),
         }
-        T::FlowMapStart => {
-            parse_flow_map(tokiter, aliases, tag, anchor)
+    }
+
+    /// Drops the nodes of the entry skipped after an error
+    fn recover(&mut self, entry: Pos, collections: usize, drop_root: bool) {
+        for _ in 0..collections {
+            self.stack.pop();
         }
-        T::Alias => {
-            tokiter.next();
-            match aliases.get(&tok.value[1..]) {
-                Some(x) => Ok(x.clone()),
-                None => {
-                    return Err(Error::parse_error(&tok.start,
-                        format!("Unknown alias {:?}", &tok.value[1..])));
+        let skipped = |node: &Node| {
+            node.span().start.offset >= entry.offset
+        };
+        match self.stack.last_mut() {
+            Some(&mut Partial::Map(_, _, _, ref mut pairs, ref mut key)) => {
+                pairs.retain(|&(ref k, _)| !skipped(k));
+                if let Some(k) = key.take() {
+                    if !skipped(&k) {
+                        let value = ImplicitNull(None, None, entry.clone());
+                        pairs.push((k, value));
+                    }
                 }
             }
+            Some(&mut Partial::Seq(_, _, _, ref mut items)) => {
+                items.retain(|item| !skipped(item));
+            }
+            None => {}
         }
-        _ => Err(Error::parse_error(&tok.start,
-            format!("Expected scalar, sequence or mapping, got {:?}",
-                    tok.kind))),
-    };
-    if result.is_ok() && indent {
-        let end = tokiter.peek(0);
-        if end.kind != T::Unindent {
-            return Err(Error::parse_error(&end.start,
-                format!("Expected unindent, got {:?}", end.kind)));
-        } else {
-            tokiter.next();
+        if drop_root {
+            self.root = None;
         }
     }
-    return result;
+
+    /// Returns the document, when all of its events are added
+    ///
+    /// The root is a null at `end` if the parser hasn't produced one
+    fn finish(self, end: Pos) -> Document<'a> {
+        return Document {
+            directives: self.directives,
+            root: self.root.unwrap_or_else(|| ImplicitNull(None, None, end)),
+        };
+    }
 }
 
-fn parse_root<'x>(tokiter: &mut TokenIter<'x>, aliases: &mut Aliases<'x>)
-    -> Result<(Vec<Directive<'x>>, Node<'x>), Error>
+/// Parses documents read by `reader` and calls `process` for each one
+///
+/// Syntax errors are added to `err` if it's specified, otherwise the first
+/// one is returned
+fn read_documents<'a, R, F>(reader: TokenReader<'a, R>,
+    err: Option<&ErrorCollector>, single: bool, mut process: F)
+    -> Result<(), Error>
+    where R: BufRead, F: FnMut(Document<'a>)
 {
-    let mut directives = Vec::new();
+    let mut parser = Parser::new(reader, single);
+    let mut builder = Builder::new();
     loop {
-        let tok = tokiter.peek(0);
-        match tok.kind {
-            T::Eof => return Ok((directives,
-                                 ImplicitNull(None, None, tok.start.clone()))),
-            T::Directive => directives.push(Directive(tok)),
-            T::DocumentStart => {
-                tokiter.next();
-                break;
+        match parser.next() {
+            Some(Ok(Event::DocumentEnd)) => {
+                let builder = mem::replace(&mut builder, Builder::new());
+                process(builder.finish(parser.tokiter.last_end()));
             }
-            _ => break,  // Start reading node
+            Some(Ok(event)) => builder.add(event, &parser),
+            Some(Err(e)) => match err {
+                Some(err) if parser.recover() => err.add_error(e),
+                _ => return Err(e),
+            },
+            None => return Ok(()),
         }
-        tokiter.next();
     }
-    let res =  match parse_node(tokiter, aliases) {
-        Ok(node) => node,
-        Err(e) => return Err(e),
-    };
-    loop {
-        let tok = match tokiter.next() {
-            Some(tok) => tok,
-            None => break,
-        };
-        match tok.kind {
-            T::DocumentEnd => break,
-            _ => {
-                return Err(Error::parse_error(&tok.start,
-                    format!("Expected document end, got {:?}", tok.kind)));
-            }
-        }
-    }
-    return Ok((directives, res));
 }
 
-
-pub fn parse_tokens<'x>(tokens: &'x Vec<Token<'x>>)
-    -> Result<Document<'x>, Error>
+/// Parses a single document read by `reader`
+fn read_document<'a, R, T, F>(reader: TokenReader<'a, R>,
+    err: Option<&ErrorCollector>, process: F)
+    -> Result<T, Error>
+    where R: BufRead, F: FnOnce(Document<'a>) -> T
 {
-    let mut aliases = BTreeMap::new();
-    let mut iter = TokenIter::new(tokens);
-    let (directives, root) = match parse_root(&mut iter, &mut aliases) {
-        Ok((directives, root)) => (directives, root),
-        Err(e) => return Err(e),
-    };
-    return Ok(Document {
-        directives: directives,
-        root: root,
-        });
+    let mut process = Some(process);
+    let mut result = None;
+    read_documents(reader, err, true, |doc| {
+        result = process.take().map(|process| process(doc));
+    })?;
+    return Ok(result.expect("single document is parsed"));
 }
 
 /// Raw parser of the yaml to ast
@@ -745,10 +1673,86 @@ pub fn parse<T, F>(name: Rc<String>, data: &str, process: F)
     -> Result<T, Error>
     where F: FnOnce(Document) -> T
 {
-    let tokens = tokenize(name, data).map_err(Error::tokenizer_error)?;
-    let doc = match parse_tokens(&tokens) {
-        Ok(doc) => doc,
-        Err(e) => return Err(e),
-    };
-    return Ok(process(doc));
+    return read_document(TokenReader::from_text(name, data), None, process);
+}
+
+/// Raw parser of the yaml to ast, which recovers from syntax errors
+///
+/// Instead of stopping at the first error, every tokenizer and parser
+/// error is added to `err` and the erroneous entry (the line where the
+/// error is, along with the following lines indented deeper) is skipped.
+/// The `process` is called with the best-effort document, where skipped
+/// entries are missing. For errors inside a flow collection the entry
+/// where the outermost bracket is opened is skipped.
+///
+/// Errors are reported in the order of the source. The error is returned
+/// only if there is nothing left to skip.
+pub fn parse_recover<T, F>(name: Rc<String>, data: &str,
+    err: &ErrorCollector, process: F)
+    -> Result<T, Error>
+    where F: FnOnce(Document) -> T
+{
+    return read_document(TokenReader::from_text(name, data), Some(err),
+                         process);
+}
+
+/// Raw parser of a single document read from `reader`, which recovers
+/// from syntax errors
+///
+/// Works like `parse_recover`, but the input is read and tokenized
+/// incrementally. Reading errors are returned as `Error::OpenError`
+pub fn parse_reader_single_recover<R, T, F>(name: Rc<String>, reader: R,
+    err: &ErrorCollector, process: F)
+    -> Result<T, Error>
+    where R: BufRead, F: FnOnce(Document) -> T
+{
+    return read_document(TokenReader::new(name, reader), Some(err),
+                         process);
+}
+
+/// Raw parser of the yaml stream with multiple documents
+///
+/// Calls `process` for each document, in order
+pub fn parse_all<T, F>(name: Rc<String>, data: &str, mut process: F)
+    -> Result<Vec<T>, Error>
+    where F: FnMut(Document) -> T
+{
+    let mut result = Vec::new();
+    read_documents(TokenReader::from_text(name, data), None, false,
+        |doc| result.push(process(doc)))?;
+    return Ok(result);
+}
+
+/// Raw parser of the yaml stream read from `reader`
+///
+/// Works like `parse_all`, but the stream is read and tokenized
+/// incrementally. Tokens are dropped as soon as the node they belong to is
+/// built, so only the nodes of the current document are kept in memory
+/// besides the values returned by `process`. Reading errors are reported
+/// as `Error::OpenError`
+pub fn parse_reader<R, T, F>(name: Rc<String>, reader: R, mut process: F)
+    -> Result<Vec<T>, Error>
+    where R: BufRead, F: FnMut(Document) -> T
+{
+    let mut result = Vec::new();
+    read_documents(TokenReader::new(name, reader), None, false,
+        |doc| result.push(process(doc)))?;
+    return Ok(result);
+}
+
+/// Raw parser of the yaml stream read from `reader`, which recovers from
+/// syntax errors
+///
+/// Works like `parse_reader`, but syntax errors are added to `err` and
+/// erroneous entries are skipped, like in `parse_recover`. Only reading
+/// errors are returned.
+pub fn parse_reader_recover<R, T, F>(name: Rc<String>, reader: R,
+    err: &ErrorCollector, mut process: F)
+    -> Result<Vec<T>, Error>
+    where R: BufRead, F: FnMut(Document) -> T
+{
+    let mut result = Vec::new();
+    read_documents(TokenReader::new(name, reader), Some(err), false,
+        |doc| result.push(process(doc)))?;
+    return Ok(result);
 }
@@ -1,18 +1,44 @@
 use std::rc::Rc;
-use std::io::Read;
+use std::io::{Read, BufRead, BufReader};
 use std::fs::File;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use rustc_serialize::{Decodable};
 
 use super::ast;
+use super::encoding::DecodeReader;
 pub use super::errors::{Error, ErrorList};
 use super::errors::ErrorCollector;
-use super::parser::parse;
+use super::parser::{parse_recover, parse_reader_recover};
+use super::parser::parse_reader_single_recover;
 use super::decode::YamlDecoder;
 use super::validate::Validator;
-use {Options};
+use options::{Options, Dependency, collect_dependencies};
 
 
+fn decode_stream<T, R, F>(filename: Rc<String>, reader: R,
+    validator: &Validator, options: &Options, err: &ErrorCollector,
+    mut process: F)
+    -> Result<(), ErrorList>
+    where T: Decodable, R: BufRead, F: FnMut(T)
+{
+    // Documents are decoded and passed to `process` as soon as they are
+    // read, so that neither the text nor the values of the whole stream
+    // are kept in memory. Documents after an error are only checked.
+    parse_reader_recover(filename, reader, err, |doc| {
+            let ast = ast::process(options, doc, err);
+            let ast = validator.validate(ast, err);
+            match Decodable::decode(&mut YamlDecoder::new(ast, err)) {
+                Ok(value) => {
+                    if !err.has_errors() {
+                        process(value);
+                    }
+                }
+                Err(e) => err.add_error(e),
+            }
+        }).map_err(|e| err.into_fatal(e))?;
+    return err.into_result(());
+}
+
 /// Parse configuration from a file
 pub fn parse_config<T: Decodable, P: AsRef<Path>>(
     filename: P, validator: &Validator, options: &Options)
@@ -20,14 +46,11 @@ pub fn parse_config<T: Decodable, P: AsRef<Path>>(
 {
     let filename = filename.as_ref();
     let err = ErrorCollector::new();
-    let mut file = File::open(filename).map_err(
-        |e| err.into_fatal(Error::OpenError(filename.to_path_buf(), e)))?;
-    let mut body = String::new();
-    file.read_to_string(&mut body).map_err(
+    let file = File::open(filename).and_then(DecodeReader::new).map_err(
         |e| err.into_fatal(Error::OpenError(filename.to_path_buf(), e)))?;
     let filename = Rc::new(format!("{}", filename.display()));
-    let ast = parse(filename, &body,
-        |doc| { ast::process(options, doc, &err) }
+    let ast = parse_reader_single_recover(filename, BufReader::new(file),
+        &err, |doc| { ast::process(options, doc, &err) }
         ).map_err(|e| err.into_fatal(e))?;
     let ast = validator.validate(ast, &err);
     let res = Decodable::decode(&mut YamlDecoder::new(ast, &err))
@@ -35,13 +58,32 @@ pub fn parse_config<T: Decodable, P: AsRef<Path>>(
     return err.into_result(res);
 }
 
+/// Parse configuration from a file and return the files it was read from
+///
+/// The `deps` are filled with the configuration file itself and every
+/// file (or directory for `!*IncludeSeq` and `!*IncludeMap`) read by the
+/// include handler, even if parsing fails. Useful for reloading
+/// configuration when files change.
+pub fn parse_config_with_deps<T: Decodable, P: AsRef<Path>>(
+    filename: P, validator: &Validator, options: &Options,
+    deps: &mut Vec<Dependency>)
+    -> Result<T, ErrorList>
+{
+    let (result, files) = collect_dependencies(options, || {
+        options.add_dependency(filename.as_ref());
+        parse_config(filename, validator, options)
+    });
+    deps.extend(files);
+    return result;
+}
+
 /// Parse configuration from a string
 pub fn parse_string<T: Decodable>(filename: &str, data: &str,
     validator: &Validator, options: &Options)
     -> Result<T, ErrorList>
 {
     let err = ErrorCollector::new();
-    let ast = parse(Rc::new(filename.to_string()), data,
+    let ast = parse_recover(Rc::new(filename.to_string()), data, &err,
             |doc| { ast::process(options, doc, &err) }
         ).map_err(|e| err.into_fatal(e))?;
     let ast = validator.validate(ast, &err);
@@ -50,3 +92,52 @@ pub fn parse_string<T: Decodable>(filename: &str, data: &str,
     return err.into_result(res);
 }
 
+/// Parse a stream of `---` separated documents from a file
+///
+/// Every document is validated and decoded separately, each one has its
+/// own directives and anchors. Empty file yields no documents.
+///
+/// Every decoded document is passed to `process` as soon as it is read,
+/// the file is tokenized incrementally, so memory use doesn't depend on
+/// the size of the file. Once an error is found, the rest of the
+/// documents are still checked, but not passed to `process` any more.
+pub fn parse_config_stream<T, P, F>(filename: P, validator: &Validator,
+    options: &Options, process: F)
+    -> Result<(), ErrorList>
+    where T: Decodable, P: AsRef<Path>, F: FnMut(T)
+{
+    let filename = filename.as_ref();
+    let err = ErrorCollector::new();
+    let file = File::open(filename).and_then(DecodeReader::new).map_err(
+        |e| err.into_fatal(Error::OpenError(filename.to_path_buf(), e)))?;
+    let filename = Rc::new(format!("{}", filename.display()));
+    return decode_stream(filename, BufReader::new(file),
+        validator, options, &err, process);
+}
+
+/// Parse a stream of `---` separated documents from a string
+pub fn parse_string_stream<T, F>(filename: &str, data: &str,
+    validator: &Validator, options: &Options, process: F)
+    -> Result<(), ErrorList>
+    where T: Decodable, F: FnMut(T)
+{
+    let err = ErrorCollector::new();
+    return decode_stream(Rc::new(filename.to_string()), data.as_bytes(),
+        validator, options, &err, process);
+}
+
+/// Parse a stream of `---` separated documents from a reader
+///
+/// Same as `parse_config_stream`, but for arbitrary reader, like a pipe or
+/// a decompressed file. The `filename` is used in error messages
+pub fn parse_reader_stream<T, R, F>(filename: &str, reader: R,
+    validator: &Validator, options: &Options, process: F)
+    -> Result<(), ErrorList>
+    where T: Decodable, R: Read, F: FnMut(T)
+{
+    let err = ErrorCollector::new();
+    let reader = DecodeReader::new(reader).map_err(
+        |e| err.into_fatal(Error::OpenError(PathBuf::from(filename), e)))?;
+    return decode_stream(Rc::new(filename.to_string()),
+        BufReader::new(reader), validator, options, &err, process);
+}
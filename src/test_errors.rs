@@ -1,9 +1,10 @@
 use std::rc::Rc;
+use std::cell::RefCell;
 use rustc_serialize::Decodable;
 
 use {Options};
 use super::decode::YamlDecoder;
-use super::ast::process;
+use super::ast::{process, Ast};
 use super::parser::parse;
 use super::errors::ErrorCollector;
 
@@ -38,7 +39,7 @@ fn decode_struct(data: &str) -> Result<Struct1, String> {
 #[test]
 fn test_path() {
     assert_eq!(decode_struct("list:\n- {}"),
-        Err("<inline text>:2:3: Decode error at .list[0].value: \
+        Err("<inline text>:2:3-2:5: Decode error at .list[0].value: \
             Expected scalar, got Null\n".to_string()));
 }
 
@@ -62,3 +63,226 @@ fn test_non_working_includes() {
         Err("<inline text>:1:17: Preprocess Error: \
             Includes are not supported\n".to_string()));
 }
+
+#[test]
+fn test_folded_less_indented() {
+    assert_eq!(decode_struct("list:\n- value: >\n     a\n    b"),
+        Err("<inline text>:4:5: Parse Error: \
+            Less indented line in a block scalar\n".to_string()));
+}
+
+#[test]
+fn test_block_bad_header() {
+    assert_eq!(decode_struct("list:\n- value: |x\n    a"),
+        Err("<inline text>:2:10: Parse Error: \
+            Invalid block scalar header \"|x\"\n".to_string()));
+    assert_eq!(decode_struct("list:\n- value: >0\n    a"),
+        Err("<inline text>:2:10: Parse Error: \
+            Invalid block scalar header \">0\"\n".to_string()));
+    assert_eq!(decode_struct("list:\n- value: |-+\n    a"),
+        Err("<inline text>:2:10: Parse Error: \
+            Invalid block scalar header \"|-+\"\n".to_string()));
+    assert_eq!(decode_struct("list:\n- value: |1 x\n    a"),
+        Err("<inline text>:2:10: Parse Error: \
+            Invalid block scalar header \"|1 x\"\n".to_string()));
+}
+
+#[test]
+fn test_block_explicit_indent_too_big() {
+    assert_eq!(decode_struct("list:\n- value: |4\n     a\n    b"),
+        Err("<inline text>:3:6: Parse Error: \
+            Less indented line in a block scalar\n".to_string()));
+}
+
+#[test]
+fn test_hex_escape_short() {
+    assert_eq!(decode_struct(r#"list: [{value: "a\x4"}]"#),
+        Err("<inline text>:1:16: Parse Error: \
+            Escape \\x requires 2 hex digits, got 1\n".to_string()));
+    assert_eq!(decode_struct(r#"list: [{value: "\u00g1"}]"#),
+        Err("<inline text>:1:16: Parse Error: \
+            Escape \\u requires 4 hex digits, got 2\n".to_string()));
+}
+
+#[test]
+fn test_hex_escape_invalid_code_point() {
+    assert_eq!(decode_struct(r#"list: [{value: "\ud800"}]"#),
+        Err("<inline text>:1:16: Parse Error: \
+            Escape \\u specifies invalid code point 0xd800\n".to_string()));
+    assert_eq!(decode_struct(r#"list: [{value: "\U00110000"}]"#),
+        Err("<inline text>:1:16: Parse Error: \
+            Escape \\U specifies invalid code point 0x110000\n"
+            .to_string()));
+}
+
+#[test]
+fn test_complex_key_duplicate() {
+    assert_eq!(decode_struct("? [a, b]\n: 1\n? [a, b]\n: 2"),
+        Err("<inline text>:3:3: Preprocess Error: Duplicate key \"[a, b]\", \
+            previously defined at <inline text>:1:3\n".to_string()));
+}
+
+#[test]
+fn test_undefined_tag_handle() {
+    assert_eq!(decode_struct("list: !e!x []"),
+        Err("<inline text>:1:12: Preprocess Error: Tag handle \"!e!\" \
+            is not defined by a %TAG directive\n".to_string()));
+}
+
+#[test]
+fn test_duplicate_tag_handle() {
+    assert_eq!(decode_struct("%TAG !e! a:\n%TAG !e! b:\n---\nlist: []"),
+        Err("<inline text>:2:1: Preprocess Error: \
+            Tag handle \"!e!\" is defined twice\n".to_string()));
+}
+
+#[test]
+fn test_core_schema_int_invalid() {
+    assert_eq!(decode_struct("list:\n- value: !!int x"),
+        Err("<inline text>:2:16-2:17: Preprocess Error: \
+            Value \"x\" is not a valid integer\n".to_string()));
+}
+
+#[test]
+fn test_duplicate_yaml_directive() {
+    assert_eq!(decode_struct("%YAML 1.2\n%YAML 1.1\n---\nlist: []"),
+        Err("<inline text>:2:1: Preprocess Error: \
+            Duplicate %YAML directive\n".to_string()));
+}
+
+#[test]
+fn test_unsupported_yaml_version() {
+    assert_eq!(decode_struct("%YAML 2.0\n---\nlist: []"),
+        Err("<inline text>:1:1: Preprocess Error: \
+            YAML version 2.0 is not supported\n".to_string()));
+}
+
+#[test]
+fn test_unknown_yaml_minor_version() {
+    let warnings = RefCell::new(Vec::new());
+    let mut options = Options::default();
+    options.warning_handler(|w| warnings.borrow_mut().push(w.to_string()));
+    let err = ErrorCollector::new();
+    parse(Rc::new("<inline text>".to_string()),
+        "%YAML 1.3\n---\nlist: []",
+        |doc| { process(&options, doc, &err) }
+    ).map_err(|e| err.into_fatal(e)).unwrap();
+    err.into_result(()).unwrap();
+    assert_eq!(*warnings.borrow(), vec!(
+        "<inline text>:1:1: Warning: \
+         YAML version 1.3 is not supported, processing as 1.2".to_string()));
+}
+
+fn process_with_options(options: &Options, data: &str)
+    -> Result<(), String>
+{
+    let err = ErrorCollector::new();
+    parse(Rc::new("<inline text>".to_string()), data,
+        |doc| { process(options, doc, &err) }
+    ).map_err(|e| err.into_fatal(e))
+    .and_then(|_| err.into_result(()))
+    .map_err(|e| format!("{}", e))
+}
+
+#[test]
+fn test_alias_expanded_nodes_limit() {
+    let mut options = Options::default();
+    options.max_expanded_nodes(20);
+    assert_eq!(process_with_options(&options,
+            "a: &a [x, x, x]\nb: &b [*a, *a, *a]\nc: [*b, *b, *b]"),
+        Err("<inline text>:3:5: Preprocess Error: Too many nodes produced \
+            by expanding aliases, maximum is 20\n".to_string()));
+}
+
+#[test]
+fn test_alias_expanded_nodes_limit_complex_key() {
+    // Two equal but distinct chains, each of them fits the limit
+    let mut data = String::new();
+    for name in &["a", "b"] {
+        data.push_str(&format!("{}0: &{}0 [x, x]\n", name, name));
+        for i in 1..8 {
+            data.push_str(&format!("{n}{}: &{n}{} [*{n}{}, *{n}{}]\n",
+                i, i, i-1, i-1, n=name));
+        }
+    }
+    let mut options = Options::default();
+    options.max_expanded_nodes(3500);
+    assert_eq!(process_with_options(&options, &data), Ok(()));
+    data.push_str("? {*a7 : 1, *b7 : 2}\n: v");
+    assert_eq!(process_with_options(&options, &data),
+        Err("<inline text>:17:4: Preprocess Error: Too many nodes produced \
+            by expanding aliases, maximum is 3500\n".to_string()));
+}
+
+#[test]
+fn test_alias_depth_limit() {
+    let mut options = Options::default();
+    options.max_alias_depth(2);
+    assert_eq!(process_with_options(&options,
+            "a: &a x\nb: &b [*a]\nc: &c [*b]\nd: [*c]"),
+        Err("<inline text>:4:5: Preprocess Error: \
+            Aliases are nested too deep, maximum depth is 2\n".to_string()));
+}
+
+#[test]
+fn test_duplicate_key() {
+    assert_eq!(decode_struct("list: []\nlist: []"),
+        Err("<inline text>:2:1: Preprocess Error: Duplicate key \"list\", \
+            previously defined at <inline text>:1:1\n".to_string()));
+}
+
+#[test]
+fn test_duplicate_key_flow() {
+    assert_eq!(decode_struct("{list: [], \"list\": []}"),
+        Err("<inline text>:1:12: Preprocess Error: Duplicate key \"list\", \
+            previously defined at <inline text>:1:2\n".to_string()));
+}
+
+#[test]
+fn test_merged_key_is_not_duplicate() {
+    assert_eq!(process_with_options(&Options::default(),
+            "a: &a {x: 1, y: 2}\nb:\n  <<: *a\n  x: 3"),
+        Ok(()));
+}
+
+#[test]
+fn test_duplicate_key_warning() {
+    let warnings = RefCell::new(Vec::new());
+    let mut options = Options::default();
+    options.warn_duplicate_keys()
+        .warning_handler(|w| warnings.borrow_mut().push(w.to_string()));
+    assert_eq!(process_with_options(&options, "a: 1\nb: 2\na: 3"), Ok(()));
+    assert_eq!(*warnings.borrow(), vec!(
+        "<inline text>:3:1: Warning: Duplicate key \"a\", \
+         previously defined at <inline text>:1:1".to_string()));
+}
+
+#[test]
+fn test_error_span() {
+    let err = ErrorCollector::new();
+    parse(Rc::new("<inline text>".to_string()), "list: !!int abc",
+        |doc| { process(&Options::default(), doc, &err) }
+    ).map_err(|e| err.into_fatal(e)).unwrap();
+    let errors = err.unwrap();
+    assert_eq!(errors.errors()
+        .map(|e| e.pos().unwrap().to_string()).collect::<Vec<_>>(),
+        vec!("<inline text>:1:13-1:16".to_string()));
+}
+
+#[test]
+fn test_node_spans() {
+    let err = ErrorCollector::new();
+    let ast = parse(Rc::new("<inline text>".to_string()),
+        "list:\n- a\n- [b, c]  # comment\n",
+        |doc| { process(&Options::default(), doc, &err) }
+    ).map_err(|e| err.into_fatal(e)).unwrap();
+    err.into_result(()).unwrap();
+    assert_eq!(ast.span().to_string(), "<inline text>:1:1-3:9");
+    match ast {
+        Ast::Map(_, _, _, ref map) => {
+            assert_eq!(map["list"].span().to_string(),
+                       "<inline text>:2:1-3:9");
+        }
+        _ => unreachable!(),
+    }
+}
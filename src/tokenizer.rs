@@ -1,4 +1,8 @@
 use std::vec::Vec;
+use std::borrow::Cow;
+use std::cmp::max;
+use std::collections::VecDeque;
+use std::io::{self, BufRead};
 use std::str::CharIndices;
 use std::rc::Rc;
 use std::fmt::Display;
@@ -10,6 +14,8 @@ use std::clone::Clone;
 use super::chars::is_whitespace;
 use super::chars::is_printable;
 use super::chars::is_tag_char;
+use super::chars::is_uri_char;
+use super::chars::is_word_char;
 use super::chars::is_flow_indicator;
 use super::chars::is_anchor_name;
 use self::TokenType::*;
@@ -40,6 +46,7 @@ pub enum TokenType {
     FlowMapEnd,  // '}'
     FlowEntry,  // ','
     Directive,  // '%...'
+    Error,  // marks the tokenizer error, never returned by the tokenizer
 }
 
 quick_error! {
@@ -60,6 +67,8 @@ quick_error! {
         UnclosedSingleQuoted {
             display("Unclosed quoted string") }
         BadCharInTag { display("Bad char in tag name") }
+        UnclosedVerbatimTag {
+            display("Unclosed verbatim tag, expected '>'") }
         BadCharInAnchor { display("Bad char in anchor name") }
         ZeroLengthAnchor {
             display("Anchor name requires at least one character") }
@@ -88,11 +97,58 @@ impl Display for Pos {
     }
 }
 
+/// Source code span, the end position is exclusive
+#[derive(Clone, Debug)]
+pub struct Span {
+    /// Position of the first character
+    pub start: Pos,
+    /// Position right after the last character
+    pub end: Pos,
+}
+
+impl Span {
+    /// Create a span from start and end positions
+    pub fn new(start: Pos, end: Pos) -> Span {
+        Span { start: start, end: end }
+    }
+}
+
+impl From<Pos> for Span {
+    fn from(pos: Pos) -> Span {
+        Span { start: pos.clone(), end: pos }
+    }
+}
+
+impl Display for Span {
+    fn fmt(&self, fmt:&mut Formatter) -> Result<(), FormatError> {
+        write!(fmt, "{filename}:{line}:{offset}-{end_line}:{end_offset}",
+            filename=self.start.filename,
+            line=self.start.line,
+            offset=self.start.line_offset,
+            end_line=self.end.line,
+            end_offset=self.end.line_offset)
+    }
+}
+
+#[derive(Clone)]
 pub struct Token<'tok> {
     pub kind: TokenType,
     pub start: Pos,
     pub end: Pos,
-    pub value: &'tok str,
+    /// Text of the token, it's owned when the input is read by parts
+    pub value: Cow<'tok, str>,
+}
+
+impl<'tok> Token<'tok> {
+    /// Copies the text of the token, so it outlives the input
+    pub fn into_owned(self) -> Token<'static> {
+        return Token {
+            kind: self.kind,
+            start: self.start,
+            end: self.end,
+            value: Cow::Owned(self.value.into_owned()),
+        };
+    }
 }
 
 #[derive(Clone)]
@@ -115,17 +171,32 @@ struct Tokenizer<'a, 'b: 'a> {
     doc_start: bool,
 }
 
+/// State of the tokenizer between tokens, which is enough to continue
+/// tokenizing from that place
+#[derive(Clone)]
+struct State {
+    indent_levels: Vec<usize>,
+    flow_level: usize,
+    doc_start: bool,
+}
+
 impl<'a> YamlIter<'a> {
+    #[cfg(test)]
     fn new<'x>(filename: Rc<String>, buf: &'x str) -> YamlIter<'x> {
         let mut iter = buf.char_indices();
+        let mut next_value = iter.next();
+        if let Some((_, '\u{FEFF}')) = next_value {
+            // Byte order mark is not a part of the content
+            next_value = iter.next();
+        }
         return YamlIter {
             buf: buf,
-            next_value: iter.next(),
+            next_value: next_value,
             chars: iter,
             position: Pos {
                 filename: filename,
                 indent: 0,
-                offset: 0,
+                offset: next_value.map(|(off, _)| off).unwrap_or(buf.len()),
                 line: 1,
                 line_start: true,
                 line_offset: 1,
@@ -134,6 +205,30 @@ impl<'a> YamlIter<'a> {
             error: None,
             };
     }
+
+    /// Iterates over `buf` which starts at `start` of the input
+    ///
+    /// Line and offset of the returned positions are relative to `start`,
+    /// i.e. the first line is `1` and the first offset is `0`
+    fn resume<'x>(start: &Pos, buf: &'x str) -> YamlIter<'x> {
+        let mut iter = buf.char_indices();
+        let next_value = iter.next();
+        return YamlIter {
+            buf: buf,
+            next_value: next_value,
+            chars: iter,
+            position: Pos {
+                filename: start.filename.clone(),
+                indent: start.indent,
+                offset: 0,
+                line: 1,
+                line_start: start.line_start,
+                line_offset: start.line_offset,
+                },
+            value: None,
+            error: None,
+            };
+    }
 }
 
 impl<'a> Iterator for YamlIter<'a> {
@@ -188,6 +283,7 @@ impl<'a> Iterator for YamlIter<'a> {
 
 impl<'a, 'b> Tokenizer<'a, 'b> {
 
+    #[cfg(test)]
     fn new<'x, 'y>(result: &'x mut Vec<Token<'y>>,
         name: Rc<String>, data: &'y str)
         -> Tokenizer<'x, 'y>
@@ -203,6 +299,30 @@ impl<'a, 'b> Tokenizer<'a, 'b> {
         }
     }
 
+    /// Continues tokenizing at `start` of the input, where `data` begins
+    fn resume<'x, 'y>(result: &'x mut Vec<Token<'y>>,
+        start: &Pos, state: State, data: &'y str)
+        -> Tokenizer<'x, 'y>
+    {
+        return Tokenizer {
+            result: result,
+            data: data,
+            iter: YamlIter::resume(start, data),
+            error: None,
+            indent_levels: state.indent_levels,
+            flow_level: state.flow_level,
+            doc_start: state.doc_start,
+        }
+    }
+
+    fn state(&self) -> State {
+        return State {
+            indent_levels: self.indent_levels.clone(),
+            flow_level: self.flow_level,
+            doc_start: self.doc_start,
+        };
+    }
+
     fn skip_whitespace(&self) -> YamlIter<'b> {
         let mut iter = self.iter.clone();
         loop {
@@ -264,6 +384,14 @@ impl<'a, 'b> Tokenizer<'a, 'b> {
                                 niter.position.indent >= minindent
                         {
                             match self.iter.next_value.map(|(_, x)| x) {
+                                None => {
+                                    // Trailing whitespace at the end of file
+                                    self.add_token(PlainString,
+                                        start, pos.clone());
+                                    self.add_token(Whitespace, pos,
+                                        niter.position);
+                                    return;
+                                }
                                 Some('[') | Some(']') | Some('{') | Some('}')
                                 | Some(',') if self.flow_level > 0 => {
                                     self.add_token(PlainString,
@@ -300,6 +428,22 @@ impl<'a, 'b> Tokenizer<'a, 'b> {
                                         return;
                                     }
                                 }
+                                Some('-') if niter.position.line_offset == 1
+                                => {
+                                    let slice = &self.data[
+                                        niter.position.offset..];
+                                    if slice.starts_with("--- ") ||
+                                       slice.starts_with("---\r") ||
+                                       slice.starts_with("---\n") ||
+                                       slice == "---"
+                                    {
+                                        self.add_token(PlainString,
+                                            start, pos.clone());
+                                        self.add_token(Whitespace, pos,
+                                            niter.position);
+                                        return;
+                                    }
+                                }
                                 _ => {}
                             }
                         } else {
@@ -338,13 +482,27 @@ impl<'a, 'b> Tokenizer<'a, 'b> {
                     '\n' | '\r' => {
                         // may end folded if next block is not indented
                         // as much
-                        let niter = self.skip_whitespace();
+                        let mut niter = self.iter.clone();
+                        // trailing empty lines belong to the scalar, they
+                        // are needed for "keep" chomping
+                        let mut last_break = pos;
+                        loop {
+                            match niter.next_value {
+                                Some((_, ' ')) | Some((_, '\r')) => {}
+                                Some((_, '\n')) => {
+                                    last_break = niter.position.clone();
+                                }
+                                _ => break,
+                            }
+                            niter.next();
+                        }
                         self.iter = niter.clone();
                         if niter.position.indent >= minindent {
                             continue;
                         }
-                        self.add_token(tok, start, pos.clone());
-                        self.add_token(Whitespace, pos, niter.position.clone());
+                        self.add_token(tok, start, last_break.clone());
+                        self.add_token(Whitespace, last_break,
+                                       niter.position.clone());
                         return;
                     }
                     _ => continue,
@@ -357,7 +515,19 @@ impl<'a, 'b> Tokenizer<'a, 'b> {
     }
 
     fn add_token(&mut self, kind: TokenType, start: Pos, end: Pos) {
-        if kind != Whitespace && kind != Comment && kind != DocumentStart &&
+        if kind == DocumentStart && self.flow_level == 0 {
+            // New document in a stream, close all the blocks of previous one
+            while self.indent_levels.len() > 1 {
+                self.result.push(Token {
+                    kind: Unindent,
+                    start: start.clone(),
+                    end: start.clone(),
+                    value: Cow::Borrowed(""),
+                    });
+                self.indent_levels.pop();
+            }
+            self.doc_start = true;
+        } else if kind != Whitespace && kind != Comment &&
             self.flow_level == 0
         {
             // always have "0" at bottom of the stack so just unwrap it
@@ -368,7 +538,7 @@ impl<'a, 'b> Tokenizer<'a, 'b> {
                     kind: Indent,
                     start: start.clone(),
                     end: start.clone(),
-                    value: &self.data[start.offset..start.offset],
+                    value: Cow::Borrowed(""),
                     });
                 self.indent_levels.push(start.indent);
             } else if start.indent < cur {
@@ -377,7 +547,7 @@ impl<'a, 'b> Tokenizer<'a, 'b> {
                         kind: Unindent,
                         start: start.clone(),
                         end: start.clone(),
-                        value: &self.data[start.offset..start.offset],
+                        value: Cow::Borrowed(""),
                         });
                     self.indent_levels.pop();
                 }
@@ -391,199 +561,250 @@ impl<'a, 'b> Tokenizer<'a, 'b> {
             kind: kind,
             start: start.clone(),
             end: end.clone(),
-            value: &self.data[start.offset..end.offset],
+            value: Cow::Borrowed(&self.data[start.offset..end.offset]),
             });
+        if kind == DocumentEnd {
+            self.doc_start = true;
+        }
     }
 
 
-    fn tokenize(&mut self) -> Option<(Pos, Error)> {
-        'tokenloop: loop  {
-            if !self.error.is_none() {
-                break;
-            }
-            match self.iter.next() {
-                Some((start, '-')) => { // list element, doc start, plainstring
-                    match self.iter.next() {
-                        Some((_, '-')) => { // maybe document end
-                            match self.iter.next() {
-                                Some((_, '-')) => {
-                                    let end = self.iter.position.clone();
-                                    self.add_token(DocumentStart, start, end);
-                                }
-                                _ => self.read_plain(start),
-                            }
-                        }
-                        Some((cur, ' ')) | Some((cur, '\t'))
-                        | Some((cur, '\r')) | Some((cur, '\n')) => {
-                            //  For handling nested maps and lists
-                            //  indentation must be adjusted to the level
-                            //  of the end of whitespace
-                            if self.iter.position.line == start.line {
-                                //  Line offset is human-readable so 1-based
-                                //  as opposed to indentation
-                                self.iter.position.indent =
-                                    self.iter.position.line_offset - 1;
+    /// Reads the next token (or a few related ones)
+    ///
+    /// Returns false at the end of the input or if an error is found
+    fn step(&mut self) -> bool {
+        if !self.error.is_none() {
+            return false;
+        }
+        match self.iter.next() {
+            Some((start, '-')) => { // list element, doc start, plainstring
+                match self.iter.next() {
+                    Some((_, '-')) => { // maybe document end
+                        match self.iter.next() {
+                            Some((_, '-')) => {
+                                let end = self.iter.position.clone();
+                                self.add_token(DocumentStart, start, end);
                             }
-                            self.add_token(SequenceEntry, start, cur.clone());
-                            self.iter = self.skip_whitespace();
-                            let end = self.iter.position.clone();
-                            self.add_token(Whitespace, cur, end);
+                            _ => self.read_plain(start),
                         }
-                        Some(_) => {
-                            self.read_plain(start);
+                    }
+                    Some((cur, ' ')) | Some((cur, '\t'))
+                    | Some((cur, '\r')) | Some((cur, '\n')) => {
+                        //  For handling nested maps and lists
+                        //  indentation must be adjusted to the level
+                        //  of the end of whitespace
+                        if self.iter.position.line == start.line {
+                            //  Line offset is human-readable so 1-based
+                            //  as opposed to indentation
+                            self.iter.position.indent =
+                                self.iter.position.line_offset - 1;
                         }
-                        None => {
-                            let end = self.iter.position.clone();
-                            self.add_token(SequenceEntry, start, end);
-                            break;
+                        self.add_token(SequenceEntry, start, cur.clone());
+                        self.iter = self.skip_whitespace();
+                        let end = self.iter.position.clone();
+                        self.add_token(Whitespace, cur, end);
+                    }
+                    Some(_) => {
+                        self.read_plain(start);
+                    }
+                    None => {
+                        let end = self.iter.position.clone();
+                        self.add_token(SequenceEntry, start, end);
+                        return false;
+                    }
+                }
+            }
+            Some((ref start, '.')) if start.indent == 0 => {
+                match (self.iter.next(), self.iter.next(),
+                       self.iter.next_value) {
+                    (Some((_, '.')), Some((_, '.')), ref end)
+                    if end.is_none() || end.is_some() &&
+                                is_whitespace(end.as_ref().unwrap().1)
+                    => { // document end
+                        let end = self.iter.position.clone();
+                        self.add_token(DocumentEnd, start.clone(), end);
+                    }
+                    _ => {
+                        self.read_plain(start.clone());
+                    }
+                }
+            }
+            Some((start, '?')) => { // key, plainstring
+                // TODO(tailhook) in flow context space is not required
+                match self.iter.next() {
+                    Some((cur, ' ')) | Some((cur, '\t'))
+                    | Some((cur, '\r')) | Some((cur, '\n')) => {
+                        // Complex keys are indented the same way as
+                        // list elements
+                        if self.iter.position.line == start.line &&
+                            self.flow_level == 0
+                        {
+                            self.iter.position.indent =
+                                self.iter.position.line_offset - 1;
                         }
+                        self.add_token(MappingKey, start, cur.clone());
+                        self.iter = self.skip_whitespace();
+                        let end = self.iter.position.clone();
+                        self.add_token(Whitespace, cur, end);
+                    }
+                    None => {
+                        let end = self.iter.position.clone();
+                        self.add_token(MappingKey, start, end);
+                        return false;
+                    }
+                    Some(_) =>  {
+                        self.read_plain(start);
                     }
                 }
-                Some((ref start, '.')) if start.indent == 0 => {
-                    match (self.iter.next(), self.iter.next(),
-                           self.iter.next_value) {
-                        (Some((_, '.')), Some((_, '.')), ref end)
-                        if end.is_none() || end.is_some() &&
-                                    is_whitespace(end.as_ref().unwrap().1)
-                        => { // document end
+            }
+            Some((start, ':')) => { // key, plainstring
+                // TODO(tailhook) in flow context space is not required
+                if self.flow_level > 0 {
+                    match self.iter.next_value {
+                        Some((_, ' ')) | Some((_, '\t'))
+                        | Some((_, '\r')) | Some((_, '\n'))
+                        | Some((_, '{')) | Some((_, '}'))
+                        | Some((_, '[')) | Some((_, ']'))
+                        | Some((_, ',')) => {
                             let end = self.iter.position.clone();
-                            self.add_token(DocumentEnd, start.clone(), end);
+                            self.add_token(MappingValue, start, end);
                         }
                         _ => {
-                            self.read_plain(start.clone());
+                            self.error = Some((start.clone(),
+                                               Error::AmbigueColon));
+                            return false;
                         }
                     }
-                }
-                Some((start, '?')) => { // key, plainstring
-                    // TODO(tailhook) in flow context space is not required
+                } else {
                     match self.iter.next() {
                         Some((cur, ' ')) | Some((cur, '\t'))
                         | Some((cur, '\r')) | Some((cur, '\n')) => {
-                            self.add_token(MappingKey, start, cur.clone());
+                            // Value of the complex key is indented
+                            // the same way as list elements
+                            if start.line_start &&
+                                self.iter.position.line == start.line
+                            {
+                                self.iter.position.indent =
+                                    self.iter.position.line_offset - 1;
+                            }
+                            self.add_token(MappingValue,
+                                start, cur.clone());
                             self.iter = self.skip_whitespace();
                             let end = self.iter.position.clone();
                             self.add_token(Whitespace, cur, end);
                         }
                         None => {
                             let end = self.iter.position.clone();
-                            self.add_token(MappingKey, start, end);
-                            break;
+                            self.add_token(MappingValue, start, end);
+                            return false;
                         }
                         Some(_) =>  {
                             self.read_plain(start);
                         }
                     }
                 }
-                Some((start, ':')) => { // key, plainstring
-                    // TODO(tailhook) in flow context space is not required
-                    if self.flow_level > 0 {
-                        match self.iter.next_value {
-                            Some((_, ' ')) | Some((_, '\t'))
-                            | Some((_, '\r')) | Some((_, '\n'))
-                            | Some((_, '{')) | Some((_, '}'))
-                            | Some((_, '[')) | Some((_, ']'))
-                            | Some((_, ',')) => {
-                                let end = self.iter.position.clone();
-                                self.add_token(MappingValue, start, end);
-                            }
-                            _ => {
-                                self.error = Some((start.clone(),
-                                                   Error::AmbigueColon));
-                                break;
-                            }
-                        }
-                    } else {
-                        match self.iter.next() {
-                            Some((cur, ' ')) | Some((cur, '\t'))
-                            | Some((cur, '\r')) | Some((cur, '\n')) => {
-                                self.add_token(MappingValue,
-                                    start, cur.clone());
-                                self.iter = self.skip_whitespace();
-                                let end = self.iter.position.clone();
-                                self.add_token(Whitespace, cur, end);
-                            }
-                            None => {
-                                let end = self.iter.position.clone();
-                                self.add_token(MappingValue, start, end);
-                                break;
-                            }
-                            Some(_) =>  {
-                                self.read_plain(start);
-                            }
-                        }
-                    }
+            }
+            Some((start, '%')) => {
+                if start.line_offset != 1 {
+                    self.error = Some((start.clone(),
+                                       Error::UnexpectedDirective));
+                    return false;
                 }
-                Some((start, '%')) => {
-                    if start.line_offset != 1 {
-                        self.error = Some((start.clone(),
-                                           Error::UnexpectedDirective));
+                for (_, ch) in &mut self.iter {
+                    if ch == '\r' || ch == '\n' {
                         break;
                     }
-                    for (_, ch) in &mut self.iter {
-                        if ch == '\r' || ch == '\n' {
-                            break;
-                        }
-                    }
-                    let end = self.iter.position.clone();
-                    self.add_token(Directive, start, end);
                 }
-                Some((start, '@')) | Some((start, '`')) => {
-                    self.error = Some((start.clone(), Error::BadChars));
-                    break;
+                let end = self.iter.position.clone();
+                self.add_token(Directive, start, end);
+            }
+            Some((start, '@')) | Some((start, '`')) => {
+                self.error = Some((start.clone(), Error::BadChars));
+                return false;
+            }
+            Some((start, '\t')) => {
+                self.error = Some((start.clone(), Error::UnquotedTab));
+                return false;
+            }
+            Some((start, '"')) => {
+                let mut prev = '"';
+                for (_, ch) in &mut self.iter {
+                    if ch == '"' && prev != '\\' {
+                        break;
+                    }
+                    prev = ch;
                 }
-                Some((start, '\t')) => {
-                    self.error = Some((start.clone(), Error::UnquotedTab));
-                    break;
+                if self.iter.value.is_none() {
+                    self.error = Some((start.clone(),
+                                       Error::UnclosedDoubleQuoted));
+                    return false;
                 }
-                Some((start, '"')) => {
-                    let mut prev = '"';
-                    for (_, ch) in &mut self.iter {
-                        if ch == '"' && prev != '\\' {
-                            break;
-                        }
-                        prev = ch;
+                let end = self.iter.position.clone();
+                self.add_token(DoubleString, start, end.clone());
+                // This is YAML 1.2 hack to be superset of json
+                match self.iter.next_value {
+                    Some((_, ':')) => {
+                        self.iter.next();
+                        let mvalend = self.iter.position.clone();
+                        self.add_token(MappingValue, end, mvalend);
                     }
-                    if self.iter.value.is_none() {
-                        self.error = Some((start.clone(),
-                                           Error::UnclosedDoubleQuoted));
+                    _ => {}
+                }
+            }
+            Some((start, '\'')) => {
+                for (_, ch) in &mut self.iter {
+                    if ch == '\'' {
                         break;
                     }
-                    let end = self.iter.position.clone();
-                    self.add_token(DoubleString, start, end.clone());
-                    // This is YAML 1.2 hack to be superset of json
-                    match self.iter.next_value {
-                        Some((_, ':')) => {
-                            self.iter.next();
-                            let mvalend = self.iter.position.clone();
-                            self.add_token(MappingValue, end, mvalend);
-                        }
-                        _ => {}
-                    }
                 }
-                Some((start, '\'')) => {
-                    for (_, ch) in &mut self.iter {
-                        if ch == '\'' {
-                            break;
-                        }
-                    }
-                    if self.iter.value.is_none() {
-                        self.error = Some((start.clone(),
-                                           Error::UnclosedSingleQuoted));
+                if self.iter.value.is_none() {
+                    self.error = Some((start.clone(),
+                                       Error::UnclosedSingleQuoted));
+                    return false;
+                }
+                let end = self.iter.position.clone();
+                self.add_token(SingleString, start, end);
+            }
+            Some((start, '#')) => {
+                for (_, ch) in &mut self.iter {
+                    if ch == '\r' || ch == '\n' {
                         break;
                     }
-                    let end = self.iter.position.clone();
-                    self.add_token(SingleString, start, end);
                 }
-                Some((start, '#')) => {
-                    for (_, ch) in &mut self.iter {
-                        if ch == '\r' || ch == '\n' {
-                            break;
+                let end = self.iter.position.clone();
+                self.add_token(Comment, start, end);
+            }
+            Some((start, '!')) => {
+                if let Some((_, '<')) = self.iter.next_value {
+                    // Verbatim tag, like `!<tag:yaml.org,2002:str>`
+                    self.iter.next();
+                    loop {
+                        match self.iter.next() {
+                            Some((_, '>')) => break,
+                            Some((pos, ch)) if !is_uri_char(ch) => {
+                                self.error = Some((pos,
+                                                   Error::BadCharInTag));
+                                return false;
+                            }
+                            Some(_) => {}
+                            None => {
+                                self.error = Some((start.clone(),
+                                    Error::UnclosedVerbatimTag));
+                                return false;
+                            }
                         }
                     }
-                    let end = self.iter.position.clone();
-                    self.add_token(Comment, start, end);
-                }
-                Some((start, '!')) => {
+                    match self.iter.next_value {
+                        Some((_, ch)) if !is_whitespace(ch) => {
+                            let (pos, _) = self.iter.next().unwrap();
+                            self.error = Some((pos, Error::BadCharInTag));
+                            return false;
+                        }
+                        _ => {}
+                    }
+                } else {
+                    // Named handle like `!name!` may precede the suffix
+                    let mut in_handle = true;
                     loop {
                         match self.iter.next_value {
                             Some((_, ch)) if is_whitespace(ch) => break,
@@ -591,100 +812,110 @@ impl<'a, 'b> Tokenizer<'a, 'b> {
                             _ => {}
                         }
                         let (pos, ch) = self.iter.next().unwrap();
+                        if ch == '!' && in_handle {
+                            in_handle = false;
+                            continue;
+                        }
+                        in_handle = in_handle && is_word_char(ch);
                         if !is_tag_char(ch) {
                             self.error = Some((pos.clone(),
                                                Error::BadCharInTag));
-                            break 'tokenloop;
+                            return false;
                         }
                     }
-                    let end = self.iter.position.clone();
-                    self.add_token(Tag, start, end);
                 }
-                Some((start, '&')) => {
-                    loop {
-                        match self.iter.next_value {
-                            Some((_, ch)) if !is_anchor_name(ch) => break,
-                            None => break,
-                            _ => {}
-                        }
-                        self.iter.next();
-                    }
-                    if let Some((_, ch)) = self.iter.next_value {
-                        if is_flow_indicator(ch) {
-                            self.error = Some((self.iter.position.clone(),
-                                               Error::BadCharInAnchor));
-                            break 'tokenloop;
-                        }
-                    }
-                    if self.iter.position.offset - start.offset < 2 {
-                        self.error = Some((start.clone(),
-                                           Error::ZeroLengthAnchor));
-                        break;
-                    }
-                    let end = self.iter.position.clone();
-                    self.add_token(Anchor, start, end);
-                }
-                Some((start, '*')) => {
-                    loop {
-                        match self.iter.next_value {
-                            Some((_, ch)) if !is_anchor_name(ch) => break,
-                            None => break,
-                            _ => {}
-                        }
-                        self.iter.next();
-                    }
-                    if self.iter.position.offset - start.offset < 2 {
-                        self.error = Some((start.clone(),
-                                           Error::ZeroLengthAlias));
-                        break;
+                let end = self.iter.position.clone();
+                self.add_token(Tag, start, end);
+            }
+            Some((start, '&')) => {
+                loop {
+                    match self.iter.next_value {
+                        Some((_, ch)) if !is_anchor_name(ch) => break,
+                        None => break,
+                        _ => {}
                     }
-                    let end = self.iter.position.clone();
-                    self.add_token(Alias, start, end);
+                    self.iter.next();
                 }
-                Some((start, ',')) => {
-                    let end = self.iter.position.clone();
-                    self.add_token(FlowEntry, start, end);
-                }
-                Some((start, '[')) => {
-                    let end = self.iter.position.clone();
-                    self.add_token(FlowSeqStart, start, end);
-                    self.flow_level += 1;
-                }
-                Some((start, ']')) => {
-                    let end = self.iter.position.clone();
-                    self.add_token(FlowSeqEnd, start, end);
-                    if self.flow_level > 0 {
-                        self.flow_level -= 1;
+                if let Some((_, ch)) = self.iter.next_value {
+                    if is_flow_indicator(ch) {
+                        self.error = Some((self.iter.position.clone(),
+                                           Error::BadCharInAnchor));
+                        return false;
                     }
                 }
-                Some((start, '{')) => {
-                    let end = self.iter.position.clone();
-                    self.add_token(FlowMapStart, start, end);
-                    self.flow_level += 1;
+                if self.iter.position.offset - start.offset < 2 {
+                    self.error = Some((start.clone(),
+                                       Error::ZeroLengthAnchor));
+                    return false;
                 }
-                Some((start, '}')) => {
-                    let end = self.iter.position.clone();
-                    self.add_token(FlowMapEnd, start, end);
-                    if self.flow_level > 0 {
-                        self.flow_level -= 1;
+                let end = self.iter.position.clone();
+                self.add_token(Anchor, start, end);
+            }
+            Some((start, '*')) => {
+                loop {
+                    match self.iter.next_value {
+                        Some((_, ch)) if !is_anchor_name(ch) => break,
+                        None => break,
+                        _ => {}
                     }
+                    self.iter.next();
                 }
-                Some((start, '|')) => {
-                    self.read_block(Literal, start);
+                if self.iter.position.offset - start.offset < 2 {
+                    self.error = Some((start.clone(),
+                                       Error::ZeroLengthAlias));
+                    return false;
                 }
-                Some((start, '>')) => {
-                    self.read_block(Folded, start);
+                let end = self.iter.position.clone();
+                self.add_token(Alias, start, end);
+            }
+            Some((start, ',')) => {
+                let end = self.iter.position.clone();
+                self.add_token(FlowEntry, start, end);
+            }
+            Some((start, '[')) => {
+                let end = self.iter.position.clone();
+                self.add_token(FlowSeqStart, start, end);
+                self.flow_level += 1;
+            }
+            Some((start, ']')) => {
+                let end = self.iter.position.clone();
+                self.add_token(FlowSeqEnd, start, end);
+                if self.flow_level > 0 {
+                    self.flow_level -= 1;
                 }
-                Some((start, ' '))
-                | Some((start, '\r')) | Some((start, '\n')) => {
-                    self.iter = self.skip_whitespace();
-                    let end = self.iter.position.clone();
-                    self.add_token(Whitespace, start, end);
+            }
+            Some((start, '{')) => {
+                let end = self.iter.position.clone();
+                self.add_token(FlowMapStart, start, end);
+                self.flow_level += 1;
+            }
+            Some((start, '}')) => {
+                let end = self.iter.position.clone();
+                self.add_token(FlowMapEnd, start, end);
+                if self.flow_level > 0 {
+                    self.flow_level -= 1;
                 }
-                Some((start, _)) => { self.read_plain(start); }
-                None => break,
             }
+            Some((start, '|')) => {
+                self.read_block(Literal, start);
+            }
+            Some((start, '>')) => {
+                self.read_block(Folded, start);
+            }
+            Some((start, ' '))
+            | Some((start, '\r')) | Some((start, '\n')) => {
+                self.iter = self.skip_whitespace();
+                let end = self.iter.position.clone();
+                self.add_token(Whitespace, start, end);
+            }
+            Some((start, _)) => { self.read_plain(start); }
+            None => return false,
         }
+        return self.error.is_none() && self.iter.error.is_none();
+    }
+
+    /// Adds the tokens closing the blocks at the end of the input
+    fn finish(&mut self) -> Option<(Pos, Error)> {
         let pos = self.iter.position.clone();
         if self.indent_levels.len() > 1 {
             for _ in 0..(self.indent_levels.len() - 1) {
@@ -692,7 +923,7 @@ impl<'a, 'b> Tokenizer<'a, 'b> {
                     kind: Unindent,
                     start: pos.clone(),
                     end: pos.clone(),
-                    value: &self.data[pos.offset..pos.offset],
+                    value: Cow::Borrowed(""),
                     });
             }
         }
@@ -700,12 +931,24 @@ impl<'a, 'b> Tokenizer<'a, 'b> {
             kind: Eof,
             start: pos.clone(),
             end: pos.clone(),
-            value: &self.data[pos.offset..pos.offset],
+            value: Cow::Borrowed(""),
             });
+        return self.error();
+    }
+
+    /// Error which stopped tokenizing, if any
+    fn error(&self) -> Option<(Pos, Error)> {
         return self.error.clone().or(self.iter.error.clone());
     }
+
+    #[cfg(test)]
+    fn tokenize(&mut self) -> Option<(Pos, Error)> {
+        while self.step() {}
+        return self.finish();
+    }
 }
 
+#[cfg(test)]
 pub fn tokenize<'x>(name: Rc<String>, data: &'x str)
     -> Result<Vec<Token<'x>>, (Pos, Error)>
 {
@@ -717,6 +960,333 @@ pub fn tokenize<'x>(name: Rc<String>, data: &'x str)
     };
 }
 
+/// Minimal size of the part of the input which is tokenized at once
+const PART_SIZE: usize = 8192;
+
+/// Where tokenizing of the part of the input has stopped
+enum Cut {
+    /// Number of the final tokens and where to continue
+    Part(usize, Pos, State),
+    /// Number of the final tokens before the error, and the state before
+    /// the error
+    Error(usize, State, (Pos, Error)),
+    /// All the tokens are final
+    End,
+}
+
+/// Tokenizer of the input read from `BufRead` or given as a string
+///
+/// The input is read and tokenized by parts, which end at a line break.
+/// Only the tokens which can't be changed by the rest of the input are
+/// returned. After an error, tokenizing continues at the next line.
+/// The input which is already released is not kept in memory. Tokens of
+/// the input given as a string borrow it, otherwise they own their text.
+pub struct TokenReader<'a, R> {
+    reader: R,
+    /// The input starting at `buf_offset`
+    buf: Cow<'a, str>,
+    buf_offset: usize,
+    /// Position and state where the next part starts
+    pos: Pos,
+    state: State,
+    tokens: VecDeque<Result<Token<'a>, (Pos, Error)>>,
+    /// Positions where tokenizing can be restarted from
+    checkpoints: VecDeque<(Pos, State)>,
+    eof: bool,
+    done: bool,
+}
+
+/// Converts the position relative to `start` (see `YamlIter::resume`) to
+/// the position in the whole input
+fn shift(pos: &mut Pos, start: &Pos) {
+    pos.line += start.line - 1;
+    pos.offset += start.offset;
+}
+
+/// Returns position of the start of the line following the `pos`
+fn next_line(data: &str, pos: &Pos) -> Pos {
+    let mut next = pos.clone();
+    let rest = &data[pos.offset..];
+    match rest.find(|c| c == '\r' || c == '\n') {
+        Some(idx) => {
+            // Line numbers are counted the same way as in `YamlIter`
+            let len = if rest[idx..].starts_with("\r\n") { 2 } else { 1 };
+            next.line += len;
+            next.offset += idx + len;
+            next.line_start = true;
+            next.line_offset = 1;
+            next.indent = 0;
+        }
+        None => {
+            next.offset = data.len();
+            next.line_offset += rest.chars().count();
+            next.line_start = false;
+        }
+    }
+    return next;
+}
+
+/// Tokenizes `data`, which starts at `start` of the input
+///
+/// Tokenizing stops after the first final token which ends past the
+/// `size` bytes of the data. Returns `None` if there are no final tokens,
+/// so more input is needed. Positions are relative to `start`.
+fn tokenize_data<'x>(start: &Pos, state: State, data: &'x str, eof: bool,
+    size: usize)
+    -> Option<(Vec<Token<'x>>, Cut)>
+{
+    let mut result = Vec::new();
+    let cut = {
+        let mut tokenizer = Tokenizer::resume(&mut result,
+            start, state, data);
+        let mut cut = None;
+        loop {
+            let len = tokenizer.result.len();
+            let state = tokenizer.state();
+            if tokenizer.step() {
+                // Tokens at the end of the part may be continued
+                // in the next one
+                let offset = tokenizer.iter.position.offset;
+                if offset < data.len() {
+                    cut = Some(Cut::Part(tokenizer.result.len(),
+                        tokenizer.iter.position.clone(),
+                        tokenizer.state()));
+                    if offset >= size {
+                        break cut;
+                    }
+                }
+                continue;
+            }
+            match tokenizer.error() {
+                Some(err) => {
+                    if eof || tokenizer.iter.next_value.is_some() {
+                        cut = Some(Cut::Error(len, state, err));
+                    }
+                }
+                None => {
+                    if eof {
+                        tokenizer.finish();
+                        cut = Some(Cut::End);
+                    }
+                }
+            }
+            break cut;
+        }
+    };
+    return cut.map(|cut| (result, cut));
+}
+
+impl<'a> TokenReader<'a, io::Empty> {
+    /// Tokenizer of the whole input given at once
+    pub fn from_text(name: Rc<String>, data: &'a str)
+        -> TokenReader<'a, io::Empty>
+    {
+        let mut reader = TokenReader::with_buffer(name, io::empty(),
+            Cow::Borrowed(data));
+        reader.eof = true;
+        return reader;
+    }
+}
+
+impl<'a, R: BufRead> TokenReader<'a, R> {
+    pub fn new(name: Rc<String>, reader: R) -> TokenReader<'a, R> {
+        return TokenReader::with_buffer(name, reader,
+            Cow::Owned(String::new()));
+    }
+
+    fn with_buffer(name: Rc<String>, reader: R, buf: Cow<'a, str>)
+        -> TokenReader<'a, R>
+    {
+        let pos = Pos {
+            filename: name,
+            indent: 0,
+            line: 1,
+            line_start: true,
+            line_offset: 1,
+            offset: 0,
+        };
+        let state = State {
+            indent_levels: vec!(0),
+            flow_level: 0,
+            doc_start: true,
+        };
+        let mut checkpoints = VecDeque::new();
+        checkpoints.push_back((pos.clone(), state.clone()));
+        return TokenReader {
+            reader: reader,
+            buf: buf,
+            buf_offset: 0,
+            pos: pos,
+            state: state,
+            tokens: VecDeque::new(),
+            checkpoints: checkpoints,
+            eof: false,
+            done: false,
+        };
+    }
+
+    /// Name of the input, as used in positions
+    pub fn name(&self) -> Rc<String> {
+        return self.pos.filename.clone();
+    }
+
+    /// Returns the next token or an error
+    ///
+    /// `None` is returned after the end of file token
+    pub fn next_token(&mut self)
+        -> io::Result<Option<Result<Token<'a>, (Pos, Error)>>>
+    {
+        loop {
+            if let Some(tok) = self.tokens.pop_front() {
+                return Ok(Some(tok));
+            }
+            if self.done {
+                return Ok(None);
+            }
+            self.fill()?;
+        }
+    }
+
+    /// Reads and tokenizes the next part of the input
+    fn fill(&mut self) -> io::Result<()> {
+        let left = self.buf.len() - (self.pos.offset - self.buf_offset);
+        let mut size = max(PART_SIZE, left * 2);
+        loop {
+            while !self.eof &&
+                self.buf.len() - (self.pos.offset - self.buf_offset) < size
+            {
+                if self.reader.read_line(self.buf.to_mut())? == 0 {
+                    self.eof = true;
+                }
+            }
+            if self.pos.offset == 0 && self.buf.starts_with('\u{FEFF}') {
+                // Byte order mark is not a part of the content
+                self.pos.offset = '\u{FEFF}'.len_utf8();
+                self.checkpoints[0].0.offset = self.pos.offset;
+            }
+            if self.tokenize_part(size) {
+                return Ok(());
+            }
+            size *= 2;
+        }
+    }
+
+    /// Tokenizes the input read so far, about `size` bytes of it
+    ///
+    /// Returns false if more input is needed to return any tokens
+    fn tokenize_part(&mut self, size: usize) -> bool {
+        let start = self.pos.offset - self.buf_offset;
+        let state = self.state.clone();
+        let part = match self.buf {
+            Cow::Borrowed(buf) => {
+                tokenize_data(&self.pos, state, &buf[start..], self.eof,
+                              size)
+            }
+            Cow::Owned(ref buf) => {
+                tokenize_data(&self.pos, state, &buf[start..], self.eof,
+                              size)
+                .map(|(result, cut)| {
+                    (result.into_iter().map(Token::into_owned).collect(),
+                     cut)
+                })
+            }
+        };
+        let (result, cut) = match part {
+            Some(part) => part,
+            None => return false,
+        };
+        let (len, next, error) = match cut {
+            Cut::End => {
+                self.done = true;
+                (result.len(), None, None)
+            }
+            Cut::Part(len, mut pos, state) => {
+                shift(&mut pos, &self.pos);
+                (len, Some((pos, state)), None)
+            }
+            Cut::Error(len, state, (mut pos, err)) => {
+                let mut next = next_line(&self.buf[start..], &pos);
+                shift(&mut next, &self.pos);
+                shift(&mut pos, &self.pos);
+                (len, Some((next, state)), Some((pos, err)))
+            }
+        };
+        for mut tok in result.into_iter().take(len) {
+            shift(&mut tok.start, &self.pos);
+            shift(&mut tok.end, &self.pos);
+            self.tokens.push_back(Ok(tok));
+        }
+        if let Some(error) = error {
+            self.tokens.push_back(Err(error));
+        }
+        if let Some((pos, state)) = next {
+            self.pos = pos.clone();
+            self.state = state.clone();
+            self.checkpoints.push_back((pos, state));
+        }
+        return true;
+    }
+
+    /// Drops the input before `offset`
+    ///
+    /// Tokenizing is never restarted before the offset after that
+    pub fn release(&mut self, offset: usize) {
+        while self.checkpoints.len() > 1 &&
+            self.checkpoints[1].0.offset <= offset
+        {
+            self.checkpoints.pop_front();
+        }
+        let len = self.checkpoints[0].0.offset - self.buf_offset;
+        if len > 0 {
+            match self.buf {
+                Cow::Borrowed(ref mut buf) => {
+                    let data: &'a str = *buf;
+                    *buf = &data[len..];
+                }
+                Cow::Owned(ref mut buf) => {
+                    buf.drain(..len);
+                }
+            }
+            self.buf_offset += len;
+        }
+    }
+
+    /// Tokenizes the input again from `pos` in the block context
+    ///
+    /// The `pos` must be the start of a token which is already returned,
+    /// and it must not be released. Tokens which are not returned yet are
+    /// discarded.
+    pub fn restart(&mut self, pos: &Pos) {
+        while self.checkpoints.len() > 1 &&
+            self.checkpoints.back().unwrap().0.offset > pos.offset
+        {
+            self.checkpoints.pop_back();
+        }
+        let (start, state) = self.checkpoints.back().unwrap().clone();
+        let mut state = {
+            // Indentation levels at the `pos` are needed
+            let data = &self.buf[start.offset - self.buf_offset..];
+            let mut result = Vec::new();
+            let mut tokenizer = Tokenizer::resume(&mut result,
+                &start, state, data);
+            loop {
+                let state = tokenizer.state();
+                if start.offset + tokenizer.iter.position.offset
+                    >= pos.offset || !tokenizer.step()
+                {
+                    break state;
+                }
+            }
+        };
+        state.flow_level = 0;
+        self.tokens.clear();
+        self.pos = pos.clone();
+        self.state = state.clone();
+        self.checkpoints.push_back((pos.clone(), state));
+        self.done = false;
+    }
+}
+
 #[cfg(test)]
 pub fn test_tokenize<'x>(data: &'x str)
     -> Result<Vec<Token<'x>>, String>
@@ -734,7 +1304,10 @@ fn simple_tokens<'x>(res: Result<Vec<Token<'x>>, String>)
             assert_eq!(vec.last().unwrap().kind, Eof);
             return (&vec[0..(vec.len()-1)]).iter().map(
             |ref tok| {
-                return (tok.kind, tok.value);
+                match tok.value {
+                    Cow::Borrowed(value) => return (tok.kind, value),
+                    Cow::Owned(_) => unreachable!(),
+                }
             }).collect();
         }
         Err(err) => {
@@ -898,7 +1471,8 @@ fn test_map_key() {
         vec!((PlainString, "?something")));
     let tokens = test_tokenize("? something");
     assert_eq!(simple_tokens(tokens),
-        vec!((MappingKey, "?"), (Whitespace, " "), (PlainString, "something")));
+        vec!((MappingKey, "?"), (Whitespace, " "), (Indent, ""),
+             (PlainString, "something"), (Unindent, "")));
 }
 
 #[test]
@@ -911,8 +1485,8 @@ fn test_map_value() {
         vec!((PlainString, ":something")));
     let tokens = test_tokenize(": something");
     assert_eq!(simple_tokens(tokens),
-        vec!((MappingValue, ":"), (Whitespace, " "),
-             (PlainString, "something")));
+        vec!((MappingValue, ":"), (Whitespace, " "), (Indent, ""),
+             (PlainString, "something"), (Unindent, "")));
 }
 
 #[test]
@@ -978,6 +1552,13 @@ fn test_plain8() {
              ));
 }
 
+#[test]
+fn test_plain_trailing_newline() {
+    let tokens = test_tokenize("a\nb\n");
+    assert_eq!(simple_tokens(tokens),
+        vec!((PlainString, "a\nb"), (Whitespace, "\n")));
+}
+
 #[test]
 fn test_plain_words() {
     let tokens = test_tokenize("a: a b");
@@ -1009,6 +1590,24 @@ fn test_block_flow() {
              (Folded, ">\n b"), (Whitespace, "\n"), (PlainString, "c")));
 }
 
+#[test]
+fn test_block_folded() {
+    let tokens = test_tokenize("a: >\n b\n\n  c\n d\ne");
+    assert_eq!(simple_tokens(tokens),
+        vec!((PlainString, "a"), (MappingValue, ":"), (Whitespace, " "),
+             (Folded, ">\n b\n\n  c\n d"), (Whitespace, "\n"),
+             (PlainString, "e")));
+}
+
+#[test]
+fn test_block_trailing_lines() {
+    let tokens = test_tokenize("a: |+\n b\n\n  \nc");
+    assert_eq!(simple_tokens(tokens),
+        vec!((PlainString, "a"), (MappingValue, ":"), (Whitespace, " "),
+             (Literal, "|+\n b\n\n  "), (Whitespace, "\n"),
+             (PlainString, "c")));
+}
+
 #[test]
 fn test_block_in_list() {
     let tokens = test_tokenize("- |\n  a");
@@ -1053,6 +1652,16 @@ fn test_reserved() {
         vec!((PlainString, "a\n@")));
 }
 
+#[test]
+fn test_byte_order_mark() {
+    let tokens = test_tokenize("\u{FEFF}a: b");
+    assert_eq!(simple_tokens(tokens),
+        vec!((PlainString, "a"), (MappingValue, ":"),
+             (Whitespace, " "), (PlainString, "b")));
+    let tokens = test_tokenize("\u{FEFF}");
+    assert_eq!(simple_tokens(tokens), vec!());
+}
+
 #[test]
 fn test_bad_char_ctl() {
     let err = test_tokenize("\x01").err().unwrap();
@@ -1143,6 +1752,24 @@ fn test_tag() {
         Bad char in tag name");
 }
 
+#[test]
+fn test_verbatim_tag() {
+    assert_eq!(simple_tokens(test_tokenize("!<tag:yaml.org,2002:str> b")),
+        vec!((Tag, "!<tag:yaml.org,2002:str>"), (Whitespace, " "),
+             (PlainString, "b")));
+    assert_eq!(simple_tokens(test_tokenize("!<!local> b")),
+        vec!((Tag, "!<!local>"), (Whitespace, " "), (PlainString, "b")));
+    let err = test_tokenize("!<tag:x b").err().unwrap();
+    assert_eq!(&format!("{}", err), "<inline_test>:1:8: \
+        Bad char in tag name");
+    let err = test_tokenize("!<tag:x").err().unwrap();
+    assert_eq!(&format!("{}", err), "<inline_test>:1:1: \
+        Unclosed verbatim tag, expected '>'");
+    let err = test_tokenize("!<tag:x>y").err().unwrap();
+    assert_eq!(&format!("{}", err), "<inline_test>:1:9: \
+        Bad char in tag name");
+}
+
 #[test]
 fn test_anchor() {
     assert_eq!(simple_tokens(test_tokenize("&abc")),
@@ -1221,12 +1848,54 @@ fn test_flow_map_map() {
              (FlowMapEnd, "}")));
 }
 
+#[test]
+fn test_flow_map_alias_anchor() {
+    assert_eq!(simple_tokens(test_tokenize(
+        r#"{db: *primary, cache: &c {ttl: 5}}"#)),
+        vec!((FlowMapStart, "{"),
+             (PlainString, "db"),
+             (MappingValue, ":"),
+             (Whitespace, " "),
+             (Alias, "*primary"),
+             (FlowEntry, ","),
+             (Whitespace, " "),
+             (PlainString, "cache"),
+             (MappingValue, ":"),
+             (Whitespace, " "),
+             (Anchor, "&c"),
+             (Whitespace, " "),
+             (FlowMapStart, "{"),
+             (PlainString, "ttl"),
+             (MappingValue, ":"),
+             (Whitespace, " "),
+             (PlainString, "5"),
+             (FlowMapEnd, "}"),
+             (FlowMapEnd, "}")));
+}
+
 #[test]
 fn test_plain_scalar_braces() {
     assert_eq!(simple_tokens(test_tokenize(r#"a:{}"#)),
         vec!((PlainString, "a:{}")));
 }
 
+#[test]
+fn test_doc_start_unindent() {
+    assert_eq!(simple_tokens(test_tokenize("a:\n  b\n---\nc")),
+        vec![
+            (PlainString, "a"),
+            (MappingValue, ":"),
+            (Whitespace, "\n  "),
+            (Indent, ""),
+            (PlainString, "b"),
+            (Whitespace, "\n"),
+            (Unindent, ""),
+            (DocumentStart, "---"),
+            (Whitespace, "\n"),
+            (PlainString, "c"),
+        ]);
+}
+
 #[test]
 fn test_doc_end() {
     assert_eq!(simple_tokens(test_tokenize("...")),
@@ -1251,7 +1920,7 @@ fn test_doc_end() {
             (Whitespace, "\n"),
         ]);
     assert_eq!(simple_tokens(test_tokenize("test\n...xxx\n")),
-        vec![(PlainString, "test\n...xxx\n")]);
+        vec![(PlainString, "test\n...xxx"), (Whitespace, "\n")]);
     assert_eq!(simple_tokens(test_tokenize("test\n ...\n")),
         vec![
             (PlainString, "test"),
@@ -1281,3 +1950,56 @@ fn test_doc_end() {
             (DocumentEnd, "..."),
         ]);
 }
+
+#[cfg(test)]
+fn format_token(tok: &Token) -> String {
+    return format!("{:?} {:?} {}{}-{}", tok.kind, tok.value,
+                   tok.start, tok.start.offset, tok.end.offset);
+}
+
+#[cfg(test)]
+fn read_tokens(data: &str) -> Vec<String> {
+    let mut reader = TokenReader::new(Rc::new("<inline text>".to_string()),
+        io::BufReader::with_capacity(1, data.as_bytes()));
+    let mut result = Vec::new();
+    while let Some(tok) = reader.next_token().unwrap() {
+        result.push(match tok {
+            Ok(tok) => format_token(&tok),
+            Err((pos, err)) => format!("{} {}", pos, err),
+        });
+    }
+    return result;
+}
+
+#[test]
+fn test_reader_same_as_tokenize() {
+    // Longer than a few parts read at once
+    let data = "a:\n  b: [1,\n    2]\n  c: |\n    text\n\n  \
+        d: plain\n    multiline\n- {e: f}  # comment\n---\n\
+        'x': \"y\"\n...\n".repeat(300);
+    let tokens = tokenize(Rc::new("<inline text>".to_string()), &data)
+        .unwrap();
+    assert_eq!(read_tokens(&data),
+               tokens.iter().map(format_token).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_reader_continues_after_error() {
+    assert_eq!(read_tokens("a: 1\nb: @\nc: 2\n"), vec![
+        "PlainString \"a\" <inline text>:1:1:0-1",
+        "MappingValue \":\" <inline text>:1:2:1-2",
+        "Whitespace \" \" <inline text>:1:3:2-3",
+        "PlainString \"1\" <inline text>:1:4:3-4",
+        "Whitespace \"\\n\" <inline text>:1:5:4-5",
+        "PlainString \"b\" <inline text>:2:1:5-6",
+        "MappingValue \":\" <inline text>:2:2:6-7",
+        "Whitespace \" \" <inline text>:2:3:7-8",
+        "<inline text>:2:4: Characters '@' and '`' are not allowed",
+        "PlainString \"c\" <inline text>:3:1:10-11",
+        "MappingValue \":\" <inline text>:3:2:11-12",
+        "Whitespace \" \" <inline text>:3:3:12-13",
+        "PlainString \"2\" <inline text>:3:4:13-14",
+        "Whitespace \"\\n\" <inline text>:3:5:14-15",
+        "Eof \"\" <inline text>:4:1:15-15",
+        ]);
+}
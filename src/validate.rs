@@ -8,16 +8,18 @@
 
 use std::fmt::{Display};
 use std::path::{PathBuf, Path, Component};
-use std::collections::{BTreeMap, HashSet};
+use std::collections::HashSet;
+
+use indexmap::IndexMap;
 
 use num_traits::PrimInt;
 use humannum::parse_integer;
 
 use super::errors::{Error, ErrorCollector};
-pub use super::tokenizer::Pos;
+pub use super::tokenizer::{Pos, Span};
 use super::ast::Ast as A;
 use super::ast::Tag as T;
-use super::ast::{Ast, NullKind};
+use super::ast::{Ast, MapItems, NullKind};
 use super::ast::ScalarKind::{Quoted, Plain};
 
 
@@ -78,21 +80,23 @@ impl Scalar {
 impl Validator for Scalar {
     fn default(&self, pos: Pos) -> Option<Ast> {
         if self.default.is_none() && self.optional {
-            return Some(A::Null(pos.clone(), T::NonSpecific, NullKind::Implicit));
+            return Some(A::Null(pos.clone().into(), T::NonSpecific, None,
+                                NullKind::Implicit));
         }
         self.default.as_ref().map(|val| {
-            A::Scalar(pos.clone(), T::NonSpecific, Quoted, val.clone()) })
+            A::Scalar(pos.clone().into(), T::NonSpecific, None, Quoted,
+                      val.clone()) })
     }
     fn validate(&self, ast: Ast, err: &ErrorCollector) -> Ast {
         let (pos, kind, val) = match ast {
-            A::Scalar(pos, _, kind, string) => {
+            A::Scalar(pos, _, _, kind, string) => {
                 (pos, kind, string)
             }
-            A::Null(_, _, _) if self.optional => {
+            A::Null(_, _, _, _) if self.optional => {
                 return ast;
             }
             ast => {
-                err.add_error(Error::validation_error(&ast.pos(),
+                err.add_error(Error::validation_error(&ast.span(),
                     format!("Value must be scalar")));
                 return ast;
             }
@@ -109,7 +113,7 @@ impl Validator for Scalar {
                     format!("Value must be at most {} characters", maxl)));
             }
         });
-        return A::Scalar(pos, T::NonSpecific, kind, val);
+        return A::Scalar(pos, T::NonSpecific, None, kind, val);
     }
 }
 
@@ -157,28 +161,30 @@ impl Validator for Numeric {
 
     fn default(&self, pos: Pos) -> Option<Ast> {
         if self.default.is_none() && self.optional {
-            return Some(A::Null(pos.clone(), T::NonSpecific, NullKind::Implicit));
+            return Some(A::Null(pos.clone().into(), T::NonSpecific, None,
+                                NullKind::Implicit));
         }
         self.default.as_ref().map(|val| {
-            A::Scalar(pos.clone(), T::NonSpecific, Quoted, val.to_string())
+            A::Scalar(pos.clone().into(), T::NonSpecific, None, Quoted,
+                      val.to_string())
         })
     }
     fn validate(&self, ast: Ast, err: &ErrorCollector) -> Ast {
-        let (pos, val): (Pos, i64)  = match ast {
-            A::Scalar(pos, tag, kind, string)
+        let (pos, val): (Span, i64)  = match ast {
+            A::Scalar(pos, tag, _, kind, string)
             => match parse_integer(&string) {
                 Ok(val) => (pos, val),
                 Err(e) => {
                     err.add_error(Error::validation_error(&pos,
                         format!("number error: {}", e)));
-                    return A::Scalar(pos, tag, kind, string);
+                    return A::Scalar(pos, tag, None, kind, string);
                 }
             },
-            A::Null(_, _, _) if self.optional => {
+            A::Null(_, _, _, _) if self.optional => {
                 return ast;
             }
             ast => {
-                err.add_error(Error::validation_error(&ast.pos(),
+                err.add_error(Error::validation_error(&ast.span(),
                     format!("Value must be scalar")));
                 return ast;
             }
@@ -195,7 +201,7 @@ impl Validator for Numeric {
                     format!("Value must be at most {}", max)));
             }
         });
-        return A::Scalar(pos, T::NonSpecific, Plain, val.to_string());
+        return A::Scalar(pos, T::NonSpecific, None, Plain, val.to_string());
     }
 }
 
@@ -236,22 +242,23 @@ impl Directory {
 impl Validator for Directory {
     fn default(&self, pos: Pos) -> Option<Ast> {
         if self.default.is_none() && self.optional {
-            return Some(A::Null(pos.clone(), T::NonSpecific, NullKind::Implicit));
+            return Some(A::Null(pos.clone().into(), T::NonSpecific, None,
+                                NullKind::Implicit));
         }
         self.default.as_ref().map(|val| {
-            A::Scalar(pos.clone(), T::NonSpecific, Quoted,
+            A::Scalar(pos.clone().into(), T::NonSpecific, None, Quoted,
                       val.display().to_string()) })
     }
     fn validate(&self, ast: Ast, err: &ErrorCollector) -> Ast {
         let (pos, kind, val) = match ast {
-            A::Scalar(pos, _, kind, string) => {
+            A::Scalar(pos, _, _, kind, string) => {
                 (pos, kind, string)
             }
-            A::Null(_, _, _) if self.optional => {
+            A::Null(_, _, _, _) if self.optional => {
                 return ast;
             }
             ast => {
-                err.add_error(Error::validation_error(&ast.pos(),
+                err.add_error(Error::validation_error(&ast.span(),
                     format!("Path expected")));
                 return ast;
             }
@@ -286,7 +293,7 @@ impl Validator for Directory {
                 None => {}
             };
         }
-        return A::Scalar(pos, T::NonSpecific, kind, val);
+        return A::Scalar(pos, T::NonSpecific, None, kind, val);
     }
 }
 
@@ -303,7 +310,7 @@ pub struct Structure<'a> {
     descr: Option<String>,
     members: Vec<(String, Box<Validator + 'a>)>,
     optional: bool,
-    from_scalar: Option<fn (scalar: Ast) -> BTreeMap<String, Ast>>,
+    from_scalar: Option<fn (scalar: Ast) -> MapItems>,
 }
 
 impl<'a> Structure<'a> {
@@ -326,7 +333,7 @@ impl<'a> Structure<'a> {
         self
     }
     pub fn parser(mut self,
-        f: fn (scalar: Ast) -> BTreeMap<String, Ast>)
+        f: fn (scalar: Ast) -> MapItems)
         -> Structure<'a>
     {
         self.from_scalar = Some(f);
@@ -337,9 +344,10 @@ impl<'a> Structure<'a> {
 impl<'a> Validator for Structure<'a> {
     fn default(&self, pos: Pos) -> Option<Ast> {
         if self.optional {
-            return Some(A::Null(pos.clone(), T::NonSpecific, NullKind::Implicit));
+            return Some(A::Null(pos.clone().into(), T::NonSpecific, None,
+                                NullKind::Implicit));
         }
-        let mut map = BTreeMap::new();
+        let mut map = IndexMap::new();
         for &(ref k, ref validator) in self.members.iter() {
             match validator.default(pos.clone()) {
                 Some(val) => {
@@ -348,46 +356,64 @@ impl<'a> Validator for Structure<'a> {
                 None => continue,
             }
         }
-        return Some(A::Map(pos, T::NonSpecific, map));
+        return Some(A::Map(pos.into(), T::NonSpecific, None, map));
     }
     fn validate(&self, ast: Ast, err: &ErrorCollector) -> Ast {
         let (pos, mut map) = match (ast, self.from_scalar) {
-            (A::Map(pos, _, items), _) => {
+            (A::Map(pos, _, _, items), _) => {
                 (pos, items)
             }
-            (A::Null(pos, _, NullKind::Implicit), _) => {
-                return self.default(pos).unwrap();
+            (A::Null(pos, _, _, NullKind::Implicit), _) => {
+                return self.default(pos.start).unwrap();
             }
             (ast@A::Scalar(..), Some(from_scalar)) => {
-                (ast.pos(), from_scalar(ast))
+                (ast.span(), from_scalar(ast))
             }
             (ast, _) => {
-                err.add_error(Error::validation_error(&ast.pos(),
+                err.add_error(Error::validation_error(&ast.span(),
                     format!("Value must be mapping")));
                 return ast;
             }
         };
+        // Dashed variant of the key is ignored if the original one is present
+        for &(ref k, _) in self.members.iter() {
+            let dashed = k[..].replace("_", "-");
+            if dashed != *k && map.contains_key(k) {
+                map.shift_remove(&dashed);
+            }
+        }
+        // Keep the order of the keys as in the source, members which are
+        // not specified are appended in the order of declaration
+        let mut res = IndexMap::new();
+        for (key, value) in map.into_iter() {
+            let member = self.members.iter().find(|&&(ref k, _)| {
+                *k == key || k[..].replace("_", "-") == key
+            });
+            match member {
+                Some(&(ref k, ref validator)) => {
+                    res.insert(k.clone(), validator.validate(value, err));
+                }
+                None => {
+                    res.insert(key, value);
+                }
+            }
+        }
         for &(ref k, ref validator) in self.members.iter() {
-            let value = match map.remove(k)
-                .or(map.remove(&k[..].replace("_", "-"))) {
-                Some(src) => {
-                    validator.validate(src, err)
+            if res.contains_key(k) {
+                continue;
+            }
+            match validator.default(pos.start.clone()) {
+                Some(x) => {
+                    res.insert(k.clone(), x);
                 }
                 None => {
-                    match validator.default(pos.clone()) {
-                        Some(x) => x,
-                        None => {
-                            err.add_error(Error::validation_error(&pos,
-                                format!("Field {} is expected", k)));
-                            continue;
-                        }
-                    }
+                    err.add_error(Error::validation_error(&pos,
+                        format!("Field {} is expected", k)));
                 }
-            };
-            map.insert(k.clone(), value);
+            }
         }
         let mut keys: HashSet<String>;
-        keys = map.keys()
+        keys = res.keys()
             .filter(|s| !&s[..].starts_with("_"))
             .map(|s| s.clone()).collect();
         for &(ref k, _) in self.members.iter() {
@@ -397,7 +423,7 @@ impl<'a> Validator for Structure<'a> {
             err.add_error(Error::validation_error(&pos,
                 format!("Keys {:?} are not expected", keys)));
         }
-        return A::Map(pos, T::NonSpecific, map);
+        return A::Map(pos, T::NonSpecific, None, res);
     }
 }
 
@@ -474,14 +500,15 @@ impl<'a> Enum<'a> {
 impl<'a> Validator for Enum<'a> {
     fn default(&self, pos: Pos) -> Option<Ast> {
         if self.default_tag.is_some() && self.optional {
-            return Some(A::Null(pos.clone(),
-                T::LocalTag(self.default_tag.as_ref().unwrap().clone()),
+            return Some(A::Null(pos.clone().into(),
+                T::LocalTag(self.default_tag.as_ref().unwrap().clone()), None,
                 NullKind::Implicit));
         } else if self.default_value.is_some() {
             return self.default_value.as_ref().map(|val| {
-                A::Scalar(pos.clone(), T::NonSpecific, Quoted, val.clone()) })
+                A::Scalar(pos.clone().into(), T::NonSpecific, None, Quoted,
+                          val.clone()) })
         } else if self.optional {
-            return Some(A::Null(pos.clone(), T::NonSpecific,
+            return Some(A::Null(pos.clone().into(), T::NonSpecific, None,
                                 NullKind::Implicit));
         }
         return None;
@@ -493,11 +520,12 @@ impl<'a> Validator for Enum<'a> {
             }
             &T::NonSpecific => {
                 if self.allow_plain {
-                    if let A::Scalar(ref pos, _, _, ref val) = ast {
+                    if let A::Scalar(ref pos, _, _, _, ref val) = ast {
                         for &(ref k, ref validator) in self.options.iter() {
                             if &k[..] == val {
                                 let value = validator.validate(
-                                    A::Null(pos.clone(), T::NonSpecific,
+                                    A::Null(pos.clone().into(),
+                                            T::NonSpecific, None,
                                             NullKind::Implicit),
                                     err);
                                 return value.with_tag(
@@ -511,17 +539,24 @@ impl<'a> Validator for Enum<'a> {
                 } else if let Some(ref value) = self.default_value {
                     Some(value.clone())
                 } else {
-                    err.add_error(Error::validation_error(&ast.pos(),
+                    err.add_error(Error::validation_error(&ast.span(),
                         format!("One of the tags {:?} expected",
                             self.options.iter().map(|&(ref k, _)| k)
                                 .collect::<Vec<&String>>())));
                     None
                 }
             }
-            _ => unimplemented!(),
+            &T::GlobalTag(ref tag) => {
+                err.add_error(Error::validation_error(&ast.span(),
+                    format!("Global tag !<{}> is not expected, \
+                        one of the tags {:?} expected", tag,
+                        self.options.iter().map(|&(ref k, _)| k)
+                            .collect::<Vec<&String>>())));
+                None
+            }
         };
         if let Some(tag_name) = tag_name {
-            let pos = ast.pos().clone();
+            let pos = ast.span();
             for &(ref k, ref validator) in self.options.iter() {
                 if &k[..] == &tag_name[..] {
                     let value = validator.validate(ast, err);
@@ -543,7 +578,7 @@ pub struct Mapping<'a> {
     descr: Option<String>,
     key_element: Box<Validator + 'a>,
     value_element: Box<Validator + 'a>,
-    from_scalar: Option<fn (scalar: Ast) -> BTreeMap<String, Ast>>,
+    from_scalar: Option<fn (scalar: Ast) -> MapItems>,
 }
 
 impl<'a> Mapping<'a> {
@@ -558,7 +593,7 @@ impl<'a> Mapping<'a> {
         }
     }
     pub fn parser(mut self,
-        f: fn (scalar: Ast) -> BTreeMap<String, Ast>)
+        f: fn (scalar: Ast) -> MapItems)
         -> Mapping<'a>
     {
         self.from_scalar = Some(f);
@@ -568,36 +603,37 @@ impl<'a> Mapping<'a> {
 
 impl<'a> Validator for Mapping<'a> {
     fn default(&self, pos: Pos) -> Option<Ast> {
-        return Some(A::Map(pos, T::NonSpecific, BTreeMap::new()));
+        return Some(A::Map(pos.into(), T::NonSpecific, None, IndexMap::new()));
     }
     fn validate(&self, ast: Ast, err: &ErrorCollector) -> Ast {
         let (pos, map) = match (ast, self.from_scalar) {
-            (A::Map(pos, _, items), _) => {
+            (A::Map(pos, _, _, items), _) => {
                 (pos, items)
             }
-            (A::Null(pos, _, NullKind::Implicit), _) => {
-                return A::Map(pos, T::NonSpecific, BTreeMap::new());
+            (A::Null(pos, _, _, NullKind::Implicit), _) => {
+                return A::Map(pos, T::NonSpecific, None, IndexMap::new());
             }
             (ast@A::Scalar(..), Some(from_scalar)) => {
-                (ast.pos(), from_scalar(ast))
+                (ast.span(), from_scalar(ast))
             }
             (ast, _) => {
-                err.add_error(Error::validation_error(&ast.pos(),
+                err.add_error(Error::validation_error(&ast.span(),
                     format!("Value must be mapping")));
                 return ast;
             }
         };
-        let mut res = BTreeMap::new();
+        let mut res = IndexMap::new();
         for (k, v) in map.into_iter() {
             let key = match self.key_element.validate(
-                A::Scalar(v.pos().clone(), T::NonSpecific, Plain, k), err) {
-                A::Scalar(_, _, _, val) => val,
+                A::Scalar(v.pos().into(), T::NonSpecific, None, Plain, k),
+                err) {
+                A::Scalar(_, _, _, _, val) => val,
                 _ => unreachable!(),
             };
             let value = self.value_element.validate(v, err);
             res.insert(key, value);
         }
-        return A::Map(pos, T::NonSpecific, res);
+        return A::Map(pos, T::NonSpecific, None, res);
     }
 }
 
@@ -629,21 +665,21 @@ impl<'a> Sequence<'a> {
 
 impl<'a> Validator for Sequence<'a> {
     fn default(&self, pos: Pos) -> Option<Ast> {
-        return Some(A::Seq(pos, T::NonSpecific, Vec::new()));
+        return Some(A::Seq(pos.into(), T::NonSpecific, None, Vec::new()));
     }
     fn validate(&self, ast: Ast, err: &ErrorCollector) -> Ast {
         let (pos, children) = match (ast, self.from_scalar) {
-            (A::Seq(pos, _, items), _) => {
+            (A::Seq(pos, _, _, items), _) => {
                 (pos, items)
             }
-            (A::Null(pos, _, NullKind::Implicit), _) => {
-                return A::Seq(pos, T::NonSpecific, Vec::new());
+            (A::Null(pos, _, _, NullKind::Implicit), _) => {
+                return A::Seq(pos, T::NonSpecific, None, Vec::new());
             }
-            (ast@A::Scalar(_, _, _, _), Some(fun)) => {
-                (ast.pos().clone(), fun(ast))
+            (ast@A::Scalar(_, _, _, _, _), Some(fun)) => {
+                (ast.span(), fun(ast))
             }
             (ast, _) => {
-                err.add_error(Error::validation_error(&ast.pos(),
+                err.add_error(Error::validation_error(&ast.span(),
                     format!("Value must be sequence")));
                 return ast;
             }
@@ -653,7 +689,7 @@ impl<'a> Validator for Sequence<'a> {
             let value = self.element.validate(val, err);
             res.push(value);
         }
-        return A::Seq(pos, T::NonSpecific, res);
+        return A::Seq(pos, T::NonSpecific, None, res);
     }
 }
 
@@ -682,9 +718,9 @@ impl Validator for Nothing {
         return None;
     }
     fn validate(&self, ast: Ast, err: &ErrorCollector) -> Ast {
-        if let A::Null(_, _, _) = ast {
+        if let A::Null(_, _, _, _) = ast {
         } else {
-            err.add_error(Error::parse_error(&ast.pos(),
+            err.add_error(Error::parse_error(&ast.span(),
                 format!("Null expected, {} found", ast)));
         }
         return ast;
@@ -702,7 +738,7 @@ mod test {
 
     use {Options};
     use super::super::decode::YamlDecoder;
-    use super::super::ast::{process, Ast as A};
+    use super::super::ast::{process, Ast as A, MapItems};
     use super::super::ast::Tag::{NonSpecific};
     use super::super::ast::ScalarKind::{Plain};
     use super::super::parser::parse;
@@ -766,6 +802,28 @@ mod test {
         });
     }
 
+    #[test]
+    fn test_key_order() {
+        let str_val = Structure::new()
+            .member("intkey", Numeric::new().default(123))
+            .member("strkey", Scalar::new().default("default_value"))
+            .member("some_key", Scalar::new().default("x"));
+        let err = ErrorCollector::new();
+        let ast = parse(
+                Rc::new("<inline text>".to_string()),
+                "strkey: a\n_note: b\nintkey: 1",
+                |doc| { process(&Options::default(), doc, &err) }
+            ).map_err(|e| err.into_fatal(e)).unwrap();
+        match str_val.validate(ast, &err) {
+            A::Map(_, _, _, map) => {
+                assert_eq!(map.keys().collect::<Vec<_>>(),
+                    vec!["strkey", "_note", "intkey", "some_key"]);
+            }
+            _ => unreachable!(),
+        }
+        err.into_result(()).unwrap();
+    }
+
     #[derive(Clone, Debug, PartialEq, Eq, RustcDecodable)]
     struct TestDash {
         some_key: usize,
@@ -825,7 +883,7 @@ mod test {
         assert_eq!(parse_with_warnings("some-key: 13\nanother-key: 12"),
             (TestDash {
                 some_key: 13,
-            }, vec!("<inline text>:1:1: Validation Error: \
+            }, vec!("<inline text>:1:1-2:16: Validation Error: \
                  Keys {\"another-key\"} are not expected"
                  .to_string())));
     }
@@ -864,12 +922,13 @@ mod test {
     }
 
     fn parse_map<T:Decodable>(body: &str) -> T {
-        fn parse_default(ast: A) -> BTreeMap<String, A> {
+        fn parse_default(ast: A) -> MapItems {
             match ast {
-                A::Scalar(pos, _, style, value) => {
-                    let mut map = BTreeMap::new();
+                A::Scalar(pos, _, _, style, value) => {
+                    let mut map = MapItems::new();
                     map.insert("default_value".to_string(),
-                        A::Scalar(pos.clone(), NonSpecific, style, value));
+                        A::Scalar(pos.clone(), NonSpecific, None, style,
+                                  value));
                     map
                 },
                 _ => unreachable!(),
@@ -924,6 +983,16 @@ mod test {
         assert_eq!(res, m);
     }
 
+    #[test]
+    fn test_map_complex_keys() {
+        let mut m = BTreeMap::new();
+        m.insert("[a, b]".to_string(), 1);
+        m.insert("{x: 1}".to_string(), 2);
+        let res: BTreeMap<String, usize> = parse_map(
+            "? [a, b]\n: 1\n? {x: 1}\n: 2");
+        assert_eq!(res, m);
+    }
+
     #[test]
     fn test_map_with_parser() {
         let mut m = HashMap::new();
@@ -978,11 +1047,12 @@ mod test {
     fn parse_seq(body: &str) -> Vec<usize> {
         fn split(ast: A) -> Vec<A> {
             match ast {
-                A::Scalar(pos, _, style, value) => {
+                A::Scalar(pos, _, _, style, value) => {
                     value
                         .split(" ")
                         .map(|v| {
-                            A::Scalar(pos.clone(), NonSpecific, Plain, v.to_string())
+                            A::Scalar(pos.clone(), NonSpecific, None, Plain,
+                                      v.to_string())
                         })
                         .collect::<Vec<_>>()
                 },
@@ -1274,12 +1344,13 @@ mod test {
     }
 
     fn parse_struct_with_parser(body: &str) -> Parsed {
-        fn value_parser(ast: A) -> BTreeMap<String, A> {
+        fn value_parser(ast: A) -> MapItems {
             match ast {
-                A::Scalar(pos, _, style, value) => {
-                    let mut map = BTreeMap::new();
+                A::Scalar(pos, _, _, style, value) => {
+                    let mut map = MapItems::new();
                     map.insert("value".to_string(),
-                        A::Scalar(pos.clone(), NonSpecific, style, value));
+                        A::Scalar(pos.clone(), NonSpecific, None, style,
+                                  value));
                     map
                 }
                 _ => unreachable!(),
@@ -1344,4 +1415,18 @@ mod test {
     fn test_enum_def_tag() {
         assert_eq!(parse_enum_def("!Alpha"), TestEnumDef::Alpha);
     }
+
+    #[test]
+    fn test_enum_global_tag() {
+        let validator = enum_def_validator();
+        let res: Result<TestEnumDef, _> = parse_string("<inline text>",
+            "!!Alpha", &validator, &Options::default());
+        assert_eq!(format!("{}", res.unwrap_err()),
+            "<inline text>:1:8: Validation Error: \
+             Global tag !<tag:yaml.org,2002:Alpha> is not expected, \
+             one of the tags [\"Alpha\", \"Beta\"] expected\n\
+             <inline text>:1:8: Decode error at : \
+             Global tag !<tag:yaml.org,2002:Alpha> can't be used to \
+             select one of [\"Alpha\", \"Beta\"]\n");
+    }
 }